@@ -17,22 +17,217 @@
 //! # The blockchain db
 //!
 use pref::PRef;
+use page::PAGE_SIZE;
 use logfile::LogFile;
 use tablefile::TableFile;
-use datafile::{DataFile, DagIterator};
+use datafile::{DataFile, DagIterator, SharedPayload};
 use memtable::MemTable;
-use format::{Payload, Envelope};
+use format::{Payload, Envelope, StoredPayload};
 use error::HammersbaldError;
+use options::{Options, KeyTransform, FreeSpace};
+use frozen::FrozenDb;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use rand::{thread_rng, RngCore};
+use siphasher::sip::SipHasher;
+
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// longest key that can be stored
+pub const MAX_KEY_LEN: usize = 255;
+/// longest data that can be stored
+pub const MAX_DATA_LEN: usize = (1 << 23) - 1;
+/// most prefs data can refer to
+pub const MAX_REFERRED: usize = 1 << 20;
+
+// free space is asked for again after this many bytes of data were stored
+const FREE_SPACE_CHECK_BYTES: u64 = 1 << 20;
+// first byte of a dump record of data stored with key
+const DUMP_INDEXED: u8 = 0;
+// first byte of a dump record of data stored without key
+const DUMP_REFERRED: u8 = 1;
+
+/// check that key, data and referred can be stored, as done by put and put_referred
+/// the key is checked as given, a key transform is not applied
+pub fn validate_put(key: &[u8], data: &[u8], referred: &[PRef]) -> Result<(), HammersbaldError> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(HammersbaldError::KeyTooLong(MAX_KEY_LEN));
+    }
+    if data.len() > MAX_DATA_LEN {
+        return Err(HammersbaldError::ValueTooLarge(MAX_DATA_LEN));
+    }
+    if referred.len() > MAX_REFERRED {
+        return Err(HammersbaldError::TooManyReferences(MAX_REFERRED));
+    }
+    Ok(())
+}
 
 /// a trait to create a new db
 pub trait HammersbaldFactory {
     /// create a new db
-    fn new_db (name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Hammersbald, HammersbaldError>;
+    fn new_db (name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Hammersbald, HammersbaldError> {
+        Self::new_db_with_options(name, Options::new(cached_data_pages, bucket_fill_target))
+    }
+
+    /// create a new db with options
+    fn new_db_with_options (name: &str, options: Options) -> Result<Hammersbald, HammersbaldError>;
 }
 
 /// The blockchain db
+///
+/// The db is Send and Sync, a `&Hammersbald`, e.g. in an `Arc`, serves reads from several
+/// threads at once. Page caches and file handles are behind locks, buckets not read at open
+/// are read into a copy per lookup, so reads do not mutate shared state without a lock.
+/// Writes need `&mut Hammersbald`, so they are never concurrent with reads of the same handle
 pub struct Hammersbald {
-    mem: MemTable
+    mem: MemTable,
+    read_only: bool,
+    key_transform: Option<KeyTransform>,
+    min_free_bytes: Option<u64>,
+    free_space: Option<FreeSpace>,
+    // free bytes and data length when the free space was last asked for
+    free_checked: Option<(u64, u64)>,
+    scratch_dir: PathBuf
+}
+
+/// appends data without indexing, the index is built at once when finished
+/// the data is not accessible by key before finish
+pub struct BulkLoader {
+    db: Hammersbald,
+    added: Vec<(u64, PRef)>
+}
+
+/// statistics of the db
+pub struct Stats {
+    /// pages waiting to be written by background writers
+    pub write_queue_len: usize,
+    /// memory used by pages waiting to be written by background writers
+    pub write_queue_bytes: u64,
+    /// bytes of indexed data overwritten since the db was opened
+    pub orphaned_bytes: u64,
+    /// bytes appended to the data file
+    pub data_bytes: u64,
+    /// number of buckets of the hash table
+    pub buckets: usize
+}
+
+/// data of a key as read by get_ref. Data stored within a page is a slice of the page
+/// shared with the page cache, data spanning pages is read into a buffer
+pub struct EntryRef {
+    pref: PRef,
+    payload: SharedPayload,
+    data_at: usize,
+    data_len: usize,
+    referred: Vec<PRef>
+}
+
+impl EntryRef {
+    fn new (pref: PRef, payload: SharedPayload) -> Result<EntryRef, HammersbaldError> {
+        if !Payload::well_formed_data(payload.payload()) {
+            return Err(HammersbaldError::Corrupted(format!("no data at {}", pref)));
+        }
+        let (data_len, referred) = match Payload::deserialize(payload.payload())? {
+            Payload::Indexed(indexed) => (indexed.data.data.len(), indexed.data.referred()),
+            _ => return Err(HammersbaldError::Corrupted(format!("no indexed data at {}", pref)))
+        };
+        // data follows its length
+        let data_at = 3 + Payload::data_at(payload.payload())
+            .ok_or_else(|| HammersbaldError::Corrupted(format!("no indexed data at {}", pref)))?;
+        Ok(EntryRef { pref, payload, data_at, data_len, referred })
+    }
+
+    /// the pref the data is stored at
+    pub fn pref(&self) -> PRef {
+        self.pref
+    }
+
+    /// the data, not copied
+    pub fn data(&self) -> &[u8] {
+        &self.payload.payload()[self.data_at .. self.data_at + self.data_len]
+    }
+
+    /// further accessible data
+    pub fn referred(&self) -> &[PRef] {
+        self.referred.as_slice()
+    }
+}
+
+/// a key with its hash, for repeated lookups of the same key
+/// only valid for the db that prepared it
+#[derive(Clone, Debug)]
+pub struct PreparedKey {
+    key: Vec<u8>,
+    hash: u64,
+    sip_keys: (u64, u64)
+}
+
+impl PreparedKey {
+    /// the key, after the key transform of the db
+    pub fn key(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+}
+
+/// how durable data is once put_with_durability returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// appended to buffers, durable after the next batch
+    Buffered,
+    /// written to the operating system, survives a crash of the process
+    Flushed,
+    /// written to disk, survives a crash of the system
+    Synced
+}
+
+/// report of rebuilding the hash table from the data file
+pub struct RepairReport {
+    /// the error that prevented normal opening
+    pub cause: String,
+    /// number of keys indexed
+    pub indexed: usize,
+    /// number of entries that could not be indexed
+    pub discarded: usize
+}
+
+/// what recovery would do to the files of a db, see Persistent::dry_run_recovery
+#[derive(Debug)]
+pub struct RecoveryPlan {
+    /// length of the data file
+    pub current_data_len: u64,
+    /// length the data file would be truncated to
+    pub target_data_len: u64,
+    /// length of the table file
+    pub current_table_len: u64,
+    /// length the table file would be truncated to
+    pub target_table_len: u64,
+    /// length of the link file
+    pub current_link_len: u64,
+    /// length the link file would be truncated to
+    pub target_link_len: u64,
+    /// table pages that would be rewritten with their content of the last batch
+    pub table_pages: Vec<PRef>
+}
+
+/// how the bytes of the data file are used, see fragmentation
+#[derive(Debug)]
+pub struct FragmentationReport {
+    /// bytes appended to the data file
+    pub total_bytes: u64,
+    /// bytes of referred data and of indexed data accessible by its key
+    pub live_bytes: u64,
+    /// bytes of indexed data no longer accessible by its key
+    pub orphaned_bytes: u64,
+    /// bytes left empty at the end of the last page written by a batch
+    pub padding_bytes: u64,
+    /// bytes at the end of each page for the pref of its last envelope and the app trailer
+    pub page_trailer_bytes: u64
 }
 
 /// public API to the blockchain db
@@ -51,6 +246,7 @@ pub trait HammersbaldAPI {
     fn put(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError>;
 
     /// retrieve single data by key
+    /// keys of equal hash are told apart by the stored key, unless only the hash was stored
     /// returns (pref, data, referred)
     fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError>;
 
@@ -69,14 +265,115 @@ pub trait HammersbaldAPI {
 impl Hammersbald {
     /// create a new db with key and data file
     pub fn new(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bucket_fill_target :usize) -> Result<Hammersbald, HammersbaldError> {
-        let mem = MemTable::new(log, table, data, link, bucket_fill_target);
-        let mut db = Hammersbald { mem };
+        Self::new_with_options(log, table, data, link, &Options { bucket_fill_target, .. Options::default() })
+    }
+
+    /// create a new db with key and data file and options
+    pub fn new_with_options(log: LogFile, table: TableFile, data: DataFile, link: DataFile, options: &Options) -> Result<Hammersbald, HammersbaldError> {
+        let mut db = Self::with_files(log, table, data, link, options, false);
         db.recover()?;
         db.load()?;
-        db.batch()?;
+        // opening does not grow the files, it is not refused if the disk is nearly full
+        db.mem.batch()?;
+        Ok(db)
+    }
+
+    /// create a db, rebuild the hash table from the data file if recovery or load fails
+    /// returns the report of the rebuild if there was one
+    pub fn open_or_repair(log: LogFile, table: TableFile, data: DataFile, link: DataFile, options: &Options) -> Result<(Hammersbald, Option<RepairReport>), HammersbaldError> {
+        let mut db = Self::with_files(log, table, data, link, options, false);
+        let mut report = None;
+        let mut opened = db.recover();
+        if opened.is_ok() {
+            opened = db.load();
+        }
+        if let Err(HammersbaldError::BadMagic) = opened {
+            return Err(HammersbaldError::BadMagic);
+        }
+        if let Err(cause) = opened {
+            let (indexed, discarded) = db.mem.rebuild_index()?;
+            report = Some(RepairReport { cause: cause.to_string(), indexed, discarded });
+        }
+        db.mem.batch()?;
+        Ok((db, report))
+    }
+
+    /// open a db whose files can not be written
+    /// recovery is performed in memory only and all writes are rejected
+    pub fn new_read_only(log: LogFile, table: TableFile, data: DataFile, link: DataFile, options: &Options) -> Result<Hammersbald, HammersbaldError> {
+        let mut db = Self::with_files(log, table, data, link, options, true);
+        db.mem.replay_log()?;
+        db.load()?;
         Ok(db)
     }
 
+    // a db of the files, not yet recovered or loaded
+    // free space is not asked for if read-only, as nothing is written
+    fn with_files(log: LogFile, table: TableFile, data: DataFile, link: DataFile, options: &Options, read_only: bool) -> Hammersbald {
+        let mem = MemTable::new(log, table, data, link, options);
+        let (min_free_bytes, free_space) = if read_only {
+            (None, None)
+        } else {
+            (options.min_free_bytes, options.free_space.clone())
+        };
+        Hammersbald { mem, read_only, key_transform: options.key_transform.clone(), min_free_bytes, free_space, free_checked: None,
+            scratch_dir: options.scratch_dir.clone().unwrap_or_else(env::temp_dir) }
+    }
+
+    // writes are refused if read-only or the disk is nearly full
+    // free space is asked for after FREE_SPACE_CHECK_BYTES of data were stored since it was last asked,
+    // in between it is estimated from the data stored since
+    fn check_writable(&mut self) -> Result<(), HammersbaldError> {
+        if self.read_only {
+            return Err(HammersbaldError::ReadOnly);
+        }
+        if let (Some(min_free), Some(free_space)) = (self.min_free_bytes, self.free_space.clone()) {
+            let position = self.mem.data_len();
+            let estimate = match self.free_checked {
+                Some((free, at)) => position.checked_sub(at)
+                    .filter(|stored| *stored < FREE_SPACE_CHECK_BYTES)
+                    .map(|stored| free.saturating_sub(stored)),
+                None => None
+            };
+            let free = match estimate {
+                Some(free) if free >= min_free => free,
+                _ => {
+                    let free = free_space()?;
+                    self.free_checked = Some((free, position));
+                    free
+                }
+            };
+            if free < min_free {
+                return Err(HammersbaldError::DiskNearlyFull(free));
+            }
+        }
+        Ok(())
+    }
+
+    // as check_writable, but asks for the free space, e.g. before a batch
+    fn check_writable_now(&mut self) -> Result<(), HammersbaldError> {
+        self.free_checked = None;
+        self.check_writable()
+    }
+
+    /// the key as hashed and stored, that is transformed by the key transform of the options
+    pub fn transformed_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.key_transform {
+            Some(ref transform) => Cow::Owned(transform(key)),
+            None => Cow::Borrowed(key)
+        }
+    }
+
+    /// is this db read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// true if data was stored since the last batch, a batch is then needed to make it durable
+    pub fn is_dirty(&self) -> bool {
+        self.mem.is_dirty()
+    }
+
     /// load memtable
     fn load(&mut self) -> Result<(), HammersbaldError> {
         self.mem.load()
@@ -86,8 +383,21 @@ impl Hammersbald {
         self.mem.recover()
     }
 
+    /// check that the hash table does not point beyond the link file, done at the end of recovery
+    pub fn validate_after_recovery(&self) -> Result<(), HammersbaldError> {
+        self.mem.validate_after_recovery()
+    }
+
+    /// the slots of each bucket as of the last batch, read from disk a bucket at a time
+    /// memory does not grow with the table, e.g. for diagnostics of a db opened lazily
+    /// returns (bucket number, slots), or the error reading the bucket
+    pub fn stream_buckets<'a> (&'a self) -> impl Iterator<Item=Result<(usize, Vec<(u64, PRef)>), HammersbaldError>> +'a {
+        self.mem.stream_buckets()
+    }
+
     /// get hash table bucket iterator
-    pub fn slots<'a> (&'a self) -> impl Iterator<Item=&'a Vec<(u32, PRef)>> +'a {
+    /// buckets of a lazily loaded db are read as iterated, an error reading one is its item
+    pub fn slots<'a> (&'a self) -> impl Iterator<Item=Result<Vec<(u64, PRef)>, HammersbaldError>> +'a {
         self.mem.slots()
     }
 
@@ -96,139 +406,2231 @@ impl Hammersbald {
         self.mem.buckets()
     }
 
+    /// get hash table buckets with their fill, as (bucket number, pref of its link, number of entries)
+    /// the pref is invalid for a bucket not stored yet
+    pub fn buckets_with_fill<'a> (&'a self) -> impl Iterator<Item=Result<(usize, PRef, usize), HammersbaldError>> +'a {
+        self.mem.buckets_with_fill()
+    }
+
     /// return an iterator of all payloads
     pub fn data_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         self.mem.data_envelopes()
     }
 
+    /// return an iterator of all payloads decoded, latest first
+    /// a payload that can not be decoded yields an error, the iteration continues with the one before
+    pub fn payloads<'a>(&'a self) -> impl Iterator<Item=(PRef, Result<StoredPayload, HammersbaldError>)> +'a {
+        self.mem.data_envelopes().map(|(pref, envelope)| (pref, StoredPayload::decode(pref, &envelope)))
+    }
+
+    /// return an iterator of payloads stored at or after from and before to, latest first
+    pub fn data_envelopes_range<'a>(&'a self, from: PRef, to: PRef) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+        self.mem.data_envelopes_range(from, to)
+    }
+
+    /// the pref the next data would be stored at, data before it can be read
+    /// use as bound of data_envelopes_range to resume shipping data
+    pub fn data_tip(&self) -> PRef {
+        PRef::from(self.mem.data_len())
+    }
+
+    /// keys stored at or after since with the pref of their latest data, latest first
+    /// keys stored only as hash are not listed
+    pub fn changed_since<'a>(&'a self, since: PRef) -> impl Iterator<Item=(Vec<u8>, PRef)> + 'a {
+        let mut seen = HashSet::new();
+        self.mem.data_envelopes_range(since, self.data_tip()).filter_map(move |(pref, envelope)| {
+            if let Ok(Payload::Indexed(indexed)) = Payload::deserialize(envelope.payload()) {
+                if indexed.has_key() && seen.insert(indexed.key.to_vec()) {
+                    return Some((indexed.key.to_vec(), pref));
+                }
+            }
+            None
+        })
+    }
+
     /// return an iterator of all links
     pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         self.mem.link_envelopes()
     }
 
+    /// get the key of indexed data
+    /// returns None for referred data or if only the hash of the key was stored
+    pub fn key_of(&self, pref: PRef) -> Result<Option<Vec<u8>>, HammersbaldError> {
+        let envelope = self.mem.get_envelope(pref)?;
+        if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+            if indexed.has_key() {
+                return Ok(Some(indexed.key.to_vec()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// all data stored with the key, also that overwritten, oldest first
+    /// returns (pref, data), this scans the whole data file
+    /// data stored without its key is not found, history is lost if the data file is rewritten
+    pub fn history(&self, key: &[u8]) -> Result<Vec<(PRef, Vec<u8>)>, HammersbaldError> {
+        let key = self.transformed_key(key);
+        let mut versions = Vec::new();
+        for (pref, envelope) in self.mem.data_envelopes() {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if indexed.has_key() && indexed.key == &key[..] {
+                    versions.push((pref, indexed.data.data.to_vec()));
+                }
+            }
+        }
+        versions.reverse();
+        Ok(versions)
+    }
+
+    /// write accessible data oldest first as records independent of the page layout:
+    /// tag (u8, 0 for data with key, 1 without), key length (u8) and key only if tagged 0,
+    /// data length (u32), data, number of referred (u32) and referred,
+    /// each referred as the number (u64) of an earlier record, all big endian.
+    /// Data no longer accessible by key is written without key if it is referred.
+    /// returns the number of records written, all keys must be stored with their data
+    pub fn dump<W: Write>(&self, out: &mut W) -> Result<u64, HammersbaldError> {
+        let mut referred = HashSet::new();
+        let mut prefs = Vec::new();
+        for (pref, envelope) in self.mem.data_envelopes() {
+            match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(indexed) => referred.extend(indexed.data.referred()),
+                Payload::Referred(data) => referred.extend(data.referred()),
+                Payload::Link(_) => {}
+            }
+            prefs.push(pref);
+        }
+        let mut records = HashMap::new();
+        for pref in prefs.into_iter().rev() {
+            let envelope = self.mem.get_envelope(pref)?;
+            let (key, data) = match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(indexed) => {
+                    if !indexed.has_key() {
+                        return Err(HammersbaldError::Corrupted("can not dump a db that stores only hashes of keys".to_string()));
+                    }
+                    if self.mem.get(indexed.key)?.map(|(p, _, _)| p) == Some(pref) {
+                        (Some(indexed.key), indexed.data)
+                    } else if referred.contains(&pref) {
+                        (None, indexed.data)
+                    } else {
+                        continue;
+                    }
+                },
+                Payload::Referred(data) => (None, data),
+                Payload::Link(_) => continue
+            };
+            if let Some(key) = key {
+                out.write_u8(DUMP_INDEXED)?;
+                out.write_u8(key.len() as u8)?;
+                out.write_all(key)?;
+            } else {
+                out.write_u8(DUMP_REFERRED)?;
+            }
+            out.write_u32::<BigEndian>(data.data.len() as u32)?;
+            out.write_all(data.data)?;
+            let refs = data.referred();
+            out.write_u32::<BigEndian>(refs.len() as u32)?;
+            for r in refs {
+                out.write_u64::<BigEndian>(*records.get(&r).ok_or(HammersbaldError::ForwardReference)?)?;
+            }
+            let n = records.len() as u64;
+            records.insert(pref, n);
+        }
+        Ok(records.len() as u64)
+    }
+
+    /// store the records written by dump, records without key are stored as referred data
+    /// the input must end after a record, returns the number of records read
+    pub fn load_dump<R: Read>(&mut self, input: &mut R) -> Result<u64, HammersbaldError> {
+        let mut prefs = Vec::new();
+        loop {
+            let key = match input.read_u8() {
+                Ok(DUMP_INDEXED) => {
+                    let mut key = vec!(0u8; input.read_u8()? as usize);
+                    input.read_exact(&mut key)?;
+                    Some(key)
+                },
+                Ok(DUMP_REFERRED) => None,
+                Ok(tag) => return Err(HammersbaldError::Corrupted(format!("unknown dump record tag {}", tag))),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(HammersbaldError::IO(e))
+            };
+            let mut data = vec!(0u8; input.read_u32::<BigEndian>()? as usize);
+            input.read_exact(&mut data)?;
+            let nref = input.read_u32::<BigEndian>()?;
+            let mut referred = Vec::with_capacity(nref as usize);
+            for _ in 0 .. nref {
+                let n = input.read_u64::<BigEndian>()? as usize;
+                referred.push(*prefs.get(n).ok_or(HammersbaldError::ForwardReference)?);
+            }
+            let pref = match key {
+                Some(key) => self.put(&key, &data, &referred)?,
+                None => self.put_referred(&data, &referred)?
+            };
+            prefs.push(pref);
+        }
+        Ok(prefs.len() as u64)
+    }
+
+    /// find references that are not backward, these break the iteration of a DAG
+    /// forward references are rejected only by debug builds
+    /// returns (pref of the data, pref it refers to) for each, empty if there are none
+    pub fn audit_references(&self) -> Result<Vec<(PRef, PRef)>, HammersbaldError> {
+        let mut forward = Vec::new();
+        for (pref, envelope) in self.mem.data_envelopes() {
+            let referred = match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(indexed) => indexed.data.referred(),
+                Payload::Referred(data) => data.referred(),
+                Payload::Link(_) => continue
+            };
+            forward.extend(referred.into_iter().filter(|r| *r >= pref).map(|r| (pref, r)));
+        }
+        forward.reverse();
+        Ok(forward)
+    }
+
+    /// a digest of the keys and data of accessible indexed data
+    /// dbs with the same keys and data have the same digest, whatever the order they were stored
+    /// prefs and referred data are not part of the digest
+    pub fn content_digest(&self) -> Result<[u8; 32], HammersbaldError> {
+        let mut entries = Vec::new();
+        for slots in self.mem.slots() {
+            for (_, pref) in slots? {
+                let envelope = self.mem.get_envelope(pref)?;
+                if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                    let mut entry = Vec::new();
+                    entry.write_u32::<BigEndian>(indexed.key.len() as u32)?;
+                    entry.extend_from_slice(indexed.key);
+                    entry.extend_from_slice(indexed.data.data);
+                    entries.push(Self::digest(&entry));
+                }
+            }
+        }
+        entries.sort();
+        Ok(Self::digest(&entries.concat()))
+    }
+
+    // four sip hashes with distinct keys
+    fn digest(bytes: &[u8]) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        for i in 0 .. 4 {
+            let mut hasher = SipHasher::new_with_keys(i as u64, 0);
+            hasher.write(bytes);
+            BigEndian::write_u64(&mut digest[i * 8 .. i * 8 + 8], hasher.finish());
+        }
+        digest
+    }
+
+    /// iterate the keys and prefs of accessible indexed data whose header satisfies pred
+    /// the header is the first Options::header_bytes of the data, stored with the hash table,
+    /// data is read only for matching headers. The key is empty if only its hash was stored
+    /// an error reading a bucket or data is yielded in place of its entries
+    pub fn scan_by_header<'a, F>(&'a self, pred: F) -> impl Iterator<Item=Result<(Vec<u8>, PRef), HammersbaldError>> +'a
+        where F: Fn(&[u8]) -> bool + 'a {
+        self.mem.scan_by_header(pred)
+    }
+
+    /// iterate the keys and prefs of accessible indexed data for which pred(key, data) is true
+    /// the key is empty if only its hash was stored, an error reading data is its item
+    pub fn filter_entries<'a, F>(&'a self, pred: F) -> impl Iterator<Item=Result<(Vec<u8>, PRef), HammersbaldError>> +'a
+        where F: Fn(&[u8], &[u8]) -> bool + 'a {
+        self.mem.filter_entries(pred)
+    }
+
+    /// the keys of all accessible indexed data, read from the hash table
+    /// keys stored only as hash are not included
+    pub fn key_set(&self) -> Result<HashSet<Vec<u8>>, HammersbaldError> {
+        let mut keys = HashSet::new();
+        self.for_each_key(|key| { keys.insert(key.to_vec()); })?;
+        Ok(keys)
+    }
+
+    /// call f with the key of each accessible indexed data, as key_set without collecting them
+    pub fn for_each_key<F>(&self, f: F) -> Result<(), HammersbaldError> where F: FnMut(&[u8]) {
+        self.mem.for_each_key(f)
+    }
+
     /// get indexed or referred payload
     pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, HammersbaldError> {
         self.mem.get_envelope(pref)
     }
 
-    /// get db params
-    pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
-        self.mem.params()
+    /// iterate a DAG as dag does, yielding the data and referred of each node read on the way
+    /// returns (pref, data, referred), or the error that ended the iteration
+    pub fn dag_entries<'a>(&'a self, root: PRef) -> impl Iterator<Item=Result<(PRef, Vec<u8>, Vec<PRef>), HammersbaldError>> + 'a {
+        self.mem.dag(root).filter_map(|entry| {
+            let (pref, envelope) = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e))
+            };
+            match Payload::deserialize(envelope.payload()) {
+                Ok(Payload::Indexed(indexed)) => Some(Ok((pref, indexed.data.data.to_vec(), indexed.data.referred()))),
+                Ok(Payload::Referred(referred)) => Some(Ok((pref, referred.data.to_vec(), referred.referred()))),
+                Ok(Payload::Link(_)) => None,
+                Err(e) => Some(Err(e))
+            }
+        })
     }
-}
 
-impl HammersbaldAPI for Hammersbald {
-    /// initialize a db
-    fn init (&mut self) -> Result<(), HammersbaldError> {
-        self.mem.init()
+    /// iterate a DAG as dag, but yield data referred repeatedly only once
+    /// visited data is remembered in a scratch file, a bit per byte of the data file,
+    /// instead of memory. A scratch file given is overwritten and left for the caller to remove,
+    /// without one a file in Options::scratch_dir is used and removed when the iterator is dropped
+    pub fn dag_disk_visited<'a>(&'a self, root: PRef, scratch_path: Option<&Path>) -> Result<DagIterator<'a>, HammersbaldError> {
+        match scratch_path {
+            Some(scratch_path) => {
+                let scratch = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(scratch_path)?;
+                Ok(self.mem.dag(root).with_visited_file(scratch))
+            },
+            None => {
+                let path = self.scratch_dir.join(format!("hammersbald-visited-{}", thread_rng().next_u64()));
+                let scratch = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+                Ok(self.mem.dag(root).with_scratch_file(scratch, path))
+            }
+        }
     }
 
-
-    /// end current batch and start a new batch
-    fn batch (&mut self)  -> Result<(), HammersbaldError> {
-        self.mem.batch()
+    /// iterate a DAG as dag, but stop after max_nodes data, e.g. for graphs of untrusted origin
+    /// truncated of the iterator tells if data was left out
+    pub fn dag_limited<'a>(&'a self, root: PRef, max_nodes: usize) -> DagIterator<'a> {
+        self.mem.dag(root).with_max_nodes(max_nodes)
     }
 
-    /// stop background writer
-    fn shutdown (&mut self) {
-        self.mem.shutdown()
+    /// prefs of data reachable from any of the roots, the roots included, each once
+    /// one traversal shares what was visited among the roots, e.g. for the mark phase of a gc
+    pub fn reachable<'a>(&'a self, roots: &[PRef]) -> impl Iterator<Item=Result<PRef, HammersbaldError>> + 'a {
+        self.mem.reachable(roots).map(|entry| entry.map(|(pref, _)| pref))
     }
 
-    /// store data with a key
-    /// storing with the same key makes previous data unaddressable
-    fn put(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
-        #[cfg(debug_assertions)]
-        {
-            if key.len() > 255 || data.len() >= 1 << 23 {
-                return Err(HammersbaldError::ForwardReference);
+    /// write a DAG as a Graphviz DOT graph, a node per data labeled with its pref and
+    /// up to 8 leading bytes of its data in hex, an edge per referred pref.
+    /// Data referred repeatedly is written once
+    pub fn dag_to_dot<W: Write>(&self, root: PRef, out: &mut W) -> Result<(), HammersbaldError> {
+        writeln!(out, "digraph dag {{")?;
+        let mut visited = HashSet::new();
+        let mut todo = vec!(root);
+        visited.insert(root);
+        while let Some(pref) = todo.pop() {
+            let (_, data, referred) = self.get_referred(pref)?;
+            let preview = data.iter().take(8).map(|b| format!("{:02x}", b)).collect::<String>();
+            let more = if data.len() > 8 { ".." } else { "" };
+            writeln!(out, "  n{} [label=\"{}\\n{}{}\"];", pref.as_u64(), pref, preview, more)?;
+            for r in referred {
+                writeln!(out, "  n{} -> n{};", pref.as_u64(), r.as_u64())?;
+                if visited.insert(r) {
+                    todo.push(r);
+                }
             }
         }
-        let data_offset = self.mem.append_data(key, data, referred)?;
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+
+    /// read the concatenated data of a DAG
+    /// the root is read first, then nodes breadth-first with the referred of each
+    /// node in descending pref order
+    pub fn dag_reader<'a>(&'a self, root: PRef) -> impl Read + 'a {
+        self.mem.dag_reader(root)
+    }
+
+    /// make data stored so far durable without ending the batch, e.g. to order dependent writes
+    /// data survives a crash from here, but is found by key only after the next batch
+    /// the data file continues at the next page
+    pub fn barrier(&mut self) -> Result<(), HammersbaldError> {
+        self.check_writable_now()?;
+        self.mem.barrier()
+    }
+
+    /// store data with a key as put, durable as requested without ending the batch
+    /// data stored before is made as durable. After a crash the data is read by its pref,
+    /// it is found by key only after a batch. Flushed and Synced continue the data file at the next page
+    /// returns the pref the data was stored
+    pub fn put_with_durability(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>, durability: Durability) -> Result<PRef, HammersbaldError> {
+        let pref = self.put_tagged(key, data, referred, 0)?;
+        match durability {
+            Durability::Buffered => {},
+            Durability::Flushed => self.mem.persist_data(false)?,
+            Durability::Synced => self.mem.persist_data(true)?
+        }
+        Ok(pref)
+    }
+
+    /// drop data stored at or after a data_tip recorded right after a batch, e.g. to undo an import
+    /// the batch is ended. Data stored before the pref and overwritten after it is also not found
+    pub fn rewind(&mut self, data_pref: PRef) -> Result<(), HammersbaldError> {
+        // frees disk space, so not refused if the disk is nearly full
+        if self.read_only {
+            return Err(HammersbaldError::ReadOnly);
+        }
+        self.mem.rewind(data_pref)
+    }
+
+    /// split a bucket of the hash table, as an insert would do occasionally
+    /// returns false if the table can not grow further
+    /// the split is stored with the next batch
+    pub fn split_once(&mut self) -> Result<bool, HammersbaldError> {
+        self.check_writable()?;
+        self.mem.split()
+    }
+
+    /// remove hash table entries that point beyond the data file or not to indexed data
+    /// returns the number of entries removed, the repair is stored with the next batch
+    pub fn repair_dangling(&mut self) -> Result<u64, HammersbaldError> {
+        self.check_writable()?;
+        self.mem.repair_dangling()
+    }
+
+    /// are hash table buckets read from disk when first used
+    /// this is the case if the table exceeded the recovery memory limit at open
+    pub fn is_lazy(&self) -> bool {
+        self.mem.is_lazy()
+    }
+
+    /// are hash table hashes 64 bits wide
+    pub fn is_wide_hash(&self) -> bool {
+        self.mem.is_wide_hash()
+    }
+
+    /// end current batch and read back a random sample_ratio (0.0 - 1.0) of the pages written
+    /// since the previous batch, returns Corrupted if storage does not return what was written
+    /// this is expensive, use it in tests or for critical writes
+    pub fn batch_verified(&mut self, sample_ratio: f64) -> Result<(), HammersbaldError> {
+        self.check_writable_now()?;
+        self.mem.batch_verified(sample_ratio)
+    }
+
+    /// store data with a key and application defined flags
+    /// storing with the same key makes previous data unaddressable
+    /// returns the pref the data was stored
+    pub fn put_tagged(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>, flags: u8) -> Result<PRef, HammersbaldError> {
+        Ok(self.put_indexed(key, data, referred, flags)?.0)
+    }
+
+    /// store data with a key as put
+    /// returns the pref the data was stored and true if data of the key was overwritten,
+    /// false if the key is new. With store_keys false data of the same hash counts as the key
+    pub fn put_status(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<(PRef, bool), HammersbaldError> {
+        self.put_indexed(key, data, referred, 0)
+    }
+
+    fn put_indexed(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>, flags: u8) -> Result<(PRef, bool), HammersbaldError> {
+        trace_span!("put", key_len = key.len(), data_len = data.len(), referred = referred.len());
+        self.check_writable()?;
+        let key = self.transformed_key(key);
+        validate_put(&key, data, referred)?;
+        let data_offset = self.mem.append_data(&key, data, referred, flags)?;
         #[cfg(debug_assertions)]
         {
             if referred.iter().any(|o| o.as_u64() >= data_offset.as_u64()) {
                 return Err(HammersbaldError::ForwardReference);
             }
         }
-        self.mem.put(key, data_offset)?;
-        Ok(data_offset)
+        let replaced = self.mem.put(&key, data_offset)?;
+        Ok((data_offset, replaced))
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
-        self.mem.get(key)
+    /// store a large value as referred data in consecutive pages, no other data is stored between
+    /// its first and last page. This holds as the db has a single writer, data appended
+    /// concurrently through another handle to the same files would interleave
+    /// returns the pref the data was stored
+    pub fn append_contiguous(&mut self, data: &[u8]) -> Result<PRef, HammersbaldError> {
+        self.put_referred(data, &vec!())
     }
 
-    fn put_referred(&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
-        let data_offset = self.mem.append_referred(data, referred)?;
-        #[cfg(debug_assertions)]
-        {
-            if referred.iter().any(|o| o.as_u64() >= data_offset.as_u64()) {
+    /// store data with a key, the data is only borrowed for the append, e.g. from a memory mapped file
+    /// it is copied into pages once, no owned buffer is needed
+    /// returns the pref the data was stored
+    pub fn put_from_slice(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, HammersbaldError> {
+        self.put_tagged(key, data, &vec!(), 0)
+    }
+
+    /// store data read from a reader to its end with a key, e.g. from stdin
+    /// the length is not needed up front, but is stored before the data, so the data is copied
+    /// in chunks to a file in Options::scratch_dir first, then in chunks to the db. Data longer
+    /// than max_len or MAX_DATA_LEN is refused with ValueTooLarge of the limit before anything is stored
+    /// returns the pref the data was stored
+    pub fn put_stream<R: Read>(&mut self, key: &[u8], reader: &mut R, max_len: usize) -> Result<PRef, HammersbaldError> {
+        trace_span!("put_stream", key_len = key.len(), max_len = max_len);
+        self.check_writable()?;
+        let key = self.transformed_key(key);
+        validate_put(&key, &[], &[])?;
+        let path = self.scratch_dir.join(format!("hammersbald-stream-{}", thread_rng().next_u64()));
+        let result = self.put_staged(&key, reader, min(max_len, MAX_DATA_LEN), &path);
+        fs::remove_file(&path).ok();
+        result
+    }
+
+    fn put_staged<R: Read>(&mut self, key: &[u8], reader: &mut R, limit: usize, path: &Path) -> Result<PRef, HammersbaldError> {
+        let mut scratch = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let len = io::copy(&mut reader.take(limit as u64 + 1), &mut scratch)?;
+        if len > limit as u64 {
+            return Err(HammersbaldError::ValueTooLarge(limit));
+        }
+        scratch.seek(SeekFrom::Start(0))?;
+        let data_offset = self.mem.append_data_stream(key, &mut scratch, len as usize, 0)?;
+        self.mem.put(key, data_offset)?;
+        Ok(data_offset)
+    }
+
+    /// append a serialized indexed or referred data payload as is, as received from a replica
+    /// indexed data is also indexed if its key is stored, replicas applying the same payloads
+    /// in the same order assign the same prefs
+    /// returns the pref the data was stored
+    pub fn append_raw_entry(&mut self, payload: &[u8]) -> Result<PRef, HammersbaldError> {
+        self.check_writable()?;
+        let position = self.mem.data_len();
+        if Payload::well_formed_data(payload) {
+            let referred = match Payload::deserialize(payload)? {
+                Payload::Indexed(indexed) => indexed.data.referred(),
+                Payload::Referred(data) => data.referred(),
+                Payload::Link(_) => vec!()
+            };
+            if referred.iter().any(|o| o.as_u64() >= position) {
                 return Err(HammersbaldError::ForwardReference);
             }
         }
+        let data_offset = self.mem.append_raw_entry(payload)?;
+        if let Payload::Indexed(indexed) = Payload::deserialize(payload)? {
+            if indexed.has_key() {
+                self.mem.put(indexed.key, data_offset)?;
+            }
+        }
         Ok(data_offset)
     }
 
-    fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
-        let envelope = self.mem.get_envelope(pref)?;
-        match Payload::deserialize(envelope.payload())? {
-            Payload::Referred(referred) => return Ok((vec!(), referred.data.to_vec(), referred.referred())),
-            Payload::Indexed(indexed) => return Ok((indexed.key.to_vec(), indexed.data.data.to_vec(), indexed.data.referred())),
-            _ => Err(HammersbaldError::Corrupted("referred should point to data".to_string()))
-        }
+    /// retrieve single data and its flags by key, flags are zero if not set
+    /// returns (pref, flags, data, referred)
+    pub fn get_tagged(&self, key: &[u8]) -> Result<Option<(PRef, u8, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        self.mem.get_tagged(&self.transformed_key(key))
     }
 
-    fn dag(&self, root: PRef) -> DagIterator {
-        self.mem.dag(root)
+    /// retrieve single data by key with the unix time in milliseconds it was stored at,
+    /// None if stored without Options::record_timestamps
+    /// returns (pref, data, referred, time)
+    pub fn get_with_time(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>, Option<u64>)>, HammersbaldError> {
+        self.mem.get_with_time(&self.transformed_key(key))
     }
-}
 
-#[cfg(test)]
-mod test {
-    extern crate rand;
-    extern crate hex;
+    /// retrieve single data by key without copying it out of the cached page it is stored in
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<EntryRef>, HammersbaldError> {
+        match self.mem.lookup_shared(&self.transformed_key(key))? {
+            Some((pref, payload)) => Ok(Some(EntryRef::new(pref, payload)?)),
+            None => Ok(None)
+        }
+    }
 
-    use transient::Transient;
+    /// transform and hash a key once for repeated get_prepared
+    pub fn prepare_key(&self, key: &[u8]) -> PreparedKey {
+        let key = self.transformed_key(key).into_owned();
+        let hash = self.mem.hash(&key);
+        PreparedKey { key, hash, sip_keys: self.mem.sip_keys() }
+    }
 
-    use super::*;
-    use self::rand::thread_rng;
-    use std::collections::HashMap;
-    use api::test::rand::RngCore;
+    /// retrieve single data by a prepared key, as get without hashing the key
+    /// a key prepared by a db with other hash keys is rejected with ForeignPreparedKey
+    pub fn get_prepared(&self, pk: &PreparedKey) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        if pk.sip_keys != self.mem.sip_keys() {
+            return Err(HammersbaldError::ForeignPreparedKey);
+        }
+        if let Some((pref, envelope)) = self.mem.lookup_hashed(&pk.key, pk.hash)? {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                return Ok(Some((pref, indexed.data.data.to_vec(), indexed.data.referred())));
+            }
+        }
+        Ok(None)
+    }
 
-    #[test]
-    fn test_two_batches () {
-        let mut db = Transient::new_db("first", 1, 1).unwrap();
-        db.init().unwrap();
+    /// the pref of the data last stored with the key, without copying the data
+    pub fn pref_of(&self, key: &[u8]) -> Result<Option<PRef>, HammersbaldError> {
+        self.mem.pref_of(&self.transformed_key(key))
+    }
 
-        let mut rng = thread_rng();
+    /// estimated bytes the data of a key occupies on disk, None if the key is not stored
+    /// page trailers are attributed in proportion to the stored bytes, the shared
+    /// header of the link holding its slot and its table entry are not counted
+    pub fn entry_footprint(&self, key: &[u8]) -> Result<Option<u64>, HammersbaldError> {
+        self.mem.entry_footprint(&self.transformed_key(key))
+    }
 
-        let mut check = HashMap::new();
-        let mut key = [0x0u8;32];
-        let mut data = [0x0u8;40];
+    /// the prefs of the pages the data of a key is stored in, with its key and length,
+    /// in file order. None if the key is not stored
+    pub fn value_pages(&self, key: &[u8]) -> Result<Option<Vec<PRef>>, HammersbaldError> {
+        self.mem.value_pages(&self.transformed_key(key))
+    }
 
-        for _ in 0 .. 10000 {
-            rng.fill_bytes(&mut key);
-            rng.fill_bytes(&mut data);
-            let pref = db.put(&key, &data, &vec!()).unwrap();
-            check.insert(key, (pref, data));
+    /// end the last batch and replace the hash table with a minimal perfect hash over the keys
+    /// the frozen db can not be written, all keys must be stored with their data
+    pub fn freeze(mut self) -> Result<FrozenDb, HammersbaldError> {
+        if !self.read_only {
+            self.batch()?;
         }
-        db.batch().unwrap();
+        FrozenDb::build(self)
+    }
 
-        for (k, (o, v)) in check.iter() {
-            assert_eq!(db.get(&k[..]).unwrap(), Some((*o, v.to_vec(), vec!())));
-        }
+    /// start loading data in bulk, for imports of many keys
+    pub fn bulk_loader(mut self) -> Result<BulkLoader, HammersbaldError> {
+        self.check_writable()?;
+        Ok(BulkLoader { db: self, added: Vec::new() })
+    }
 
-        for _ in 0 .. 10000 {
-            rng.fill_bytes(&mut key);
-            rng.fill_bytes(&mut data);
-            let pref = db.put(&key, &data, &vec!()).unwrap();
-            check.insert(key, (pref, data));
-        }
-        db.batch().unwrap();
+    /// set the app trailer of data pages, e.g. a generation counter for replication
+    /// it is stored in Options::app_trailer_bytes of each data page completed from now,
+    /// including the page being filled. Shorter trailers are padded with zeros
+    pub fn write_app_trailer(&mut self, trailer: &[u8]) -> Result<(), HammersbaldError> {
+        self.check_writable()?;
+        self.mem.write_app_trailer(trailer);
+        Ok(())
+    }
 
-        for (k, (o, v)) in check.iter() {
-            assert_eq!(db.get(&k[..]).unwrap(), Some((*o, v.to_vec(), vec!())));
-        }
-        db.shutdown();
+    /// the app trailer of the data page holding pref
+    pub fn read_app_trailer(&self, pref: PRef) -> Result<Vec<u8>, HammersbaldError> {
+        self.mem.read_app_trailer(pref)
     }
-}
\ No newline at end of file
+
+    /// the bucket fill target in use, as recorded in the db or given in the options
+    pub fn configured_fill_target(&self) -> usize {
+        self.mem.configured_fill_target()
+    }
+
+    /// change the number of pages cached per file while the db is in use
+    /// shrinking evicts least recently used pages at once, no effect if opened with no_cache
+    pub fn resize_cache(&mut self, pages: usize) {
+        self.mem.resize_cache(pages)
+    }
+
+    /// how the bytes of the data file are used by live and orphaned data, padding and page trailers
+    /// unlike stats, data orphaned before the db was opened is included. Referred data is live
+    pub fn fragmentation(&self) -> Result<FragmentationReport, HammersbaldError> {
+        self.mem.fragmentation()
+    }
+
+    /// get db statistics
+    pub fn stats(&self) -> Stats {
+        let write_queue_len = self.mem.queue_len();
+        Stats { write_queue_len, write_queue_bytes: (write_queue_len * PAGE_SIZE) as u64,
+            orphaned_bytes: self.mem.orphaned_bytes(), data_bytes: self.mem.data_len(),
+            buckets: self.mem.params().2 }
+    }
+
+    /// number of buckets of the hash table
+    pub fn bucket_count(&self) -> usize {
+        self.mem.bucket_count()
+    }
+
+    /// number of buckets split in the current round of linear hashing
+    pub fn split_step(&self) -> u32 {
+        self.mem.split_step()
+    }
+
+    /// the current round of linear hashing ends after more than 2^(log_mod + 1) splits,
+    /// log_mod then increases and split_step starts over
+    pub fn log_mod(&self) -> u32 {
+        self.mem.log_mod()
+    }
+
+    /// get db params
+    pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
+        self.mem.params()
+    }
+}
+
+impl BulkLoader {
+    /// store data with a key, it is indexed by finish
+    /// storing with the same key makes previous data unaddressable
+    /// returns the pref the data was stored
+    pub fn put(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        self.db.check_writable()?;
+        let key = self.db.transformed_key(key).into_owned();
+        validate_put(&key, data, referred)?;
+        let data_offset = self.db.mem.append_data(&key, data, referred, 0)?;
+        #[cfg(debug_assertions)]
+        {
+            if referred.iter().any(|o| o.as_u64() >= data_offset.as_u64()) {
+                return Err(HammersbaldError::ForwardReference);
+            }
+        }
+        self.added.push((self.db.mem.hash(&key), data_offset));
+        Ok(data_offset)
+    }
+
+    /// store referred data
+    /// returns the pref the data was stored
+    pub fn put_referred(&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        self.db.put_referred(data, referred)
+    }
+
+    /// build the index of all data stored and end the batch
+    pub fn finish(mut self) -> Result<Hammersbald, HammersbaldError> {
+        self.db.mem.bulk_index(self.added)?;
+        self.db.batch()?;
+        Ok(self.db)
+    }
+}
+
+impl HammersbaldAPI for Hammersbald {
+    /// initialize a db
+    fn init (&mut self) -> Result<(), HammersbaldError> {
+        self.check_writable_now()?;
+        self.mem.init()
+    }
+
+
+    /// end current batch and start a new batch
+    fn batch (&mut self)  -> Result<(), HammersbaldError> {
+        self.check_writable_now()?;
+        self.mem.batch()
+    }
+
+    /// stop background writer
+    fn shutdown (&mut self) {
+        self.mem.shutdown()
+    }
+
+    /// store data with a key
+    /// storing with the same key makes previous data unaddressable
+    fn put(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        self.put_tagged(key, data, referred, 0)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        trace_span!("get", key_len = key.len());
+        self.mem.get(&self.transformed_key(key))
+    }
+
+    fn put_referred(&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        trace_span!("put_referred", data_len = data.len(), referred = referred.len());
+        self.check_writable()?;
+        validate_put(&[], data, referred)?;
+        let data_offset = self.mem.append_referred(data, referred)?;
+        #[cfg(debug_assertions)]
+        {
+            if referred.iter().any(|o| o.as_u64() >= data_offset.as_u64()) {
+                return Err(HammersbaldError::ForwardReference);
+            }
+        }
+        Ok(data_offset)
+    }
+
+    fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
+        let envelope = self.mem.get_envelope(pref)?;
+        match Payload::deserialize(envelope.payload())? {
+            Payload::Referred(referred) => return Ok((vec!(), referred.data.to_vec(), referred.referred())),
+            Payload::Indexed(indexed) => return Ok((indexed.key.to_vec(), indexed.data.data.to_vec(), indexed.data.referred())),
+            _ => Err(HammersbaldError::Corrupted("referred should point to data".to_string()))
+        }
+    }
+
+    fn dag(&self, root: PRef) -> DagIterator {
+        self.mem.dag(root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+    extern crate hex;
+
+    use transient::Transient;
+    use asyncfile::AsyncFile;
+    use cachedfile::CachedFile;
+    use pagedfile::PagedFile;
+    use page::Page;
+    use testdir::TestDir;
+
+    use super::*;
+    use self::rand::thread_rng;
+    use std::collections::{HashMap, HashSet};
+    use api::test::rand::RngCore;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_bulk_loader () {
+        let mut db = Transient::new_db("bulk", 1, 4).unwrap();
+        db.init().unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 100u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.insert(key.to_vec(), db.put(&key, &[1], &vec!()).unwrap());
+        }
+        db.batch().unwrap();
+
+        let mut loader = db.bulk_loader().unwrap();
+        let mut rng = thread_rng();
+        for _ in 0 .. 10000 {
+            let i = rng.next_u32() % 5000;
+            let key = [(i >> 8) as u8, i as u8];
+            let data = [i as u8; 10];
+            let leaf = loader.put_referred(&data, &vec!()).unwrap();
+            check.insert(key.to_vec(), loader.put(&key, &data, &vec!(leaf)).unwrap());
+        }
+        let mut db = loader.finish().unwrap();
+
+        // sized for the keys as incremental inserts would on average
+        assert!(db.buckets().count() >= check.len() / 4);
+        assert_eq!(db.slots().map(|s| s.unwrap().len()).sum::<usize>(), check.len());
+        for (key, pref) in &check {
+            let (p, _, referred) = db.get(key).unwrap().unwrap();
+            assert_eq!(p, *pref);
+            assert!(referred.is_empty() || db.dag(*pref).count() == 2);
+        }
+        // continues as incrementally built
+        db.put(&[0, 0], &[2], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(&[0, 0]).unwrap().unwrap().1, vec!(2));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dump () {
+        let mut db = Transient::new_db("dump", 1, 1).unwrap();
+        db.init().unwrap();
+        let leaf = db.put_referred(&[1], &vec!()).unwrap();
+        let old = db.put(&[2], &[2], &vec!(leaf)).unwrap();
+        db.put(&[2], &[3], &vec!(old)).unwrap();
+        db.put(&[4], &[4], &vec!()).unwrap();
+        // overwritten and not referred
+        db.put(&[4], &[5], &vec!()).unwrap();
+        for i in 0 .. 100u8 {
+            db.put(&[10, i], &[i], &vec!(leaf)).unwrap();
+        }
+        // an empty key is not referred data
+        db.put(&[], &[6], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(db.dump(&mut dump).unwrap(), 105);
+
+        // a dump cut within a record is not loaded silently
+        let mut cut = Transient::new_db("cut", 1, 1).unwrap();
+        cut.init().unwrap();
+        match cut.load_dump(&mut &dump[.. dump.len() - 1]) {
+            Err(HammersbaldError::IO(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            _ => panic!("expected UnexpectedEof")
+        }
+        cut.shutdown();
+
+        let mut loaded = Transient::new_db("loaded", 1, 1).unwrap();
+        loaded.init().unwrap();
+        assert_eq!(loaded.load_dump(&mut dump.as_slice()).unwrap(), 105);
+        loaded.batch().unwrap();
+
+        let keys = db.filter_entries(|_, _| true).map(|entry| entry.unwrap().0).collect::<HashSet<_>>();
+        assert_eq!(loaded.filter_entries(|_, _| true).map(|entry| entry.unwrap().0).collect::<HashSet<_>>(), keys);
+        for key in &keys {
+            let (pref, data, _) = db.get(key).unwrap().unwrap();
+            let (lpref, ldata, _) = loaded.get(key).unwrap().unwrap();
+            assert_eq!(data, ldata);
+            let dag = db.dag(pref).map(|entry| entry.unwrap().1.payload().to_vec()).collect::<Vec<_>>();
+            let ldag = loaded.dag(lpref).map(|entry| entry.unwrap().1.payload().to_vec()).collect::<Vec<_>>();
+            assert_eq!(dag.len(), ldag.len());
+        }
+        assert_eq!(loaded.get(&[2]).unwrap().unwrap().1, vec!(3));
+        assert_eq!(loaded.dag(loaded.get(&[2]).unwrap().unwrap().0).count(), 3);
+        assert_eq!(loaded.get(&[]).unwrap().unwrap().1, vec!(6));
+        db.shutdown();
+        loaded.shutdown();
+    }
+
+    #[test]
+    fn test_pref_of () {
+        let mut db = Transient::new_db("pref_of", 1, 1).unwrap();
+        db.init().unwrap();
+        let pref = db.put(&[1], &[1; 100], &vec!()).unwrap();
+        assert_eq!(db.pref_of(&[1]).unwrap(), Some(pref));
+        let pref = db.put(&[1], &[2], &vec!()).unwrap();
+        assert_eq!(db.pref_of(&[1]).unwrap(), Some(pref));
+        assert_eq!(db.pref_of(&[2]).unwrap(), None);
+        db.batch().unwrap();
+        assert_eq!(db.pref_of(&[1]).unwrap(), Some(pref));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_prepared () {
+        let mut db = Transient::new_db("get_prepared", 1, 1).unwrap();
+        db.init().unwrap();
+        let prepared = db.prepare_key(&[1]);
+        assert_eq!(db.get_prepared(&prepared).unwrap(), None);
+        let pref = db.put(&[1], &[1], &vec!()).unwrap();
+        assert_eq!(db.get_prepared(&prepared).unwrap(), Some((pref, vec!(1), vec!())));
+        for i in 0 .. 1000u32 {
+            db.put(&[(i >> 8) as u8, i as u8, 0], &[2], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        assert_eq!(db.get_prepared(&prepared).unwrap(), db.get(&[1]).unwrap());
+
+        let mut other = Transient::new_db("get_prepared_other", 1, 1).unwrap();
+        other.init().unwrap();
+        match other.get_prepared(&prepared) {
+            Err(HammersbaldError::ForeignPreparedKey) => {},
+            _ => panic!("prepared key of another db should be rejected")
+        }
+        other.shutdown();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_app_trailer () {
+        let mut db = Transient::new_db_with_options("app_trailer", Options { app_trailer_bytes: 4, .. Options::new(1, 1) }).unwrap();
+        db.init().unwrap();
+        let mut check = Vec::new();
+        for i in 0 .. 1000u32 {
+            db.write_app_trailer(&[0, 0, (i >> 8) as u8, i as u8]).unwrap();
+            let key = [(i >> 8) as u8, i as u8];
+            check.push((key, db.put(&key, &[i as u8; 50], &vec!()).unwrap(), i));
+            if i % 100 == 0 {
+                db.batch().unwrap();
+            }
+        }
+        db.batch().unwrap();
+        let mut previous = 0;
+        for (key, pref, i) in &check {
+            assert_eq!(db.get(key).unwrap(), Some((*pref, vec!(*i as u8; 50), vec!())));
+            // the trailer of a page is that set when the page was completed
+            let trailer = db.read_app_trailer(*pref).unwrap();
+            let generation = ((trailer[2] as u32) << 8) | trailer[3] as u32;
+            assert!(generation >= *i && generation >= previous);
+            previous = generation;
+        }
+        assert_eq!(db.data_envelopes().count(), 1000);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_key_set () {
+        let mut db = Transient::new_db("key_set", 1, 1).unwrap();
+        db.init().unwrap();
+        let mut check = HashSet::new();
+        for i in 0 .. 1000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            db.put(&key, &[1], &vec!()).unwrap();
+            check.insert(key.to_vec());
+        }
+        // overwritten versions are not repeated
+        for i in 0 .. 100u32 {
+            db.put(&[(i >> 8) as u8, i as u8], &[2], &vec!()).unwrap();
+        }
+        db.put_referred(&[3], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.key_set().unwrap(), check);
+        let mut n = 0;
+        db.for_each_key(|_| n += 1).unwrap();
+        assert_eq!(n, 1000);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag_to_dot () {
+        let mut db = Transient::new_db("dot", 1, 1).unwrap();
+        db.init().unwrap();
+        let leaf = db.put_referred(&[1, 2, 3, 4, 5, 6, 7, 8, 9], &vec!()).unwrap();
+        let left = db.put_referred(&[2], &vec!(leaf)).unwrap();
+        let right = db.put_referred(&[3], &vec!(leaf)).unwrap();
+        let root = db.put(&[0], &[4], &vec!(left, right, leaf)).unwrap();
+        db.batch().unwrap();
+        let mut dot = Vec::new();
+        db.dag_to_dot(root, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph dag {"));
+        assert_eq!(dot.matches("[label=").count(), 4);
+        assert_eq!(dot.matches(" -> ").count(), 5);
+        assert!(dot.contains(&format!("n{} [label=\"{}\\n0102030405060708..\"];", leaf.as_u64(), leaf)));
+        assert!(dot.contains(&format!("n{} -> n{};", left.as_u64(), leaf.as_u64())));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag_disk_visited () {
+        let mut db = Transient::new_db("disk_visited", 1, 1).unwrap();
+        db.init().unwrap();
+        // each node refers to the two before, a plain dag would yield exponentially many
+        let mut prefs = vec!(db.put_referred(&[0], &vec!()).unwrap());
+        prefs.push(db.put_referred(&[1], &vec!(prefs[0])).unwrap());
+        for i in 2 .. 10000u32 {
+            let referred = vec!(prefs[i as usize - 1], prefs[i as usize - 2]);
+            prefs.push(db.put_referred(&[i as u8; 10], &referred).unwrap());
+        }
+        let root = db.put(&[0], &[0], &vec!(prefs[9999], prefs[5000])).unwrap();
+        db.batch().unwrap();
+
+        let dir = TestDir::new("visited");
+        let scratch = dir.path().join("visited");
+        let mut seen = HashSet::new();
+        for entry in db.dag_disk_visited(root, Some(&scratch)).unwrap() {
+            let (pref, _) = entry.unwrap();
+            assert!(seen.insert(pref));
+        }
+        assert_eq!(seen.len(), 10001);
+        assert!(::std::fs::metadata(&scratch).unwrap().len() <= db.stats().data_bytes / 8 + 1);
+        // a small dag yields as dag
+        assert_eq!(db.dag_disk_visited(prefs[1], Some(&scratch)).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>(), db.dag(prefs[1]).map(|entry| entry.unwrap().0).collect::<Vec<_>>());
+        // an error writing the visited set ends the iteration
+        let mut dag = db.dag(root).with_visited_file(::std::fs::File::open(&scratch).unwrap());
+        assert!(dag.next().unwrap().is_err());
+        assert!(dag.next().is_none());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_shared_reads () {
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<Hammersbald>();
+
+        let mut db = Transient::new_db("shared", 1, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 10000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            db.put(&key, &key, &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        let db = Arc::new(db);
+        let readers = (0 .. 4).map(|t| {
+            let db = db.clone();
+            ::std::thread::spawn(move || {
+                for i in 0 .. 10000u32 {
+                    let key = [(i >> 8) as u8, i as u8];
+                    assert_eq!(db.get(&key).unwrap().unwrap().1, key.to_vec(), "reader {}", t);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        Arc::try_unwrap(db).ok().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_min_free_bytes () {
+        let free = Arc::new(AtomicUsize::new(1 << 21));
+        let asked = Arc::new(AtomicUsize::new(0));
+        let reported = free.clone();
+        let counted = asked.clone();
+        let options = Options { min_free_bytes: Some(1 << 16),
+            free_space: Some(Arc::new(move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(reported.load(Ordering::SeqCst) as u64)
+            })), .. Options::new(1, 1) };
+        let mut db = Transient::new_db_with_options("free", options).unwrap();
+        db.init().unwrap();
+        let pref = db.put(&[1], &[1], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        // asked at the batch and then once per FREE_SPACE_CHECK_BYTES stored
+        asked.store(0, Ordering::SeqCst);
+        for i in 0 .. 1000u32 {
+            db.put_referred(&[i as u8; 1000], &vec!()).unwrap();
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), 0);
+        for i in 0 .. 100u32 {
+            db.put_referred(&[i as u8; 1000], &vec!()).unwrap();
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+        db.batch().unwrap();
+
+        free.store(1000, Ordering::SeqCst);
+        // not asked before the batch
+        db.put(&[2], &[2], &vec!()).unwrap();
+        match db.batch() {
+            Err(HammersbaldError::DiskNearlyFull(1000)) => {},
+            other => panic!("batch should be refused: {:?}", other)
+        }
+        match db.put(&[2], &[2], &vec!()) {
+            Err(HammersbaldError::DiskNearlyFull(1000)) => {},
+            other => panic!("put should be refused: {:?}", other)
+        }
+        assert!(db.put_referred(&[3], &vec!()).is_err());
+        assert_eq!(db.get(&[1]).unwrap(), Some((pref, vec!(1), vec!())));
+
+        free.store(1 << 20, Ordering::SeqCst);
+        db.put(&[2], &[2], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(&[2]).unwrap().unwrap().1, vec!(2));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_stream () {
+        let mut db = Transient::new_db("stream", 1, 1).unwrap();
+        db.init().unwrap();
+        let data = (0 .. 100000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let pref = db.put_stream(&[1], &mut io::Cursor::new(data.clone()), 100000).unwrap();
+        let data_len = db.stats().data_bytes;
+        match db.put_stream(&[2], &mut io::Cursor::new(data.clone()), 99999) {
+            Err(HammersbaldError::ValueTooLarge(99999)) => {},
+            other => panic!("stream should be too long: {:?}", other)
+        }
+        assert!(db.put_stream(&[2], &mut io::repeat(1), usize::max_value()).is_err());
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "broken"))
+            }
+        }
+        assert!(db.put_stream(&[2], &mut io::Cursor::new(data.clone()).chain(Broken), 200000).is_err());
+        // nothing of the refused streams is stored
+        assert_eq!(db.stats().data_bytes, data_len);
+        db.batch().unwrap();
+        assert_eq!(db.get(&[1]).unwrap(), Some((pref, data, vec!())));
+        assert_eq!(db.get(&[2]).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_reachable () {
+        let mut db = Transient::new_db("reachable", 1, 1).unwrap();
+        db.init().unwrap();
+        let shared = db.put_referred(&[0], &vec!()).unwrap();
+        let inner = db.put_referred(&[1], &vec!(shared)).unwrap();
+        let left_only = db.put_referred(&[2], &vec!()).unwrap();
+        let left = db.put(&[1], &[3], &vec!(inner, left_only, shared)).unwrap();
+        let right_only = db.put_referred(&[4], &vec!(inner)).unwrap();
+        let right = db.put(&[2], &[5], &vec!(right_only, shared)).unwrap();
+        let unreached = db.put_referred(&[6], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        let reached = db.reachable(&[left, right]).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(reached.len(), 6);
+        assert_eq!(reached.iter().cloned().collect::<HashSet<_>>(),
+            vec!(shared, inner, left_only, left, right_only, right).into_iter().collect::<HashSet<_>>());
+        assert!(!reached.contains(&unreached));
+        assert_eq!(db.reachable(&[left, left]).count(), 4);
+        assert_eq!(db.reachable(&[]).count(), 0);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_value_pages () {
+        let mut db = Transient::new_db("value_pages", 1, 1).unwrap();
+        db.init().unwrap();
+        db.put(&[0], &[0; 100], &vec!()).unwrap();
+        let small = db.put(&[1], &[1; 10], &vec!()).unwrap();
+        let large = db.put(&[2], &[2; 3 * PAGE_SIZE], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.value_pages(&[1]).unwrap(), Some(vec!(small.this_page())));
+        let pages = db.value_pages(&[2]).unwrap().unwrap();
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0], large.this_page());
+        for (n, page) in pages.iter().enumerate() {
+            assert_eq!(page.as_u64(), large.this_page().as_u64() + (n * PAGE_SIZE) as u64);
+        }
+        assert_eq!(db.value_pages(&[3]).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_timestamps () {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000;
+        let mut db = Transient::new_db("timestamps", 1, 1).unwrap();
+        db.init().unwrap();
+        let plain = db.put(&[0], &[0], &vec!()).unwrap();
+        db.batch().unwrap();
+        let before = now();
+        for options in &[Options { record_timestamps: true, .. Options::new(1, 1) },
+            Options { record_timestamps: true, store_keys: false, .. Options::new(1, 1) },
+            Options { record_timestamps: true, fixed_value_size: Some(2), .. Options::new(1, 1) }] {
+            let mut timed = Transient::new_db_with_options("timed", options.clone()).unwrap();
+            timed.init().unwrap();
+            let mut times = Vec::new();
+            for i in 0 .. 100u32 {
+                let key = [(i >> 8) as u8, i as u8];
+                let pref = timed.put_tagged(&key, &key, &vec!(), i as u8).unwrap();
+                let (at, data, referred, time) = timed.get_with_time(&key).unwrap().unwrap();
+                assert_eq!((at, data, referred), (pref, key.to_vec(), vec!()));
+                times.push(time.unwrap());
+            }
+            timed.batch().unwrap();
+            assert!(times[0] >= before && times[99] <= now() + 1000);
+            assert!(times.windows(2).all(|w| w[0] <= w[1]));
+            // flags are kept
+            assert_eq!(timed.get_tagged(&[0, 7]).unwrap().unwrap().1, 7);
+            assert_eq!(timed.data_envelopes().count(), 100);
+            timed.shutdown();
+        }
+        assert_eq!(db.get_with_time(&[0]).unwrap(), Some((plain, vec!(0), vec!(), None)));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_status () {
+        let mut db = Transient::new_db("put_status", 1, 1).unwrap();
+        db.init().unwrap();
+        let (pref, replaced) = db.put_status(&[1], &[1], &vec!()).unwrap();
+        assert!(!replaced);
+        let (newer, replaced) = db.put_status(&[1], &[2], &vec!()).unwrap();
+        assert!(replaced);
+        assert!(newer != pref);
+        assert_eq!(db.get(&[1]).unwrap(), Some((newer, vec!(2), vec!())));
+        for i in 0 .. 1000u32 {
+            let key = [(i >> 8) as u8, i as u8, 0];
+            assert!(!db.put_status(&key, &[1], &vec!()).unwrap().1);
+        }
+        db.batch().unwrap();
+        for i in 0 .. 1000u32 {
+            let key = [(i >> 8) as u8, i as u8, 0];
+            assert!(db.put_status(&key, &[2], &vec!()).unwrap().1);
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_referred_deltas () {
+        let mut absolute = Transient::new_db("absolute", 1, 1).unwrap();
+        let mut deltas = Transient::new_db_with_options("deltas", Options { referred_deltas: true, .. Options::new(1, 1) }).unwrap();
+        absolute.init().unwrap();
+        deltas.init().unwrap();
+        let mut check = Vec::new();
+        for db in &mut [&mut absolute, &mut deltas] {
+            let mut prefs = vec!(db.put_referred(&[0], &vec!()).unwrap());
+            for i in 1 .. 1000u32 {
+                // refers to a few nearby and the first
+                let referred = prefs.iter().rev().take(3).cloned().chain(Some(prefs[0])).collect::<Vec<_>>();
+                let pref = if i % 2 == 0 {
+                    db.put_referred(&[i as u8], &referred).unwrap()
+                } else {
+                    db.put(&[(i >> 8) as u8, i as u8], &[i as u8], &referred).unwrap()
+                };
+                prefs.push(pref);
+            }
+            db.batch().unwrap();
+            check.push((prefs.clone(), prefs.iter().map(|p| db.get_referred(*p).unwrap()).collect::<Vec<_>>()));
+        }
+        // same content, different positions
+        assert_eq!(check[0].1.iter().map(|(k, d, r)| (k.clone(), d.clone(), r.len())).collect::<Vec<_>>(),
+                   check[1].1.iter().map(|(k, d, r)| (k.clone(), d.clone(), r.len())).collect::<Vec<_>>());
+        let (ref prefs, ref entries) = check[1];
+        for (i, (_, _, referred)) in entries.iter().enumerate().skip(1) {
+            let expected = prefs[.. i].iter().rev().take(3).cloned().chain(Some(prefs[0])).collect::<Vec<_>>();
+            assert_eq!(referred, &expected);
+        }
+        assert_eq!(deltas.get(&[0, 1]).unwrap().unwrap().2, entries[1].2);
+        // the second refers to the first twice
+        assert_eq!(deltas.dag(prefs[1]).map(|entry| entry.unwrap().0).collect::<Vec<_>>(), vec!(prefs[1], prefs[0], prefs[0]));
+        // 4 referred of 6 bytes each against 1 to 3 bytes
+        assert!(deltas.data_tip().as_u64() * 10 < absolute.data_tip().as_u64() * 7);
+        absolute.shutdown();
+        deltas.shutdown();
+    }
+
+    #[test]
+    fn test_dag_entries () {
+        let mut db = Transient::new_db("dag_entries", 1, 1).unwrap();
+        db.init().unwrap();
+        let first = db.put_referred(&[1], &vec!()).unwrap();
+        let second = db.put_referred(&[2; 5000], &vec!(first)).unwrap();
+        let third = db.put(&[3], &[3], &vec!(first)).unwrap();
+        let root = db.put(&[4], &[4], &vec!(second, third)).unwrap();
+        db.batch().unwrap();
+        let entries = db.dag_entries(root).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.iter().map(|(pref, _, _)| *pref).collect::<Vec<_>>(),
+                   db.dag(root).map(|entry| entry.unwrap().0).collect::<Vec<_>>());
+        for (pref, data, referred) in entries {
+            let (_, d, r) = db.get_referred(pref).unwrap();
+            assert_eq!((data, referred), (d, r));
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag_corrupt () {
+        let mut db = Transient::new_db("dag_corrupt", 1, 1).unwrap();
+        db.init().unwrap();
+        // data that reads as an envelope of an unknown payload type
+        let mut data = vec!(0, 0, 20, 0, 0, 0, 0, 0, 0, 0xee);
+        data.extend_from_slice(&[0; 30]);
+        let pref = db.put_referred(&data, &vec!()).unwrap();
+        let root = db.put_referred(&[1], &vec!(pref + 13)).unwrap();
+        db.batch().unwrap();
+        let mut dag = db.dag(root);
+        assert_eq!(dag.next().unwrap().unwrap().0, root);
+        assert!(dag.next().unwrap().is_err());
+        assert!(dag.next().is_none());
+        let entries = db.dag_entries(root).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[1].is_err());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_ref () {
+        let mut db = Transient::new_db("get_ref", 1, 1).unwrap();
+        db.init().unwrap();
+        let leaf = db.put_referred(&[1], &vec!()).unwrap();
+        let pref = db.put(&[1], &[2; 100], &vec!(leaf)).unwrap();
+        db.put(&[2], &[3; 10000], &vec!()).unwrap();
+        db.batch().unwrap();
+        {
+            let entry = db.get_ref(&[1]).unwrap().unwrap();
+            assert_eq!(entry.pref(), pref);
+            assert_eq!(entry.data(), &[2; 100][..]);
+            assert_eq!(entry.referred(), &[leaf][..]);
+            // the cached page is shared, not copied
+            assert_eq!(db.get_ref(&[1]).unwrap().unwrap().data().as_ptr(), entry.data().as_ptr());
+            // spanning pages
+            assert_eq!(db.get_ref(&[2]).unwrap().unwrap().data(), &[3; 10000][..]);
+            assert!(db.get_ref(&[3]).unwrap().is_none());
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_content_digest () {
+        let mut first = Transient::new_db("first", 1, 1).unwrap();
+        first.init().unwrap();
+        let mut second = Transient::new_db("second", 1, 2).unwrap();
+        second.init().unwrap();
+        assert_eq!(first.content_digest().unwrap(), second.content_digest().unwrap());
+
+        for i in 0 .. 500u32 {
+            first.put(&[i as u8, (i >> 8) as u8], &[i as u8; 10], &vec!()).unwrap();
+        }
+        first.put(&[1, 0], &[9], &vec!()).unwrap();
+        first.batch().unwrap();
+        second.put(&[1, 0], &[8], &vec!()).unwrap();
+        for i in (0 .. 500u32).rev() {
+            let leaf = second.put_referred(&[1], &vec!()).unwrap();
+            second.put(&[i as u8, (i >> 8) as u8], &[i as u8; 10], &vec!(leaf)).unwrap();
+        }
+        second.batch().unwrap();
+        // the overwrite of [1, 0] is not yet repeated
+        assert_ne!(first.content_digest().unwrap(), second.content_digest().unwrap());
+        second.put(&[1, 0], &[9], &vec!()).unwrap();
+        assert_eq!(first.content_digest().unwrap(), second.content_digest().unwrap());
+        first.shutdown();
+        second.shutdown();
+    }
+
+    #[test]
+    fn test_put_from_slice () {
+        let mut db = Transient::new_db("slice", 1, 1).unwrap();
+        db.init().unwrap();
+        let source = (0 .. 20000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let first = db.put_from_slice(&[1], &source[100 .. 200]).unwrap();
+        let second = db.put_from_slice(&[2], &source[5000 .. 15000]).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(&[1]).unwrap(), Some((first, source[100 .. 200].to_vec(), vec!())));
+        assert_eq!(db.get(&[2]).unwrap(), Some((second, source[5000 .. 15000].to_vec(), vec!())));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_append_contiguous () {
+        let mut db = Transient::new_db("contiguous", 1, 1).unwrap();
+        db.init().unwrap();
+        let before = db.put(&[1], &[1], &vec!()).unwrap();
+        let value = (0 .. 1 << 20).map(|i| i as u8).collect::<Vec<u8>>();
+        let pref = db.append_contiguous(&value).unwrap();
+        let end = db.data_tip();
+        let after = db.put(&[2], &[2], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        assert!(before < pref && end <= after);
+        // only the value is stored between its start and end
+        assert_eq!(db.data_envelopes_range(pref, end).map(|(pos, _)| pos).collect::<Vec<_>>(), vec!(pref));
+        // and page trailers
+        assert!(end.as_u64() - pref.as_u64() < value.len() as u64 * 101 / 100);
+        assert_eq!(db.get_referred(pref).unwrap().1, value);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_fixed_value_size () {
+        let mut fixed = Transient::new_db_with_options("fixed", Options { fixed_value_size: Some(40), .. Options::new(1, 1) }).unwrap();
+        fixed.init().unwrap();
+        let mut variable = Transient::new_db("variable", 1, 1).unwrap();
+        variable.init().unwrap();
+
+        let mut check = Vec::new();
+        for i in 0 .. 10000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.push((key, fixed.put(&key, &[i as u8; 40], &vec!()).unwrap()));
+            variable.put(&key, &[i as u8; 40], &vec!()).unwrap();
+        }
+        let referred = fixed.put_referred(&[1; 40], &vec!()).unwrap();
+        fixed.batch().unwrap();
+        variable.batch().unwrap();
+
+        for (key, pref) in &check {
+            assert_eq!(fixed.get(key).unwrap(), Some((*pref, vec!(key[1]; 40), vec!())));
+        }
+        assert_eq!(fixed.get_referred(referred).unwrap(), (vec!(), vec!(1; 40), vec!()));
+        assert_eq!(fixed.data_envelopes().count(), 10001);
+        assert_eq!(fixed.dag(referred).count(), 1);
+        // 3 bytes less for each entry
+        let saved = variable.data_tip().as_u64() - fixed.data_tip().as_u64();
+        assert!(saved >= 10000 * 3 - PAGE_SIZE as u64);
+
+        let flagged = fixed.put_tagged(&[1, 1, 1], &[2; 40], &vec!(), 3).unwrap();
+        assert_eq!(fixed.get_tagged(&[1, 1, 1]).unwrap(), Some((flagged, 3, vec!(2; 40), vec!())));
+        assert!(fixed.put(&[1], &[1; 39], &vec!()).is_err());
+        assert!(fixed.put(&[1], &[1; 40], &vec!(referred)).is_err());
+        assert!(fixed.put_referred(&[1; 41], &vec!()).is_err());
+        fixed.shutdown();
+        variable.shutdown();
+    }
+
+    #[test]
+    fn test_append_raw_entry () {
+        let mut leader = Transient::new_db("leader", 1, 1).unwrap();
+        leader.init().unwrap();
+        let mut follower = Transient::new_db("follower", 1, 1).unwrap();
+        follower.init().unwrap();
+
+        let leaf = leader.put_referred(&[1, 2, 3], &vec!()).unwrap();
+        leader.put_tagged(&[4], &[5, 6], &vec!(leaf), 7).unwrap();
+        for (pref, envelope) in leader.data_envelopes().collect::<Vec<_>>().into_iter().rev() {
+            assert_eq!(follower.append_raw_entry(envelope.payload()).unwrap(), pref);
+        }
+        assert_eq!(follower.get_referred(leaf).unwrap(), (vec!(), vec!(1, 2, 3), vec!()));
+        assert_eq!(follower.get_tagged(&[4]).unwrap(), leader.get_tagged(&[4]).unwrap());
+
+        // a link and data referring forward
+        assert!(follower.append_raw_entry(&[2, 0, 0]).is_err());
+        assert!(follower.append_raw_entry(&[1, 0, 0, 1, 9, 0, 0, 0, 1, 0, 0]).is_err());
+        leader.shutdown();
+        follower.shutdown();
+    }
+
+    #[test]
+    fn test_buckets_with_fill () {
+        let mut db = Transient::new_db("fill", 1, 1).unwrap();
+        db.init().unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 2000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.insert(db.put(&key, &[1], &vec!()).unwrap(), key);
+        }
+        db.batch().unwrap();
+
+        let fill = db.buckets_with_fill().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(fill.len(), db.slots().count());
+        assert_eq!(fill.iter().map(|(_, _, n)| n).sum::<usize>(), 2000);
+        assert!(fill.iter().enumerate().all(|(i, (n, _, _))| i == *n));
+        for ((_, link, count), (slots, stored)) in fill.iter().zip(db.slots().zip(db.buckets())) {
+            let slots = slots.unwrap();
+            assert_eq!(*link, stored);
+            assert_eq!(*count, slots.len());
+            assert_eq!(link.is_valid(), *count > 0);
+            // keys of the bucket are found at the prefs of its slots
+            for (_, pref) in slots {
+                let key = check[&pref];
+                assert_eq!(db.get(&key).unwrap().unwrap().0, pref);
+            }
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_key_transform () {
+        use std::sync::Arc;
+
+        let upper: KeyTransform = Arc::new(|key: &[u8]| key.to_ascii_uppercase());
+        let options = Options { key_transform: Some(upper), .. Options::new(1, 1) };
+        let mut db = Transient::new_db_with_options("transform", options).unwrap();
+        db.init().unwrap();
+        let pref = db.put(b"Foo", &[1], &vec!()).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        assert_eq!(db.pref_of(b"FOO").unwrap(), Some(pref));
+        assert_eq!(db.get_referred(pref).unwrap().0, b"FOO".to_vec());
+        let pref = db.put(b"fOO", &[2], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(b"Foo").unwrap(), Some((pref, vec!(2), vec!())));
+        assert_eq!(db.history(b"foo").unwrap().len(), 2);
+        assert_eq!(db.get(b"bar").unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_audit_references () {
+        let mut db = Transient::new_db("audit", 1, 1).unwrap();
+        db.init().unwrap();
+        let leaf = db.put_referred(&[1], &vec!()).unwrap();
+        db.put(&[1], &[2], &vec!(leaf)).unwrap();
+        assert!(db.audit_references().unwrap().is_empty());
+
+        // as a release build would store it
+        let position = db.mem.data_len();
+        let bad = db.mem.append_data(&[2], &[3], &vec!(leaf, PRef::from(position + 1000)), 0).unwrap();
+        let own = db.mem.append_referred(&[4], &vec!(PRef::from(db.mem.data_len()))).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.audit_references().unwrap(), vec!((bad, PRef::from(position + 1000)), (own, own)));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_two_batches () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+
+        let mut rng = thread_rng();
+
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = db.put(&key, &data, &vec!()).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.batch().unwrap();
+
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get(&k[..]).unwrap(), Some((*o, v.to_vec(), vec!())));
+        }
+
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = db.put(&key, &data, &vec!()).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.batch().unwrap();
+
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get(&k[..]).unwrap(), Some((*o, v.to_vec(), vec!())));
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag_reader () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+
+        let mut rng = thread_rng();
+        let mut object = vec!(0u8; 10000);
+        rng.fill_bytes(&mut object);
+
+        // referred are read latest first, so store chunks in reverse
+        let mut chunks = vec!();
+        for chunk in object.chunks(3000).rev() {
+            chunks.push(db.put_referred(chunk, &vec!()).unwrap());
+        }
+        let root = db.put(&[1u8;32], &[], &chunks).unwrap();
+        db.batch().unwrap();
+
+        let mut read = vec!();
+        io::copy(&mut db.dag_reader(root), &mut read).unwrap();
+        assert_eq!(read, object);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_split_once () {
+        let mut db = Transient::new_db("first", 1, 128).unwrap();
+        db.init().unwrap();
+
+        let mut check = HashMap::new();
+        for i in 0 .. 100u32 {
+            let key = [i as u8; 32];
+            check.insert(key, db.put(&key, &[i as u8; 40], &vec!()).unwrap());
+        }
+
+        let (step, log_mod, buckets, _, _, _, _, _) = db.params();
+        let mut splits = 0;
+        while db.params().1 == log_mod {
+            assert!(db.split_once().unwrap());
+            splits += 1;
+            let (s, _, b, _, _, _, _, _) = db.params();
+            assert_eq!(b, buckets + splits);
+            if s != 0 {
+                assert_eq!(s, step + splits);
+            }
+        }
+        assert_eq!(db.params().0, 0);
+        assert_eq!(db.params().1, log_mod + 1);
+        db.batch().unwrap();
+
+        for (k, o) in check.iter() {
+            assert_eq!(db.get(&k[..]).unwrap().unwrap().0, *o);
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_hashed_keys () {
+        let mut with_keys = Transient::new_db("first", 1, 1).unwrap();
+        with_keys.init().unwrap();
+        let mut without_keys = Transient::new_db_with_options("second", Options { store_keys: false, .. Options::new(1, 1) }).unwrap();
+        without_keys.init().unwrap();
+
+        let mut rng = thread_rng();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = with_keys.put(&key, &data, &vec!()).unwrap();
+            let hashed = without_keys.put(&key, &data, &vec!()).unwrap();
+            check.insert(key, (pref, hashed, data));
+        }
+        with_keys.batch().unwrap();
+        without_keys.batch().unwrap();
+
+        for (k, (pref, hashed, v)) in check.iter() {
+            assert_eq!(with_keys.get(&k[..]).unwrap(), Some((*pref, v.to_vec(), vec!())));
+            assert_eq!(with_keys.key_of(*pref).unwrap(), Some(k.to_vec()));
+            assert_eq!(without_keys.get(&k[..]).unwrap(), Some((*hashed, v.to_vec(), vec!())));
+            assert_eq!(without_keys.key_of(*hashed).unwrap(), None);
+        }
+        // each entry saves the key and its length
+        let with_len = with_keys.params().4;
+        let without_len = without_keys.params().4;
+        assert!(without_len < with_len);
+        assert!(with_len - without_len >= (1000 * 33 / PAGE_SIZE as u64 - 1) * PAGE_SIZE as u64);
+
+        with_keys.shutdown();
+        without_keys.shutdown();
+    }
+
+    #[test]
+    fn test_data_envelopes_range () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+
+        let mut first = HashSet::new();
+        for i in 0 .. 1000u32 {
+            first.insert(db.put(&[i as u8, (i >> 8) as u8], &[i as u8; 40], &vec!()).unwrap());
+        }
+        db.batch().unwrap();
+        let boundary = PRef::from(db.params().4);
+
+        let mut second = HashSet::new();
+        for i in 1000 .. 2000u32 {
+            second.insert(db.put(&[i as u8, (i >> 8) as u8], &[i as u8; 40], &vec!()).unwrap());
+        }
+        db.batch().unwrap();
+        let end = PRef::from(db.params().4);
+
+        let shipped_first = db.data_envelopes_range(PRef::from(0), boundary).map(|(pos, _)| pos).collect::<HashSet<_>>();
+        let shipped_second = db.data_envelopes_range(boundary, end).map(|(pos, _)| pos).collect::<HashSet<_>>();
+        assert_eq!(shipped_first, first);
+        assert_eq!(shipped_second, second);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_data_tip () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+
+        for i in 0 .. 100u32 {
+            db.put(&[i as u8], &[i as u8; 40], &vec!()).unwrap();
+        }
+        let tip = db.data_tip();
+        let mut added = HashSet::new();
+        for i in 100 .. 200u32 {
+            added.insert(db.put(&[i as u8], &[i as u8; 40], &vec!()).unwrap());
+        }
+        assert_eq!(db.data_envelopes_range(tip, db.data_tip()).map(|(pos, _)| pos).collect::<HashSet<_>>(), added);
+        db.batch().unwrap();
+        assert_eq!(db.data_envelopes_range(tip, db.data_tip()).map(|(pos, _)| pos).collect::<HashSet<_>>(), added);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_changed_since () {
+        let mut db = Transient::new_db("changed", 1, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 100u8 {
+            db.put(&[i], &[0], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        let checkpoint = db.data_tip();
+        let mut expected = HashMap::new();
+        for round in 1 .. 3u8 {
+            for i in (0 .. 100u8).filter(|i| i % 10 == 0) {
+                expected.insert(vec!(i), db.put(&[i], &[round], &vec!()).unwrap());
+            }
+            db.put_referred(&[round], &vec!()).unwrap();
+        }
+        let changed = db.changed_since(checkpoint).collect::<Vec<_>>();
+        assert_eq!(changed.len(), 10);
+        assert_eq!(changed.into_iter().collect::<HashMap<_, _>>(), expected);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_filter_entries () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+
+        let mut expected = HashSet::new();
+        for i in 0 .. 300u32 {
+            let key = [i as u8, (i >> 8) as u8];
+            let pref = db.put(&key, &[(i % 7) as u8; 40], &vec!()).unwrap();
+            if i % 7 == 3 {
+                expected.insert((key.to_vec(), pref));
+            }
+        }
+        // overwritten data is not accessible
+        db.put(&[3, 0], &[0u8; 40], &vec!()).unwrap();
+        expected.retain(|(key, _)| *key != vec!(3, 0));
+        db.put_referred(&[3u8; 40], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        let found = db.filter_entries(|_, data| data[0] == 3).collect::<Result<HashSet<_>, _>>().unwrap();
+        assert_eq!(found.len(), 42);
+        assert_eq!(found, expected);
+        db.shutdown();
+    }
+
+    // a file that silently drops one appended page
+    struct Dropping {
+        file: Transient,
+        drop: usize,
+        appended: usize
+    }
+
+    impl PagedFile for Dropping {
+        fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> { self.file.read_page(pref) }
+        fn len(&self) -> Result<u64, HammersbaldError> { self.file.len() }
+        fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> { self.file.truncate(new_len) }
+        fn sync(&self) -> Result<(), HammersbaldError> { self.file.sync() }
+        fn shutdown(&mut self) {}
+        fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> {
+            self.appended += 1;
+            if self.appended == self.drop {
+                return Ok(());
+            }
+            self.file.append_page(page)
+        }
+        fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> { self.file.update_page(page) }
+        fn flush(&mut self) -> Result<(), HammersbaldError> { self.file.flush() }
+        fn queue_len(&self) -> usize { 0 }
+    }
+
+    fn db_with_data_file (data: Box<PagedFile>) -> Hammersbald {
+        db_with_data_file_and_options(data, &Options::new(100, 1))
+    }
+
+    fn db_with_data_file_and_options (data: Box<PagedFile>, options: &Options) -> Hammersbald {
+        let log = LogFile::new(Box::new(AsyncFile::new(Box::new(Transient::new(true))).unwrap()));
+        let table = TableFile::new(Box::new(CachedFile::new(Box::new(Transient::new(false)), 100).unwrap())).unwrap();
+        let data = DataFile::new(Box::new(CachedFile::new(data, 100).unwrap())).unwrap();
+        let link = DataFile::new(Box::new(CachedFile::new(Box::new(Transient::new(true)), 100).unwrap())).unwrap();
+        Hammersbald::new_with_options(log, table, data, link, options).unwrap()
+    }
+
+    // a file whose sync fails as often as set in failures
+    struct FailingSync {
+        file: Transient,
+        failures: Arc<AtomicUsize>
+    }
+
+    impl PagedFile for FailingSync {
+        fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> { self.file.read_page(pref) }
+        fn len(&self) -> Result<u64, HammersbaldError> { self.file.len() }
+        fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> { self.file.truncate(new_len) }
+        fn sync(&self) -> Result<(), HammersbaldError> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::Other, "sync failed")));
+            }
+            self.file.sync()
+        }
+        fn shutdown(&mut self) {}
+        fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> { self.file.append_page(page) }
+        fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> { self.file.update_page(page) }
+        fn flush(&mut self) -> Result<(), HammersbaldError> { self.file.flush() }
+        fn queue_len(&self) -> usize { 0 }
+    }
+
+    #[test]
+    fn test_batch_retries () {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let options = Options { batch_retries: 2, .. Options::new(100, 1) };
+        let mut db = db_with_data_file_and_options(Box::new(FailingSync{file: Transient::new(true), failures: failures.clone()}), &options);
+        db.init().unwrap();
+        for i in 0 .. 1000u32 {
+            db.put(&[(i >> 8) as u8, i as u8], &[1], &vec!()).unwrap();
+        }
+        let link_len = db.params().5;
+        failures.store(2, Ordering::SeqCst);
+        db.batch().unwrap();
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+        // failed attempts leave no links behind, the first link is where the batch started
+        let links = db.buckets_with_fill().map(|b| b.unwrap().1).filter(|link| link.is_valid()).collect::<Vec<_>>();
+        assert_eq!(links.iter().min().unwrap().as_u64(), link_len);
+        for i in 0 .. 1000u32 {
+            assert_eq!(db.get(&[(i >> 8) as u8, i as u8]).unwrap().unwrap().1, vec!(1));
+        }
+        // more failures than retries
+        db.put(&[9, 9, 9], &[2], &vec!()).unwrap();
+        let link_len = db.params().5;
+        failures.store(3, Ordering::SeqCst);
+        assert!(db.batch().is_err());
+        assert_eq!(db.params().5, link_len);
+        assert!(db.is_dirty());
+        db.batch().unwrap();
+        assert_eq!(db.get(&[9, 9, 9]).unwrap().unwrap().1, vec!(2));
+        assert!(db.params().5 > link_len);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_chunk_unavailable () {
+        use rolledfile::RolledFile;
+        use std::fs;
+
+        let dir = TestDir::new("chunk");
+        let name = dir.name("testdb");
+        let chunk_size = 2 * PAGE_SIZE as u64;
+
+        let mut prefs = Vec::new();
+        {
+            let mut db = db_with_data_file(Box::new(RolledFile::new(name.as_str(), "bc", true, chunk_size).unwrap()));
+            db.init().unwrap();
+            for i in 0 .. 250u8 {
+                prefs.push(db.put_referred(&[i; 100], &vec!()).unwrap());
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        // a chunk before the last
+        fs::remove_file(RolledFile::chunk_name(name.as_str(), 1, "bc")).unwrap();
+
+        let mut db = db_with_data_file(Box::new(RolledFile::new(name.as_str(), "bc", true, chunk_size).unwrap()));
+        let cold = *prefs.iter().find(|p| p.as_u64() / chunk_size == 1).unwrap();
+        match db.get_referred(cold) {
+            Err(HammersbaldError::ChunkUnavailable(chunk, pref)) => {
+                assert_eq!(chunk, 1);
+                assert_eq!(pref.as_u64() / chunk_size, 1);
+            },
+            _ => panic!("expected ChunkUnavailable")
+        }
+        assert_eq!(db.get_referred(prefs[0]).unwrap().1, vec!(0u8; 100));
+        db.shutdown();
+    }
+
+    #[cfg(feature="trace")]
+    #[test]
+    fn test_trace_spans () {
+        use tracing::{span, Event, Metadata, Subscriber};
+        use std::sync::Mutex;
+
+        // names of spans created, with their fields
+        struct Capture {
+            spans: Arc<Mutex<Vec<(String, Vec<String>)>>>,
+            next: AtomicUsize
+        }
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata) -> bool { true }
+            fn new_span(&self, span: &span::Attributes) -> span::Id {
+                let metadata = span.metadata();
+                let fields = metadata.fields().iter().map(|f| f.name().to_string()).collect();
+                self.spans.lock().unwrap().push((metadata.name().to_string(), fields));
+                span::Id::from_u64(self.next.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, _: &Event) {}
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let capture = Capture { spans: spans.clone(), next: AtomicUsize::new(0) };
+        ::tracing::subscriber::with_default(capture, || {
+            let mut db = Transient::new_db("first", 1, 1).unwrap();
+            db.init().unwrap();
+            db.put(&[1, 2], &[3], &vec!()).unwrap();
+            assert_eq!(db.get(&[1, 2]).unwrap().unwrap().1, vec!(3));
+            db.batch().unwrap();
+            db.shutdown();
+        });
+        let spans = spans.lock().unwrap();
+        let fields_of = |name: &str| spans.iter().find(|(n, _)| n == name).map(|(_, fields)| fields.clone()).unwrap();
+        assert_eq!(fields_of("put"), vec!("key_len", "data_len", "referred"));
+        assert_eq!(fields_of("get"), vec!("key_len"));
+        assert_eq!(fields_of("batch"), vec!("data_len", "buckets"));
+    }
+
+    #[test]
+    fn test_split_state () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        assert_eq!((db.bucket_count(), db.split_step(), db.log_mod()), (512, 0, 8));
+        let mut last = db.bucket_count();
+        for i in 0 .. 300u32 {
+            db.put(&[i as u8, (i >> 8) as u8], &[1], &vec!()).unwrap();
+            assert!(db.bucket_count() >= last);
+            last = db.bucket_count();
+        }
+        assert_eq!(db.log_mod(), 8);
+        assert!(db.split_step() > 0);
+        assert_eq!(db.bucket_count(), 512 + db.split_step() as usize);
+
+        for i in 300 .. 1000u32 {
+            db.put(&[i as u8, (i >> 8) as u8], &[1], &vec!()).unwrap();
+        }
+        // a round of 513 splits ended
+        assert_eq!(db.log_mod(), 9);
+        assert_eq!(db.bucket_count(), 512 + 513 + db.split_step() as usize);
+        assert_eq!(db.params().0, db.split_step() as usize);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_stream_buckets () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 2000u32 {
+            db.put(&[i as u8, (i >> 8) as u8], &[1], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        let streamed = db.stream_buckets().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(streamed.len() > 512);
+        assert_eq!(streamed, db.slots().map(|slots| slots.unwrap()).enumerate().collect::<Vec<_>>());
+
+        // changes are streamed after the batch
+        db.put(&[0xff, 0xff], &[1], &vec!()).unwrap();
+        assert_eq!(db.stream_buckets().map(|bucket| bucket.unwrap().1.len()).sum::<usize>(), 2000);
+        db.batch().unwrap();
+        assert_eq!(db.stream_buckets().map(|bucket| bucket.unwrap().1.len()).sum::<usize>(), 2001);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_validate_put () {
+        assert!(validate_put(&[0; MAX_KEY_LEN], &[0; 10], &[PRef::from(0)]).is_ok());
+        match validate_put(&[0; MAX_KEY_LEN + 1], &[], &[]) {
+            Err(HammersbaldError::KeyTooLong(MAX_KEY_LEN)) => {},
+            _ => panic!("expected KeyTooLong")
+        }
+        match validate_put(&[], &vec!(0; MAX_DATA_LEN + 1), &[]) {
+            Err(HammersbaldError::ValueTooLarge(MAX_DATA_LEN)) => {},
+            _ => panic!("expected ValueTooLarge")
+        }
+        match validate_put(&[], &[], &vec!(PRef::from(0); MAX_REFERRED + 1)) {
+            Err(HammersbaldError::TooManyReferences(MAX_REFERRED)) => {},
+            _ => panic!("expected TooManyReferences")
+        }
+
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        assert!(db.put(&[0; MAX_KEY_LEN + 1], &[1], &vec!()).is_err());
+        assert!(db.put_referred(&vec!(0; MAX_DATA_LEN + 1), &vec!()).is_err());
+        // nothing was stored
+        assert_eq!(db.data_tip(), PRef::from(0));
+        db.put(&[0; MAX_KEY_LEN], &[1], &vec!()).unwrap();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_scratch_dir () {
+        use std::fs;
+
+        let dir = TestDir::new("scratch");
+        let scratch_files = |prefix: &str| fs::read_dir(dir.path()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(prefix)).count();
+        {
+            let options = Options { spill_threshold_bytes: 2 * PAGE_SIZE, scratch_dir: Some(dir.path().to_path_buf()), .. Options::default() };
+            let mut db = Transient::new_db_with_options("first", options).unwrap();
+            db.init().unwrap();
+            let mut root = db.put_referred(&[0; 1000], &vec!()).unwrap();
+            for _ in 0 .. 20 {
+                root = db.put_referred(&[1; 1000], &vec!(root)).unwrap();
+            }
+            db.batch().unwrap();
+            // data and link file
+            assert_eq!(scratch_files("hammersbald-spill-"), 2);
+
+            {
+                let mut dag = db.dag_disk_visited(root, None).unwrap();
+                assert!(dag.next().is_some());
+                assert_eq!(scratch_files("hammersbald-visited-"), 1);
+                assert_eq!(dag.count(), 20);
+            }
+            assert_eq!(scratch_files("hammersbald-visited-"), 0);
+
+            let data = vec!(2u8; 10 * PAGE_SIZE);
+            let pref = db.put_stream(&[1], &mut io::Cursor::new(data.clone()), data.len()).unwrap();
+            assert_eq!(scratch_files("hammersbald-stream-"), 0);
+            assert_eq!(db.get(&[1]).unwrap(), Some((pref, data, vec!())));
+            db.shutdown();
+        }
+        assert_eq!(scratch_files("hammersbald-spill-"), 0);
+    }
+
+    #[test]
+    fn test_fragmentation () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        // length, previous, type, data length and data
+        db.put_referred(&[0; 100], &vec!()).unwrap();
+        // with key length and key
+        db.put(&[1], &[1; 100], &vec!()).unwrap();
+        db.put(&[1], &[2; 100], &vec!()).unwrap();
+        db.batch().unwrap();
+
+        let report = db.fragmentation().unwrap();
+        assert_eq!(report.total_bytes, PAGE_SIZE as u64);
+        assert_eq!(report.live_bytes, 113 + 115);
+        assert_eq!(report.orphaned_bytes, 115);
+        assert_eq!(report.page_trailer_bytes, 6);
+        assert_eq!(report.padding_bytes, PAGE_SIZE as u64 - 6 - 113 - 2 * 115);
+
+        // an entry spanning a page boundary, its page trailer is not counted with it
+        db.put(&[2], &[3; 5000], &vec!()).unwrap();
+        db.batch().unwrap();
+        let report = db.fragmentation().unwrap();
+        assert_eq!(report.total_bytes, 3 * PAGE_SIZE as u64);
+        assert_eq!(report.live_bytes, 113 + 115 + 5015);
+        assert_eq!(report.page_trailer_bytes, 18);
+        assert_eq!(report.total_bytes, report.live_bytes + report.orphaned_bytes + report.padding_bytes + report.page_trailer_bytes);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag_limited () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        let mut level = (0 .. 64u8).map(|i| db.put_referred(&[i], &vec!()).unwrap()).collect::<Vec<_>>();
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| db.put_referred(&[0], &pair.to_vec()).unwrap()).collect();
+        }
+        let root = level[0];
+        db.batch().unwrap();
+
+        let mut dag = db.dag_limited(root, 10);
+        assert_eq!(dag.by_ref().count(), 10);
+        assert!(dag.truncated());
+        assert!(dag.next().is_none());
+
+        let mut dag = db.dag_limited(root, 127);
+        assert_eq!(dag.by_ref().count(), 127);
+        assert!(!dag.truncated());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_payloads () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        let leaf = db.put_referred(&[1], &vec!()).unwrap();
+        let keyed = db.put(&[2], &[3], &vec!(leaf)).unwrap();
+        let tagged = db.put_tagged(&[4], &[5], &vec!(), 7).unwrap();
+        db.batch().unwrap();
+
+        let payloads = db.payloads().map(|(pref, payload)| (pref, payload.unwrap())).collect::<Vec<_>>();
+        assert_eq!(payloads, vec!(
+            (tagged, StoredPayload::Indexed { key: vec!(4), data: vec!(5), referred: vec!(), flags: 7, timestamp: None }),
+            (keyed, StoredPayload::Indexed { key: vec!(2), data: vec!(3), referred: vec!(leaf), flags: 0, timestamp: None }),
+            (leaf, StoredPayload::Referred { data: vec!(1), referred: vec!() })));
+
+        // a link is no data
+        assert!(StoredPayload::decode(leaf, &Envelope::new(&[2, 0, 0], PRef::invalid())).is_err());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_is_dirty () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        db.batch().unwrap();
+        assert!(!db.is_dirty());
+        db.put(&[1], &[1], &vec!()).unwrap();
+        assert!(db.is_dirty());
+        db.batch().unwrap();
+        assert!(!db.is_dirty());
+        // data without key does not change the hash table
+        db.put_referred(&[2], &vec!()).unwrap();
+        assert!(db.is_dirty());
+        db.batch().unwrap();
+        assert!(!db.is_dirty());
+        db.batch().unwrap();
+        assert!(!db.is_dirty());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_entry_across_chunks () {
+        use rolledfile::RolledFile;
+
+        let dir = TestDir::new("across");
+        let name = dir.name("testdb");
+        let chunk_size = 2 * PAGE_SIZE as u64;
+
+        let mut stored = Vec::new();
+        {
+            let mut db = db_with_data_file(Box::new(RolledFile::new(name.as_str(), "bc", true, chunk_size).unwrap()));
+            db.init().unwrap();
+            // sizes not aligned to pages, some longer than a chunk
+            for (i, len) in [100usize, 3000, 5000, 9000, 17, 4090, 12000].iter().enumerate() {
+                let data = (0 .. *len).map(|n| (n + i) as u8).collect::<Vec<_>>();
+                stored.push((db.put_referred(&data, &vec!()).unwrap(), data));
+            }
+            for (pref, data) in &stored {
+                assert_eq!(db.get_referred(*pref).unwrap().1, *data);
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        assert!(stored.iter().any(|(pref, data)| pref.as_u64() / chunk_size != (pref.as_u64() + data.len() as u64) / chunk_size));
+
+        let mut db = db_with_data_file(Box::new(RolledFile::new(name.as_str(), "bc", true, chunk_size).unwrap()));
+        for (pref, data) in &stored {
+            assert_eq!(db.get_referred(*pref).unwrap().1, *data);
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_batch_verified () {
+        let mut db = db_with_data_file(Box::new(Transient::new(true)));
+        let mut lying = db_with_data_file(Box::new(Dropping{file: Transient::new(true), drop: 3, appended: 0}));
+        for i in 0 .. 100u8 {
+            db.put(&[i], &[i; 500], &vec!()).unwrap();
+            lying.put(&[i], &[i; 500], &vec!()).unwrap();
+        }
+        db.batch_verified(1.0).unwrap();
+        match lying.batch_verified(1.0) {
+            Err(HammersbaldError::Corrupted(_)) => {},
+            _ => panic!("dropped write not detected")
+        }
+        for i in 100 .. 200u8 {
+            db.put(&[i], &[i; 500], &vec!()).unwrap();
+        }
+        db.batch_verified(1.0).unwrap();
+        for i in 0 .. 200u8 {
+            assert_eq!(db.get(&[i]).unwrap().unwrap().1, vec!(i; 500));
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_tagged () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        let plain = db.put(&[0], &[1, 2, 3], &vec!()).unwrap();
+        let tagged = db.put_tagged(&[1], &[4, 5, 6], &vec!(plain), 7).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_tagged(&[0]).unwrap().unwrap(), (plain, 0, vec!(1, 2, 3), vec!()));
+        assert_eq!(db.get_tagged(&[1]).unwrap().unwrap(), (tagged, 7, vec!(4, 5, 6), vec!(plain)));
+        assert_eq!(db.get(&[1]).unwrap().unwrap(), (tagged, vec!(4, 5, 6), vec!(plain)));
+        assert_eq!(db.key_of(tagged).unwrap(), Some(vec!(1)));
+        db.shutdown();
+
+        let mut db = Transient::new_db_with_options("second", Options { store_keys: false, .. Options::new(1, 1) }).unwrap();
+        db.init().unwrap();
+        let tagged = db.put_tagged(&[1], &[4, 5, 6], &vec!(), 255).unwrap();
+        assert_eq!(db.get_tagged(&[1]).unwrap().unwrap(), (tagged, 255, vec!(4, 5, 6), vec!()));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_entry_footprint () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        db.put(&[0], &[0; 10], &vec!()).unwrap();
+        db.put(&[1], &[1; 40900], &vec!()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.entry_footprint(&[2]).unwrap(), None);
+        let small = db.entry_footprint(&[0]).unwrap().unwrap();
+        let large = db.entry_footprint(&[1]).unwrap().unwrap();
+        // 3 length, 6 previous, 1 type, 1 key length, 1 key, 3 data length, 10 data and a 10 byte slot
+        assert_eq!(small, 25 + 1 + 10);
+        // the additional 40890 bytes fill 10 pages, each with a 6 byte trailer
+        assert_eq!(large - small, 40890 + 60);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_no_cache () {
+        let mut cached = Transient::new_db("first", 1, 1).unwrap();
+        cached.init().unwrap();
+        let mut uncached = Transient::new_db_with_options("second", Options { no_cache: true, .. Options::new(1, 1) }).unwrap();
+        uncached.init().unwrap();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        let mut check = HashMap::new();
+        for i in 0 .. 3000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = cached.put(&key, &data, &vec!()).unwrap();
+            assert_eq!(uncached.put(&key, &data, &vec!()).unwrap(), pref);
+            // read back before the batch, while pages may wait to be written
+            assert_eq!(uncached.get(&key).unwrap(), cached.get(&key).unwrap());
+            check.insert(key, pref);
+            if i % 1000 == 0 {
+                cached.batch().unwrap();
+                uncached.batch().unwrap();
+            }
+        }
+        cached.batch().unwrap();
+        uncached.batch().unwrap();
+
+        for (k, pref) in check {
+            let found = uncached.get(&k[..]).unwrap().unwrap();
+            assert_eq!(found.0, pref);
+            assert_eq!(Some(found), cached.get(&k[..]).unwrap());
+        }
+        let cached_envelopes = cached.data_envelopes().map(|(pos, envelope)| (pos, envelope.payload().to_vec())).collect::<Vec<_>>();
+        let uncached_envelopes = uncached.data_envelopes().map(|(pos, envelope)| (pos, envelope.payload().to_vec())).collect::<Vec<_>>();
+        assert!(cached_envelopes == uncached_envelopes);
+        cached.shutdown();
+        uncached.shutdown();
+    }
+
+    #[test]
+    fn test_history () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        let mut versions = Vec::new();
+        for i in 0 .. 3u8 {
+            versions.push((db.put(&[0], &[i], &vec!()).unwrap(), vec!(i)));
+            db.put(&[1], &[i], &vec!()).unwrap();
+            db.put_referred(&[i], &vec!()).unwrap();
+            db.batch().unwrap();
+        }
+        assert_eq!(db.history(&[0]).unwrap(), versions);
+        assert_eq!(db.get(&[0]).unwrap().unwrap().0, versions[2].0);
+        assert!(db.history(&[2]).unwrap().is_empty());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_empty () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        assert_eq!(db.data_envelopes().count(), 0);
+        assert_eq!(db.data_envelopes_range(PRef::from(0), PRef::from(1 << 20)).count(), 0);
+        assert_eq!(db.link_envelopes().count(), 0);
+        assert!(db.buckets().all(|pref| !pref.is_valid()));
+        assert!(db.slots().all(|slots| slots.unwrap().is_empty()));
+        assert_eq!(db.filter_entries(|_, _| true).count(), 0);
+        assert_eq!(db.dag(PRef::from(0)).count(), 0);
+        let mut content = Vec::new();
+        io::copy(&mut db.dag_reader(PRef::from(0)), &mut content).unwrap();
+        assert!(content.is_empty());
+        assert!(db.history(&[0]).unwrap().is_empty());
+        assert!(db.get(&[0]).unwrap().is_none());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_orphaned_bytes () {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 100u8 {
+            db.put(&[i], &[i; 100], &vec!()).unwrap();
+        }
+        assert_eq!(db.stats().orphaned_bytes, 0);
+        for i in 0 .. 10u8 {
+            db.put(&[i], &[i; 10], &vec!()).unwrap();
+        }
+        // 3 length, 6 previous, 1 type, 1 key length, 1 key, 3 data length and 100 data
+        let stats = db.stats();
+        assert_eq!(stats.orphaned_bytes, 10 * 115);
+        assert!(stats.data_bytes >= 100 * 115 + 10 * 25);
+        db.shutdown();
+    }
+}
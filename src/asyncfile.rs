@@ -18,19 +18,21 @@
 //! an append only file written in background
 //!
 
-use page::Page;
-use pagedfile::PagedFile;
+use page::{Page, PAGE_SIZE};
+use pagedfile::{PagedFile, read_pages_with};
 
 use error::HammersbaldError;
 use pref::PRef;
 
 use std::sync::{Mutex, Arc, Condvar};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::collections::VecDeque;
 
 pub struct AsyncFile {
-    inner: Arc<AsyncFileInner>
+    inner: Arc<AsyncFileInner>,
+    len: u64,
+    writer: Option<JoinHandle<()>>
 }
 
 struct AsyncFileInner {
@@ -38,7 +40,7 @@ struct AsyncFileInner {
     work: Condvar,
     flushed: Condvar,
     run: AtomicBool,
-    queue: Mutex<VecDeque<Page>>
+    queue: Mutex<VecDeque<(PRef, Page)>>
 }
 
 impl AsyncFileInner {
@@ -51,37 +53,76 @@ impl AsyncFileInner {
 
 impl AsyncFile {
     pub fn new (file: Box<PagedFile + Send + Sync>) -> Result<AsyncFile, HammersbaldError> {
+        let len = file.len()?;
         let inner = Arc::new(AsyncFileInner::new(file)?);
         let inner2 = inner.clone();
-        thread::spawn(move || { AsyncFile::background(inner2) });
-        Ok(AsyncFile { inner })
+        let writer = thread::spawn(move || { AsyncFile::background(inner2) });
+        Ok(AsyncFile { inner, len, writer: Some(writer) })
     }
 
     fn background (inner: Arc<AsyncFileInner>) {
         let mut queue = inner.queue.lock().expect("page queue lock poisoned");
         while inner.run.load(Ordering::Acquire) {
             while queue.is_empty() {
+                // run is cleared with the queue locked, so shutdown can not be missed
+                if !inner.run.load(Ordering::Acquire) {
+                    return;
+                }
                 queue = inner.work.wait(queue).expect("page queue lock poisoned");
             }
             let mut file = inner.file.lock().expect("file lock poisoned");
-            while let Some(page) = queue.pop_front() {
+            while let Some((_, page)) = queue.pop_front() {
+                // do not block appends while writing
+                drop(queue);
                 file.append_page(page).expect("can not extend data file");
+                queue = inner.queue.lock().expect("page queue lock poisoned");
             }
             inner.flushed.notify_all();
         }
     }
+
+    /// number of pages waiting to be written
+    pub fn queue_len(&self) -> usize {
+        self.inner.queue.lock().unwrap().len()
+    }
+
+    // wait until the background writer took all queued pages
+    // the last page taken is written before the file lock is released
+    fn drain(&self) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        self.inner.work.notify_one();
+        while !queue.is_empty() {
+            queue = self.inner.flushed.wait(queue).unwrap();
+        }
+    }
 }
 
 impl PagedFile for AsyncFile {
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        // a page taken from the queue is written before the file lock is released
+        if let Some(&(_, ref page)) = self.inner.queue.lock().unwrap().iter().find(|(p, _)| *p == pref) {
+            return Ok(Some(page.clone()));
+        }
         self.inner.file.lock().unwrap().read_page(pref)
     }
 
+    fn read_pages(&self, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError> {
+        let end = pref + (n * PAGE_SIZE) as u64;
+        if self.inner.queue.lock().unwrap().iter().any(|(p, _)| *p >= pref && *p < end) {
+            // queued pages are read one by one
+            return read_pages_with(|pref| self.read_page(pref), pref, n);
+        }
+        self.inner.file.lock().unwrap().read_pages(pref, n)
+    }
+
     fn len(&self) -> Result<u64, HammersbaldError> {
         self.inner.file.lock().unwrap().len()
     }
 
     fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> {
+        // no queued page may be appended after the truncation
+        self.drain();
+        self.len = new_len;
         self.inner.file.lock().unwrap().truncate(new_len)
     }
 
@@ -90,19 +131,23 @@ impl PagedFile for AsyncFile {
     }
 
     fn shutdown (&mut self) {
-        let mut queue = self.inner.queue.lock().unwrap();
-        self.inner.work.notify_one();
-        while !queue.is_empty() {
-            queue = self.inner.flushed.wait(queue).unwrap();
+        self.drain();
+        self.inner.file.lock().unwrap().flush().unwrap();
+        {
+            let _queue = self.inner.queue.lock().unwrap();
+            self.inner.run.store(false, Ordering::Release);
+            self.inner.work.notify_one();
+        }
+        // the writer releases the file, e.g. a spill file is then removed
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
         }
-        let mut file = self.inner.file.lock().unwrap();
-        file.flush().unwrap();
-        self.inner.run.store(false, Ordering::Release)
     }
 
     fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> {
         let mut queue = self.inner.queue.lock().unwrap();
-        queue.push_back(page);
+        queue.push_back((PRef::from(self.len), page));
+        self.len += PAGE_SIZE as u64;
         self.inner.work.notify_one();
         Ok(())
     }
@@ -112,12 +157,86 @@ impl PagedFile for AsyncFile {
     }
 
     fn flush(&mut self) -> Result<(), HammersbaldError> {
-        let mut queue = self.inner.queue.lock().unwrap();
-        self.inner.work.notify_one();
-        while !queue.is_empty() {
-            queue = self.inner.flushed.wait(queue).unwrap();
-        }
+        self.drain();
         let mut file = self.inner.file.lock().unwrap();
         file.flush()
     }
+
+    fn queue_len(&self) -> usize {
+        AsyncFile::queue_len(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    // a file that blocks appends while the gate is locked
+    struct Gated {
+        gate: Arc<Mutex<()>>,
+        len: u64
+    }
+
+    impl PagedFile for Gated {
+        fn read_page(&self, _: PRef) -> Result<Option<Page>, HammersbaldError> { Ok(None) }
+        fn len(&self) -> Result<u64, HammersbaldError> { Ok(self.len) }
+        fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> { self.len = new_len; Ok(()) }
+        fn sync(&self) -> Result<(), HammersbaldError> { Ok(()) }
+        fn shutdown(&mut self) {}
+        fn append_page(&mut self, _: Page) -> Result<(), HammersbaldError> {
+            let _open = self.gate.lock().unwrap();
+            self.len += PAGE_SIZE as u64;
+            Ok(())
+        }
+        fn update_page(&mut self, _: Page) -> Result<u64, HammersbaldError> {
+            Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::Other, "gated file is append only")))
+        }
+        fn flush(&mut self) -> Result<(), HammersbaldError> { Ok(()) }
+        fn queue_len(&self) -> usize { 0 }
+    }
+
+    #[test]
+    fn test_queue_len () {
+        let gate = Arc::new(Mutex::new(()));
+        let mut file = AsyncFile::new(Box::new(Gated{gate: gate.clone(), len: 0})).unwrap();
+        {
+            let _closed = gate.lock().unwrap();
+            for n in 0 .. 10 {
+                file.append_page(Page::new(PRef::from(n * PAGE_SIZE as u64))).unwrap();
+            }
+            // at most one page is taken by the blocked writer
+            assert!(file.queue_len() >= 9);
+        }
+        file.flush().unwrap();
+        assert_eq!(file.queue_len(), 0);
+        assert_eq!(file.len().unwrap(), 10 * PAGE_SIZE as u64);
+        file.shutdown();
+    }
+
+    #[test]
+    fn test_truncate_drains () {
+        let gate = Arc::new(Mutex::new(()));
+        let mut file = AsyncFile::new(Box::new(Gated{gate: gate.clone(), len: 0})).unwrap();
+        for round in 1 .. 20u64 {
+            // the writer is held at the gate while pages queue up
+            let (held, is_held) = ::std::sync::mpsc::channel();
+            let gate_keeper = gate.clone();
+            let gate_opener = thread::spawn(move || {
+                let _closed = gate_keeper.lock().unwrap();
+                held.send(()).unwrap();
+                thread::sleep(::std::time::Duration::from_millis(round % 3));
+            });
+            is_held.recv().unwrap();
+            for _ in 0 .. round {
+                file.append_page(Page::new(PRef::invalid())).unwrap();
+            }
+            let keep = file.len().unwrap() + (round - round / 2) * PAGE_SIZE as u64;
+            file.truncate(keep).unwrap();
+            gate_opener.join().unwrap();
+            file.flush().unwrap();
+            assert_eq!(file.len().unwrap(), keep);
+        }
+        file.shutdown();
+    }
 }
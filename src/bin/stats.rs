@@ -72,6 +72,7 @@ pub fn main () {
     let mut ndata = 0;
     let mut used_buckets = 0;
     for slots in db.slots() {
+        let slots = slots.unwrap();
         ndata += slots.len();
         if slots.len() > 0 {
             used_buckets += 1;
@@ -91,8 +92,8 @@ pub fn main () {
         match Payload::deserialize(envelope.payload()).unwrap() {
             Payload::Indexed(indexed) => {
                 if let Some(root) = roots.remove(&pos) {
-                    let h = hash(indexed.key, sip0, sip1);
-                    if root.iter().any(|hash| *hash == h) == false {
+                    let h = hash(indexed.key, sip0, sip1, db.is_wide_hash());
+                    if indexed.has_key() && root.iter().any(|hash| *hash == h) == false {
                         panic!("ERROR root {} points data with different key hash", pos);
                     }
                     indexed.data.referred().iter().for_each(|o| {referred_set.insert(*o);});
@@ -123,10 +124,14 @@ pub fn main () {
     db.shutdown();
 }
 
-fn hash (key: &[u8], sip0: u64, sip1: u64) -> u32 {
+fn hash (key: &[u8], sip0: u64, sip1: u64, wide: bool) -> u64 {
     let mut hasher = SipHasher::new_with_keys(sip0, sip1);
     hasher.write(key);
-    hasher.finish() as u32
+    if wide {
+        hasher.finish()
+    } else {
+        hasher.finish() as u32 as u64
+    }
 }
 
 // Returns key-value zipped iterator.
@@ -223,7 +223,7 @@ impl<'s> Iterator for BitcoinScriptScan<'s> {
     type Item = Transaction;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        while let Some((_, envelope)) = self.dag.next() {
+        while let Some(Ok((_, envelope))) = self.dag.next() {
             if let Some(transaction) = match Payload::deserialize(envelope.payload()) {
                 Ok(Payload::Indexed(indexed)) => {
                     self.process(indexed.data)
@@ -21,22 +21,88 @@ use page::{Page, PAGE_SIZE};
 use pagedfile::PagedFile;
 use pref::PRef;
 use error::HammersbaldError;
+use options::Options;
 
 use lru_cache::LruCache;
 
 use std::sync::{Arc, Mutex};
-use std::cmp::max;
+use std::cmp::{min, max};
 
 pub struct CachedFile {
     file: Box<PagedFile>,
-    cache: Mutex<Cache>
+    cache: Mutex<Cache>,
+    readahead_pages: usize
 }
 
 impl CachedFile {
     /// create a read cached file with a page cache of given size
     pub fn new (file: Box<PagedFile>, pages: usize) -> Result<CachedFile, HammersbaldError> {
         let len = file.len()?;
-        Ok(CachedFile{file, cache: Mutex::new(Cache::new(len, pages))})
+        Ok(CachedFile{file, cache: Mutex::new(Cache::new(len, pages)), readahead_pages: 0})
+    }
+
+    /// read this many pages at once if a page missed in the cache is next to the page missed before
+    pub fn set_readahead_pages (&mut self, pages: usize) {
+        self.readahead_pages = pages;
+    }
+
+    // read a page missed in the cache and cache it, with the pages ahead of it if reads are sequential
+    // the read ahead is limited to the cache size, so pages read ahead are not evicted before use
+    fn read_missed (&self, cache: &mut Cache, pref: PRef) -> Result<Option<Arc<Page>>, HammersbaldError> {
+        let page_size = PAGE_SIZE as u64;
+        let ahead = min(self.readahead_pages, cache.reads.capacity()) as u64;
+        let range = match cache.last_miss {
+            // scanning forward, as a page iterator
+            Some(last) if ahead > 1 && last.as_u64() + page_size == pref.as_u64() => Some((pref.as_u64(), ahead)),
+            // scanning backward, as an envelope iterator
+            Some(last) if ahead > 1 && pref.as_u64() + page_size == last.as_u64() => {
+                let from = pref.as_u64().saturating_sub((ahead - 1) * page_size);
+                Some((from, (pref.as_u64() - from) / page_size + 1))
+            },
+            _ => None
+        };
+        if let Some((from, n)) = range {
+            let mut found = None;
+            for (i, page) in self.file.read_pages(PRef::from(from), n as usize)?.into_iter().enumerate() {
+                let at = PRef::from(from + i as u64 * page_size);
+                let page = Arc::new(page);
+                if at == pref {
+                    found = Some(page.clone());
+                }
+                cache.cache(at, page);
+            }
+            // the next miss of the scan is next to the last page read ahead
+            cache.last_miss = Some(if from == pref.as_u64() { PRef::from(from + (n - 1) * page_size) } else { PRef::from(from) });
+            return Ok(found);
+        }
+        cache.last_miss = Some(pref);
+        if let Some(page) = self.file.read_page (pref)? {
+            let page = Arc::new(page);
+            cache.cache(pref, page.clone());
+            return Ok(Some(page));
+        }
+        Ok(None)
+    }
+
+    /// wrap a file into a read cache of cached_data_pages, unless options ask for no cache
+    pub fn with_options (file: Box<PagedFile>, options: &Options) -> Result<Box<PagedFile>, HammersbaldError> {
+        if options.no_cache {
+            return Ok(file);
+        }
+        let mut cached = CachedFile::new(file, options.cached_data_pages)?;
+        cached.set_readahead_pages(options.readahead_pages);
+        if let Some(ref on_evict) = options.on_evict {
+            let on_evict = on_evict.clone();
+            cached.on_evict(move |pref| on_evict(pref));
+        }
+        Ok(Box::new(cached))
+    }
+
+    /// call f with the pref of every page leaving the cache, by eviction, flush or truncate
+    /// pages are written through to the file before they are cached, so an evicted page is
+    /// never the only copy. f is called while the cache is locked and must not read this file
+    pub fn on_evict<F: Fn(PRef) + Send + Sync + 'static>(&mut self, f: F) {
+        self.cache.lock().unwrap().on_evict = Some(Box::new(f));
     }
 }
 
@@ -46,11 +112,7 @@ impl PagedFile for CachedFile {
         if let Some(page) = cache.get(pref) {
             return Ok(Some(page));
         }
-        if let Some(page) = self.file.read_page (pref)? {
-            cache.cache(pref, Arc::new(page.clone()));
-            return Ok(Some(page));
-        }
-        Ok(None)
+        Ok(self.read_missed(&mut cache, pref)?.map(|page| page.as_ref().clone()))
     }
 
     fn len(&self) -> Result<u64, HammersbaldError> {
@@ -87,25 +149,70 @@ impl PagedFile for CachedFile {
         self.cache.lock().unwrap().clear();
         self.file.flush()
     }
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
+
+    /// grow the cache or evict least recently used pages down to the new capacity
+    /// pages are written through, so evicted pages need no write
+    fn resize_cache(&mut self, pages: usize) {
+        self.cache.lock().unwrap().resize(pages)
+    }
+
+    fn read_page_shared(&self, pref: PRef) -> Result<Option<Arc<Page>>, HammersbaldError> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(page) = cache.get_shared(pref) {
+            return Ok(Some(page));
+        }
+        self.read_missed(&mut cache, pref)
+    }
 }
 
 
 pub struct Cache {
     reads: LruCache<PRef, Arc<Page>>,
-    len: u64
+    len: u64,
+    // the page read last because it was not cached
+    last_miss: Option<PRef>,
+    on_evict: Option<Box<Fn(PRef) + Send + Sync>>
 }
 
 impl Cache {
     pub fn new (len: u64, size: usize) -> Cache {
-        Cache { reads: LruCache::new(size), len }
+        Cache { reads: LruCache::new(size), len, last_miss: None, on_evict: None }
+    }
+
+    fn evicted(&self, pref: PRef) {
+        if let Some(ref on_evict) = self.on_evict {
+            on_evict(pref);
+        }
     }
 
     pub fn cache(&mut self, pref: PRef, page: Arc<Page>) {
+        if !self.reads.contains_key(&pref) && self.reads.len() >= self.reads.capacity() {
+            if let Some((evicted, _)) = self.reads.remove_lru() {
+                self.evicted(evicted);
+            }
+        }
         self.reads.insert(pref, page);
     }
 
+    pub fn resize(&mut self, size: usize) {
+        while self.reads.len() > size {
+            if let Some((evicted, _)) = self.reads.remove_lru() {
+                self.evicted(evicted);
+            }
+        }
+        self.reads.set_capacity(size);
+    }
+
     pub fn clear(&mut self) {
+        let cleared = self.reads.iter().map(|(pref, _)| *pref).collect::<Vec<_>>();
         self.reads.clear();
+        for pref in cleared {
+            self.evicted(pref);
+        }
     }
 
     pub fn append (&mut self, page: Page) ->u64 {
@@ -132,6 +239,10 @@ impl Cache {
         None
     }
 
+    pub fn get_shared(&mut self, pref: PRef) -> Option<Arc<Page>> {
+        self.reads.get_mut(&pref).map(|page| page.clone())
+    }
+
     pub fn reset_len(&mut self, len: u64) {
         self.len = len;
         let to_delete: Vec<_> = self.reads.iter().filter_map(
@@ -146,6 +257,134 @@ impl Cache {
             }).collect();
         for o in to_delete {
             self.reads.remove(&PRef::from(o));
+            self.evicted(PRef::from(o));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use pagedfile::read_pages_with;
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_on_evict () {
+        let mut file = CachedFile::new(Box::new(Transient::new(true)), 2).unwrap();
+        let evictions = Arc::new(AtomicUsize::new(0));
+        let counter = evictions.clone();
+        file.on_evict(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+
+        for n in 0 .. 10 {
+            file.append_page(Page::new(PRef::from(n * PAGE_SIZE as u64))).unwrap();
+        }
+        assert_eq!(evictions.load(Ordering::SeqCst), 8);
+        // a cached page is not evicted again
+        file.read_page(PRef::from(9 * PAGE_SIZE as u64)).unwrap().unwrap();
+        assert_eq!(evictions.load(Ordering::SeqCst), 8);
+        // a page read back from the file evicts the least recently used
+        assert!(file.read_page(PRef::from(0)).unwrap().is_some());
+        assert_eq!(evictions.load(Ordering::SeqCst), 9);
+        file.truncate(5 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(evictions.load(Ordering::SeqCst), 10);
+        file.flush().unwrap();
+        assert_eq!(evictions.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn test_resize_cache () {
+        let mut file = CachedFile::new(Box::new(Transient::new(true)), 10).unwrap();
+        let evictions = Arc::new(AtomicUsize::new(0));
+        let counter = evictions.clone();
+        file.on_evict(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+        for n in 0 .. 10 {
+            let mut page = Page::new(PRef::from(n * PAGE_SIZE as u64));
+            page.write(0, &[n as u8]);
+            file.append_page(page).unwrap();
+        }
+        assert_eq!(file.cache.lock().unwrap().reads.len(), 10);
+        file.resize_cache(3);
+        assert_eq!(file.cache.lock().unwrap().reads.len(), 3);
+        assert_eq!(evictions.load(Ordering::SeqCst), 7);
+        for n in 0 .. 10 {
+            let page = file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+            let mut buf = [0u8; 1];
+            page.read(0, &mut buf);
+            assert_eq!(buf[0], n as u8);
+        }
+        assert_eq!(file.cache.lock().unwrap().reads.len(), 3);
+        file.resize_cache(20);
+        for n in 0 .. 10 {
+            file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+        }
+        assert_eq!(file.cache.lock().unwrap().reads.len(), 10);
+    }
+
+    // counts reads of a transient file
+    struct Counting {
+        file: Transient,
+        reads: Arc<AtomicUsize>
+    }
+
+    impl PagedFile for Counting {
+        fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.file.read_page(pref)
+        }
+        fn read_pages(&self, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            read_pages_with(|pref| self.file.read_page(pref), pref, n)
+        }
+        fn len(&self) -> Result<u64, HammersbaldError> { self.file.len() }
+        fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> { self.file.truncate(new_len) }
+        fn sync(&self) -> Result<(), HammersbaldError> { self.file.sync() }
+        fn shutdown(&mut self) { self.file.shutdown() }
+        fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> { self.file.append_page(page) }
+        fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> { self.file.update_page(page) }
+        fn flush(&mut self) -> Result<(), HammersbaldError> { self.file.flush() }
+        fn queue_len(&self) -> usize { 0 }
+    }
+
+    #[test]
+    fn test_readahead () {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut transient = Transient::new(true);
+        for n in 0 .. 64 {
+            let mut page = Page::new(PRef::from(n * PAGE_SIZE as u64));
+            page.write(0, &[n as u8]);
+            transient.append_page(page).unwrap();
+        }
+        let mut file = CachedFile::new(Box::new(Counting { file: transient, reads: reads.clone() }), 16).unwrap();
+        file.set_readahead_pages(8);
+        let check = |file: &CachedFile, n: u64| {
+            let page = file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+            let mut buf = [0u8; 1];
+            page.read(0, &mut buf);
+            assert_eq!(buf[0], n as u8);
+        };
+
+        // backward, the first miss is read alone, the next reads 8 pages each time
+        for n in (32 .. 64).rev() {
+            check(&file, n);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 1 + 4);
+
+        // forward
+        file.flush().unwrap();
+        reads.store(0, Ordering::SeqCst);
+        for n in 0 .. 32 {
+            check(&file, n);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 1 + 4);
+
+        // reads not in sequence do not read ahead
+        file.flush().unwrap();
+        reads.store(0, Ordering::SeqCst);
+        for n in &[1, 5, 3, 20, 9] {
+            check(&file, *n);
         }
+        assert_eq!(reads.load(Ordering::SeqCst), 5);
     }
 }
\ No newline at end of file
@@ -0,0 +1,42 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Crash injection
+//! points in the durability paths where a test may abort a batch or recovery
+//!
+//! A batch or recovery that crashes at a point returns HammersbaldError::Crashed and
+//! leaves the files as they are, the db should then be shut down and opened again.
+//!
+
+/// table pages to be changed are logged and synced, nothing of the batch is in the table yet
+pub const BATCH_LOG_SYNCED: &str = "batch log synced";
+/// table and links are written and synced, data is not yet synced
+pub const BATCH_TABLE_SYNCED: &str = "batch table synced";
+/// data is synced, the log does not yet record the new lengths
+pub const BATCH_DATA_SYNCED: &str = "batch data synced";
+/// the log is replayed into the table, the table is not synced and the log not reset
+pub const RECOVERY_REPLAYED: &str = "recovery replayed";
+
+/// all crash points
+#[cfg(feature="crash-test")]
+pub const CRASH_POINTS: [&str; 4] = [BATCH_LOG_SYNCED, BATCH_TABLE_SYNCED, BATCH_DATA_SYNCED, RECOVERY_REPLAYED];
+
+/// decides whether to crash at a point
+#[cfg(feature="crash-test")]
+pub trait CrashPoint : Send + Sync {
+    /// crash at this point
+    fn crash (&self, point: &str) -> bool;
+}
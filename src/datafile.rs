@@ -18,19 +18,26 @@
 //! Specific implementation details to data file
 //!
 
-use page::{PAGE_PAYLOAD_SIZE, PAGE_SIZE};
+use page::{Page, PAGE_PAYLOAD_SIZE, PAGE_SIZE};
 use pagedfile::{PagedFile, PagedFileAppender};
-use format::{Envelope, Payload, Data, IndexedData, Link};
+use format::{Envelope, Payload, Data, IndexedData, Link, REFERRED_DELTAS};
 use error::HammersbaldError;
 use pref::PRef;
 
 use byteorder::{ByteOrder, BigEndian};
 
-use std::collections::VecDeque;
+use std::collections::{VecDeque, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::cmp::{min, max};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 /// file storing indexed and referred data
 pub struct DataFile {
-    appender: PagedFileAppender
+    appender: PagedFileAppender,
+    fixed_value_size: Option<usize>,
+    referred_deltas: bool
 }
 
 impl DataFile {
@@ -43,7 +50,7 @@ impl DataFile {
         if len >= PAGE_SIZE as u64 {
             if let Some(last) = file.read_page(PRef::from(len - PAGE_SIZE as u64))? {
                 let lep = last.read_pref(PAGE_PAYLOAD_SIZE);
-                return Ok(DataFile{appender: PagedFileAppender::new(file, PRef::from(len), lep)});
+                return Ok(DataFile{appender: PagedFileAppender::new(file, PRef::from(len), lep), fixed_value_size: None, referred_deltas: false});
             }
             else {
                 Err(HammersbaldError::Corrupted("missing first data page".to_string()))
@@ -51,18 +58,74 @@ impl DataFile {
         }
         else {
             let appender = PagedFileAppender::new(file, PRef::from(0), PRef::invalid());
-            return Ok(DataFile{appender})
+            return Ok(DataFile{appender, fixed_value_size: None, referred_deltas: false})
+        }
+    }
+
+    /// store all data with this size and without referred, envelopes are then stored without length
+    /// the size must be the same whenever the file is used
+    pub fn set_fixed_value_size (&mut self, size: Option<usize>) {
+        self.fixed_value_size = size;
+    }
+
+    /// store referred prefs of data appended from now as varint distances back from the data
+    /// data stored either way is read
+    pub fn set_referred_deltas (&mut self, deltas: bool) {
+        self.referred_deltas = deltas;
+    }
+
+    /// reserve bytes at the end of the payload of each page for the application
+    /// must be the same whenever the file is used
+    pub fn set_app_trailer_bytes (&mut self, bytes: usize) {
+        self.appender.set_app_trailer_bytes(bytes)
+    }
+
+    /// bytes of a page used for data
+    pub fn page_payload_size (&self) -> usize {
+        self.appender.payload_size()
+    }
+
+    /// set the app trailer written to pages completed from now, including the page being filled
+    pub fn write_app_trailer (&mut self, trailer: &[u8]) {
+        self.appender.set_app_trailer(trailer)
+    }
+
+    /// the app trailer of the page of pref
+    pub fn read_app_trailer (&self, pref: PRef) -> Result<Vec<u8>, HammersbaldError> {
+        match self.appender.read_app_trailer(pref)? {
+            Some(trailer) => Ok(trailer),
+            None => Err(HammersbaldError::InvalidOffset)
         }
     }
 
     /// return an iterator of all payloads
     pub fn envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         EnvelopeIterator::new(&self.appender, self.appender.lep())
+            .with_unsized(self.fixed_value_size.is_some())
+    }
+
+    /// return an iterator of payloads stored at or after from and before to
+    /// in the same order as envelopes, latest first
+    pub fn envelopes_range<'a>(&'a self, from: PRef, to: PRef) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+        self.envelopes().skip_while(move |(pos, _)| *pos >= to).take_while(move |(pos, _)| *pos >= from)
     }
 
     /// iterate backward through references
     pub fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
-        DagIterator::new(&self.appender, root)
+        DagIterator::new(&self.appender, root).with_unsized(self.fixed_value_size.is_some())
+    }
+
+    /// iterate the union of the DAGs of the roots, each data once
+    pub fn reachable<'a>(&'a self, roots: &[PRef]) -> DagIterator<'a> {
+        match roots.split_first() {
+            Some((first, rest)) => self.dag(*first).with_roots(rest),
+            None => self.dag(PRef::invalid())
+        }
+    }
+
+    /// stream the data of a DAG, see DagReader for the order
+    pub fn dag_reader<'a>(&'a self, root: PRef) -> DagReader<'a> {
+        DagReader::new(self.dag(root))
     }
 
     /// shutdown
@@ -71,21 +134,114 @@ impl DataFile {
     }
 
     /// get a stored content at pref
-    pub fn get_envelope(&self, mut pref: PRef) -> Result<Envelope, HammersbaldError> {
-        let mut len = [0u8;3];
-        pref = self.appender.read(pref, &mut len)?;
-        let mut buf = vec!(0u8; BigEndian::read_u24(&len) as usize);
-        self.appender.read(pref, &mut buf)?;
-        Ok(Envelope::deseralize(buf))
+    pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, HammersbaldError> {
+        match read_envelope(|pos, buf| self.appender.read(pos, buf), pref, self.fixed_value_size.is_some())? {
+            Some(envelope) => Ok(envelope),
+            None => Err(HammersbaldError::Corrupted(format!("no envelope at {}", pref)))
+        }
+    }
+
+    /// get the payload stored at pref, shared with the page cache if the envelope is within a page
+    /// a payload storing referred prefs as distances is decoded into a copy
+    pub fn get_shared(&self, pref: PRef) -> Result<SharedPayload, HammersbaldError> {
+        let unsized_envelope = self.fixed_value_size.is_some();
+        let (start, len) = match envelope_extent(&mut |pos, buf| self.appender.read(pos, buf), pref, unsized_envelope)? {
+            Some(extent) => extent,
+            None => return Err(HammersbaldError::Corrupted(format!("no envelope at {}", pref)))
+        };
+        let from = start.in_page_pos() + 6;
+        let to = start.in_page_pos() + len;
+        if start.this_page() == pref.this_page() && to <= self.page_payload_size() {
+            if let Some(page) = self.appender.read_page_shared(pref.this_page())? {
+                if page.as_slice()[from] & REFERRED_DELTAS == 0 {
+                    return Ok(SharedPayload::InPage(page, from, to));
+                }
+            }
+        }
+        Ok(SharedPayload::Read(self.get_envelope(pref)?))
+    }
+
+    /// the pages the envelope at pref is stored in
+    pub fn envelope_pages(&self, pref: PRef) -> Result<Vec<PRef>, HammersbaldError> {
+        let end = self.envelope_end(pref)?;
+        let last = PRef::from(end.as_u64() - 1).this_page();
+        let mut pages = vec!(pref.this_page());
+        while *pages.last().unwrap() < last {
+            let next = *pages.last().unwrap() + PAGE_SIZE as u64;
+            pages.push(next);
+        }
+        Ok(pages)
+    }
+
+    /// bytes the envelope at pref is stored in, without the page trailers it spans
+    pub fn stored_len(&self, pref: PRef) -> Result<u64, HammersbaldError> {
+        let end = self.envelope_end(pref)?;
+        Ok(self.len_between(pref, end))
+    }
+
+    /// bytes from pref up to end, without the page trailers between
+    pub fn len_between(&self, pref: PRef, end: PRef) -> u64 {
+        let trailers = (end.this_page().as_u64() - pref.this_page().as_u64()) / PAGE_SIZE as u64;
+        end.as_u64() - pref.as_u64() - trailers * (PAGE_SIZE - self.page_payload_size()) as u64
+    }
+
+    // after the last byte of the envelope at pref, or at the next page if that byte ended a page
+    fn envelope_end(&self, pref: PRef) -> Result<PRef, HammersbaldError> {
+        let mut end = pref;
+        let found = read_envelope(|pos, buf| {
+            let read_to = self.appender.read(pos, buf)?;
+            end = max(end, read_to);
+            Ok(read_to)
+        }, pref, self.fixed_value_size.is_some())?;
+        if found.is_none() {
+            return Err(HammersbaldError::Corrupted(format!("no envelope at {}", pref)));
+        }
+        Ok(end)
+    }
+
+    /// pref of the last envelope
+    pub fn lep (&self) -> PRef {
+        self.appender.lep()
+    }
+
+    /// position of the next append
+    pub fn position (&self) -> PRef {
+        self.appender.position()
+    }
+
+    /// read a page, pages not yet flushed are read from buffers
+    pub fn read_page (&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        self.appender.read_page(pref)
     }
 
     /// append link
     pub fn append_link (&mut self, link: Link) -> Result<PRef, HammersbaldError> {
         let mut payload = vec!();
         Payload::Link(link).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice(), self.appender.lep());
+        self.append_payload(payload.as_slice())
+    }
+
+    fn append_payload (&mut self, payload: &[u8]) -> Result<PRef, HammersbaldError> {
+        let mut encoded = None;
+        if self.referred_deltas {
+            encoded = Payload::encode_referred_deltas(payload, self.appender.position());
+        }
+        let envelope = Envelope::new(encoded.as_ref().map(|e| e.as_slice()).unwrap_or(payload), self.appender.lep());
         let mut store = vec!();
-        envelope.serialize(&mut store);
+        if let Some(size) = self.fixed_value_size {
+            let fits = UNSIZED_PAYLOAD_TYPES.contains(&payload[0]) && match Payload::deserialize(payload)? {
+                Payload::Indexed(indexed) => indexed.data.data.len() == size && indexed.data.referred().is_empty(),
+                Payload::Referred(data) => data.data.len() == size && data.referred().is_empty(),
+                Payload::Link(_) => false
+            };
+            if !fits {
+                return Err(HammersbaldError::FixedValueSize(size));
+            }
+            envelope.serialize_unsized(&mut store);
+        }
+        else {
+            envelope.serialize(&mut store);
+        }
         let me = self.appender.position();
         self.appender.advance();
         self.appender.append(store.as_slice())?;
@@ -95,17 +251,66 @@ impl DataFile {
     /// append indexed data
     pub fn append_data (&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
         let rv = Data::from_referred(referred.as_slice());
-        let indexed = IndexedData::new(key, Data::new(data, rv.as_slice()));
+        self.append_indexed(IndexedData::new(key, Data::new(data, rv.as_slice())))
+    }
+
+    /// append indexed data without its key
+    pub fn append_hashed_data (&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        let rv = Data::from_referred(referred.as_slice());
+        self.append_indexed(IndexedData::hashed(Data::new(data, rv.as_slice())))
+    }
+
+    /// append indexed data as constructed
+    pub fn append_indexed (&mut self, indexed: IndexedData) -> Result<PRef, HammersbaldError> {
+        let mut payload = vec!();
+        Payload::Indexed(indexed).serialize(&mut payload);
+        self.append_payload(payload.as_slice())
+    }
 
+    /// append indexed data of len bytes read from reader, the data of indexed is replaced by them
+    /// the data is copied in chunks, what was appended is removed if reading fails
+    pub fn append_indexed_stream<R: Read> (&mut self, indexed: IndexedData, reader: &mut R, len: usize) -> Result<PRef, HammersbaldError> {
         let mut payload = vec!();
         Payload::Indexed(indexed).serialize(&mut payload);
+        // data without referred prefs ends the payload with its length
+        let data_len_at = payload.len() - 3;
+        BigEndian::write_u24(&mut payload[data_len_at ..], len as u32);
         let envelope = Envelope::new(payload.as_slice(), self.appender.lep());
-        let mut store = vec!();
-        envelope.serialize(&mut store);
+        let mut head = vec!();
+        if let Some(size) = self.fixed_value_size {
+            if len != size {
+                return Err(HammersbaldError::FixedValueSize(size));
+            }
+            envelope.serialize_unsized(&mut head);
+        }
+        else {
+            envelope.serialize(&mut head);
+            let envelope_len = head.len() - 3 + len;
+            BigEndian::write_u24(&mut head[0 .. 3], envelope_len as u32);
+        }
+        let mark = self.appender.mark();
         let me = self.appender.position();
         self.appender.advance();
-        self.appender.append(store.as_slice())?;
-        Ok(me)
+        match self.append_chunks(head.as_slice(), reader, len) {
+            Ok(()) => Ok(me),
+            Err(error) => {
+                self.appender.rewind(mark)?;
+                Err(error)
+            }
+        }
+    }
+
+    fn append_chunks<R: Read> (&mut self, head: &[u8], reader: &mut R, len: usize) -> Result<(), HammersbaldError> {
+        self.appender.append(head)?;
+        let mut chunk = vec!(0u8; STREAM_CHUNK_SIZE);
+        let mut left = len;
+        while left > 0 {
+            let n = min(left, chunk.len());
+            reader.read_exact(&mut chunk[.. n])?;
+            self.appender.append(&chunk[.. n])?;
+            left -= n;
+        }
+        Ok(())
     }
 
     /// append referred data
@@ -114,13 +319,15 @@ impl DataFile {
         let data = Data::new(data, rv.as_slice());
         let mut payload = vec!();
         Payload::Referred(data).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice(), self.appender.lep());
-        let mut store = vec!();
-        envelope.serialize(&mut store);
-        let me = self.appender.position();
-        self.appender.advance();
-        self.appender.append(store.as_slice())?;
-        Ok(me)
+        self.append_payload(payload.as_slice())
+    }
+
+    /// append a serialized indexed or referred data payload as is, including its type
+    pub fn append_raw_entry (&mut self, payload: &[u8]) -> Result<PRef, HammersbaldError> {
+        if !Payload::well_formed_data(payload) {
+            return Err(HammersbaldError::Corrupted("raw entry is not indexed or referred data".to_string()));
+        }
+        self.append_payload(payload)
     }
 
     /// truncate file
@@ -138,22 +345,160 @@ impl DataFile {
         self.appender.sync()
     }
 
+    /// number of pages waiting to be written in background
+    pub fn queue_len (&self) -> usize {
+        self.appender.queue_len()
+    }
+
     /// get file length
     pub fn len (&self) -> Result<u64, HammersbaldError> {
         self.appender.len()
     }
+
+    /// change the number of pages cached, if the file is cached
+    pub fn resize_cache (&mut self, pages: usize) {
+        self.appender.resize_cache(pages)
+    }
+}
+
+// bytes of a stream appended at once
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// payload types read_envelope computes the length of, others are not stored without length
+const UNSIZED_PAYLOAD_TYPES: [u8; 7] = [0, 1, 4, 5, 6, 11, 12];
+
+// read the envelope stored at pos with read, None if nothing is stored there
+// unsized envelopes are stored without length, it is computed from the payload that has no referred:
+// the offset of the stored data length follows from the payload type and key length, the fixed value
+// size is not used as the key length varies. A new payload type must be sized here and added to
+// UNSIZED_PAYLOAD_TYPES before it can be stored with a fixed value size
+fn read_envelope<F> (mut read: F, pos: PRef, unsized_envelope: bool) -> Result<Option<Envelope>, HammersbaldError>
+    where F: FnMut(PRef, &mut [u8]) -> Result<PRef, HammersbaldError> {
+    let (start, len) = match envelope_extent(&mut read, pos, unsized_envelope)? {
+        Some(extent) => extent,
+        None => return Ok(None)
+    };
+    let mut buf = vec!(0u8; len);
+    if read_all(&mut read, start, &mut buf)?.is_none() {
+        return Ok(None);
+    }
+    let envelope = Envelope::deseralize(buf);
+    if envelope.payload()[0] & REFERRED_DELTAS != 0 {
+        return Ok(Some(Envelope::new(&Payload::decode_referred_deltas(envelope.payload(), pos)?, envelope.previous())));
+    }
+    Ok(Some(envelope))
+}
+
+// nothing is stored if the file ends before buf is filled
+fn read_all<F> (read: &mut F, pos: PRef, buf: &mut [u8]) -> Result<Option<PRef>, HammersbaldError>
+    where F: FnMut(PRef, &mut [u8]) -> Result<PRef, HammersbaldError> {
+    let end = read(pos, buf)?;
+    Ok(if end.as_u64() - pos.as_u64() >= buf.len() as u64 { Some(end) } else { None })
+}
+
+// where the envelope stored at pos starts after its length and how long it is, see read_envelope
+fn envelope_extent<F> (read: &mut F, pos: PRef, unsized_envelope: bool) -> Result<Option<(PRef, usize)>, HammersbaldError>
+    where F: FnMut(PRef, &mut [u8]) -> Result<PRef, HammersbaldError> {
+    let mut read_all = |pos: PRef, buf: &mut [u8]| read_all(read, pos, buf);
+    let mut start = pos;
+    let len = if unsized_envelope {
+        // previous pref, payload type, key length or flags, key length
+        let mut head = [0u8; 9];
+        if read_all(pos, &mut head)?.is_none() {
+            return Ok(None);
+        }
+        let data_len_at = match head[6] {
+            0 => 8 + head[7] as usize,
+            1 | 4 => 7,
+            5 => 9 + head[8] as usize,
+            6 => 8,
+            // flags and timestamp before the key length
+            11 => {
+                let mut head = [0u8; 17];
+                if read_all(pos, &mut head)?.is_none() {
+                    return Ok(None);
+                }
+                17 + head[16] as usize
+            },
+            12 => 16,
+            _ => return Err(HammersbaldError::Corrupted(format!("no data at {}", pos)))
+        };
+        let mut prefix = vec!(0u8; data_len_at + 3);
+        if read_all(pos, &mut prefix)?.is_none() {
+            return Ok(None);
+        }
+        data_len_at + 3 + BigEndian::read_u24(&prefix[data_len_at ..]) as usize
+    }
+    else {
+        let mut len = [0u8; 3];
+        match read_all(pos, &mut len)? {
+            Some(end) => start = end,
+            None => return Ok(None)
+        }
+        BigEndian::read_u24(&len) as usize
+    };
+    // previous pref and payload type at least
+    if len < 7 {
+        return Ok(None);
+    }
+    Ok(Some((start, len)))
+}
+
+/// a payload read by DataFile::get_shared
+pub enum SharedPayload {
+    /// the payload is at from .. to of a page shared with the cache
+    InPage(Arc<Page>, usize, usize),
+    /// the envelope was read into a buffer
+    Read(Envelope)
+}
+
+impl SharedPayload {
+    /// the payload
+    pub fn payload(&self) -> &[u8] {
+        match *self {
+            SharedPayload::InPage(ref page, from, to) => &page.as_slice()[from .. to],
+            SharedPayload::Read(ref envelope) => envelope.payload()
+        }
+    }
 }
 
 /// Iterate data file content
 pub struct EnvelopeIterator<'f> {
     file: &'f PagedFileAppender,
-    pos: PRef
+    pos: PRef,
+    unsized_envelope: bool
 }
 
 impl<'f> EnvelopeIterator<'f> {
     /// create a new iterator
     pub fn new (file: &'f PagedFileAppender, pos: PRef) -> EnvelopeIterator<'f> {
-        EnvelopeIterator {file, pos}
+        EnvelopeIterator {file, pos, unsized_envelope: false}
+    }
+
+    /// envelopes are stored without length, as with a fixed value size
+    pub fn with_unsized (mut self, unsized_envelope: bool) -> EnvelopeIterator<'f> {
+        self.unsized_envelope = unsized_envelope;
+        self
+    }
+
+    fn read(&mut self, mut pos: PRef, buf: &mut [u8]) -> Result<PRef, HammersbaldError> {
+        let mut read = 0;
+        while read < buf.len() {
+            if let Some(ref page) = self.file.read_page(pos.this_page())? {
+                let payload_size = self.file.payload_size();
+                let have = min(payload_size - pos.in_page_pos(), buf.len() - read);
+                page.read(pos.in_page_pos(), &mut buf[read .. read + have]);
+                read += have;
+                pos += have as u64;
+                if pos.in_page_pos() == payload_size {
+                    pos += (PAGE_SIZE - payload_size) as u64;
+                }
+            }
+            else {
+                break;
+            }
+        }
+        Ok(pos)
     }
 }
 
@@ -162,15 +507,12 @@ impl<'f> Iterator for EnvelopeIterator<'f> {
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         if self.pos.is_valid() {
-            let mut pos = self.pos;
-            let start = pos;
-            let mut len = [0u8;3];
-            pos = self.file.read(pos, &mut len).unwrap();
-            let mut buf = vec!(0u8; BigEndian::read_u24(&len) as usize);
-            self.file.read(pos, &mut buf).unwrap();
-            let envelope = Envelope::deseralize(buf);
-            self.pos = envelope.previous();
-            return Some((start, envelope))
+            let start = self.pos;
+            let unsized_envelope = self.unsized_envelope;
+            if let Some(envelope) = read_envelope(|pos, buf| self.read(pos, buf), start, unsized_envelope).unwrap() {
+                self.pos = envelope.previous();
+                return Some((start, envelope))
+            }
         }
         None
     }
@@ -180,7 +522,63 @@ impl<'f> Iterator for EnvelopeIterator<'f> {
 pub struct DagIterator<'f> {
     file: &'f PagedFileAppender,
     pos: PRef,
-    next: VecDeque<PRef>
+    next: VecDeque<PRef>,
+    unsized_envelope: bool,
+    visited: Option<Visited>,
+    max_nodes: Option<usize>,
+    yielded: usize,
+    truncated: bool,
+    base: u64,
+    error: Option<HammersbaldError>
+}
+
+// data yielded, to yield data referred repeatedly once
+enum Visited {
+    Memory(HashSet<PRef>),
+    File(VisitedFile)
+}
+
+impl Visited {
+    // returns true if pref was visited before
+    fn visit (&mut self, pref: PRef) -> Result<bool, HammersbaldError> {
+        match self {
+            Visited::Memory(ref mut prefs) => Ok(!prefs.insert(pref)),
+            Visited::File(ref mut file) => file.visit(pref)
+        }
+    }
+}
+
+// a bit for each byte offset of the data file, set if data at that offset was visited
+struct VisitedFile {
+    file: File,
+    // a scratch file to remove when done
+    remove: Option<PathBuf>
+}
+
+impl Drop for VisitedFile {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.remove {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl VisitedFile {
+    // set the bit of pref, returns true if it was set before
+    fn visit (&mut self, pref: PRef) -> Result<bool, HammersbaldError> {
+        let pos = pref.as_u64() / 8;
+        let bit = 1u8 << (pref.as_u64() % 8);
+        let mut byte = [0u8; 1];
+        self.file.seek(SeekFrom::Start(pos))?;
+        // not yet written beyond the end of the file
+        if self.file.read(&mut byte)? == 1 && byte[0] & bit != 0 {
+            return Ok(true);
+        }
+        byte[0] |= bit;
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.write_all(&byte)?;
+        Ok(false)
+    }
 }
 
 impl<'f> DagIterator<'f> {
@@ -188,7 +586,80 @@ impl<'f> DagIterator<'f> {
     pub fn new (file: &'f PagedFileAppender, pos: PRef) -> DagIterator<'f> {
         let mut next = VecDeque::new();
         next.push_back(pos);
-        DagIterator {file, pos, next}
+        DagIterator {file, pos, next, unsized_envelope: false, visited: None, max_nodes: None, yielded: 0, truncated: false,
+            base: 0, error: None}
+    }
+
+    /// yield data referred repeatedly only once, remembering visited data in a bit per byte
+    /// of the data file in the given empty file, so memory does not grow with visited data
+    pub fn with_visited_file (mut self, file: File) -> DagIterator<'f> {
+        self.visited = Some(Visited::File(VisitedFile { file, remove: None }));
+        self
+    }
+
+    /// as with_visited_file, the file at path is removed when the iterator is dropped
+    pub fn with_scratch_file (mut self, file: File, path: PathBuf) -> DagIterator<'f> {
+        self.visited = Some(Visited::File(VisitedFile { file, remove: Some(path) }));
+        self
+    }
+
+    /// also iterate the DAGs of these roots, yielding data reachable from several roots once
+    /// the data yielded is remembered in memory, unless with_visited_file
+    pub fn with_roots (mut self, roots: &[PRef]) -> DagIterator<'f> {
+        self.next.extend(roots.iter().cloned());
+        if self.visited.is_none() {
+            self.visited = Some(Visited::Memory(HashSet::new()));
+        }
+        self
+    }
+
+    /// stop after yielding max_nodes data, see truncated
+    pub fn with_max_nodes (mut self, max_nodes: usize) -> DagIterator<'f> {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// true if the iteration stopped at max_nodes while data was still to be visited
+    pub fn truncated (&self) -> bool {
+        self.truncated
+    }
+
+    /// envelopes are stored without length, as with a fixed value size
+    pub fn with_unsized (mut self, unsized_envelope: bool) -> DagIterator<'f> {
+        self.unsized_envelope = unsized_envelope;
+        self
+    }
+
+    /// yield prefs with the bits of base set, also those referred by the data yielded
+    /// e.g. to yield the prefs of a shard as global prefs, base has no bit set of prefs stored
+    pub fn with_base (mut self, base: u64) -> DagIterator<'f> {
+        self.base = base;
+        self
+    }
+
+    /// yield only this error, e.g. for a root that can not be iterated
+    pub fn with_error (mut self, error: HammersbaldError) -> DagIterator<'f> {
+        self.error = Some(error);
+        self
+    }
+
+    // the envelope with the bits of base set in its referred prefs
+    fn rebase (&self, envelope: Envelope) -> Envelope {
+        let mut payload = envelope.payload().to_vec();
+        if let Some(data_at) = Payload::data_at(&payload) {
+            let referred_at = data_at + 3 + BigEndian::read_u24(&payload[data_at .. data_at + 3]) as usize;
+            for referred in payload[referred_at ..].chunks_mut(6) {
+                let pref = BigEndian::read_u48(referred);
+                BigEndian::write_u48(referred, pref | self.base);
+            }
+        }
+        Envelope::new(&payload, envelope.previous())
+    }
+
+    // nothing is yielded after an error
+    fn stop (&mut self, error: HammersbaldError) -> HammersbaldError {
+        self.next.clear();
+        error
     }
 
     fn schedule_descending (&mut self, mut referred: Vec<PRef>) {
@@ -202,25 +673,92 @@ impl<'f> DagIterator<'f> {
 }
 
 impl<'f> Iterator for DagIterator<'f> {
-    type Item = (PRef, Envelope);
+    type Item = Result<(PRef, Envelope), HammersbaldError>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(self.stop(error)));
+        }
         if self.pos.is_valid() {
-            if let Some(mut pos) = self.next.pop_front() {
-                let start = pos;
-                let mut len = [0u8; 3];
-                pos = self.file.read(pos, &mut len).unwrap();
-                let mut buf = vec!(0u8; BigEndian::read_u24(&len) as usize);
-                self.file.read(pos, &mut buf).unwrap();
-                let envelope = Envelope::deseralize(buf);
-                match Payload::deserialize(envelope.payload()).unwrap() {
-                    Payload::Indexed(indexed) => self.schedule_descending(indexed.data.referred()),
-                    Payload::Referred(referred) => self.schedule_descending(referred.referred()),
-                    _ => {}
+            if self.max_nodes == Some(self.yielded) {
+                self.truncated = !self.next.is_empty();
+                return None;
+            }
+            while let Some(start) = self.next.pop_front() {
+                let seen = match self.visited {
+                    Some(ref mut visited) => visited.visit(start),
+                    None => Ok(false)
+                };
+                match seen {
+                    Ok(true) => continue,
+                    Ok(false) => {},
+                    Err(e) => return Some(Err(self.stop(e)))
                 }
-                return Some((start, envelope))
+                let file = self.file;
+                let envelope = match read_envelope(|pos, buf| file.read(pos, buf), start, self.unsized_envelope) {
+                    Ok(Some(envelope)) => envelope,
+                    // nothing stored at pos, e.g. in an empty db
+                    Ok(None) => {
+                        self.next.clear();
+                        return None;
+                    },
+                    Err(e) => return Some(Err(self.stop(e)))
+                };
+                let referred = match Payload::deserialize(envelope.payload()) {
+                    Ok(Payload::Indexed(indexed)) => indexed.data.referred(),
+                    Ok(Payload::Referred(referred)) => referred.referred(),
+                    Ok(Payload::Link(_)) => Vec::new(),
+                    Err(e) => return Some(Err(self.stop(e)))
+                };
+                self.schedule_descending(referred);
+                self.yielded += 1;
+                if self.base != 0 {
+                    return Some(Ok((PRef::from(start.as_u64() | self.base), self.rebase(envelope))));
+                }
+                return Some(Ok((start, envelope)))
             }
         }
         None
     }
-}
\ No newline at end of file
+}
+
+/// Read the concatenated data of a DAG
+///
+/// Nodes are read in the order of the DagIterator: the root first, then breadth-first,
+/// with the referred prefs of each node visited in descending order (latest stored first).
+/// A node referred from several others is read each time it is reached.
+pub struct DagReader<'f> {
+    dag: DagIterator<'f>,
+    current: Vec<u8>,
+    pos: usize
+}
+
+impl<'f> DagReader<'f> {
+    /// create a new reader
+    pub fn new (dag: DagIterator<'f>) -> DagReader<'f> {
+        DagReader {dag, current: vec!(), pos: 0}
+    }
+}
+
+impl<'f> io::Read for DagReader<'f> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        while self.pos == self.current.len() {
+            if let Some(entry) = self.dag.next() {
+                let (_, envelope) = entry?;
+                self.current = match Payload::deserialize(envelope.payload())? {
+                    Payload::Indexed(indexed) => indexed.data.data.to_vec(),
+                    Payload::Referred(referred) => referred.data.to_vec(),
+                    _ => vec!()
+                };
+                self.pos = 0;
+            }
+            else {
+                return Ok(0);
+            }
+        }
+        let have = min(buf.len(), self.current.len() - self.pos);
+        buf[..have].copy_from_slice(&self.current[self.pos .. self.pos + have]);
+        self.pos += have;
+        Ok(have)
+    }
+}
@@ -0,0 +1,106 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//!
+//! # Data-only db
+//! reads the data file of a db whose hash table is lost, by scanning
+//!
+//! A get scans the data file from its end for the latest data of the key,
+//! so it takes time proportional to the data stored. Nothing can be written.
+//! Use it to read what is left if the table and link files are lost or unusable.
+//!
+
+use datafile::{DataFile, DagIterator};
+use error::HammersbaldError;
+use format::{Envelope, Payload};
+use options::{KeyTransform, Options};
+use pref::PRef;
+
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::HashSet;
+
+/// a read-only db of only a data file, lookups scan the data
+pub struct DataOnlyDb {
+    data: DataFile,
+    key_transform: Option<KeyTransform>
+}
+
+impl DataOnlyDb {
+    /// read a data file written with the options
+    pub fn new (mut data: DataFile, options: &Options) -> DataOnlyDb {
+        data.set_fixed_value_size(options.fixed_value_size);
+        data.set_referred_deltas(options.referred_deltas);
+        data.set_app_trailer_bytes(min(options.app_trailer_bytes, 64));
+        DataOnlyDb { data, key_transform: options.key_transform.clone() }
+    }
+
+    /// retrieve the latest data stored with the key, scanning the data file
+    /// returns (pref, data, referred)
+    pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        let key = match self.key_transform {
+            Some(ref transform) => Cow::Owned(transform(key)),
+            None => Cow::Borrowed(key)
+        };
+        for (pref, envelope) in self.data.envelopes() {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if indexed.key == &key[..] {
+                    return Ok(Some((pref, indexed.data.data.to_vec(), indexed.data.referred())));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// get data
+    /// returns (key, data, referred)
+    pub fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
+        match Payload::deserialize(self.data.get_envelope(pref)?.payload())? {
+            Payload::Referred(referred) => Ok((vec!(), referred.data.to_vec(), referred.referred())),
+            Payload::Indexed(indexed) => Ok((indexed.key.to_vec(), indexed.data.data.to_vec(), indexed.data.referred())),
+            _ => Err(HammersbaldError::Corrupted("referred should point to data".to_string()))
+        }
+    }
+
+    /// return an iterator of all payloads, latest first
+    pub fn data_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+        self.data.envelopes()
+    }
+
+    /// keys with the pref of their latest data, latest first
+    /// keys stored only as hash are skipped
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(Vec<u8>, PRef)> +'a {
+        let mut seen = HashSet::new();
+        self.data.envelopes().filter_map(move |(pref, envelope)| {
+            if let Ok(Payload::Indexed(indexed)) = Payload::deserialize(envelope.payload()) {
+                if indexed.has_key() && seen.insert(indexed.key.to_vec()) {
+                    return Some((indexed.key.to_vec(), pref));
+                }
+            }
+            None
+        })
+    }
+
+    /// iterator for a DAG
+    pub fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
+        self.data.dag(root)
+    }
+
+    /// stop background writer
+    pub fn shutdown (&mut self) {
+        self.data.shutdown()
+    }
+}
@@ -20,6 +20,8 @@
 #[cfg(feature="bitcoin_support")]
 use bitcoin::network::serialize;
 
+use pref::PRef;
+
 use std::convert;
 use std::error::Error;
 use std::fmt;
@@ -42,7 +44,32 @@ pub enum HammersbaldError {
     /// Lock poisoned
     Poisoned(String),
     /// Queue error
-    Queue(String)
+    Queue(String),
+    /// write attempted to a db opened read-only
+    ReadOnly,
+    /// the chunk file holding the pref is missing, e.g. moved to cold storage
+    ChunkUnavailable(u16, PRef),
+    /// data is not of the fixed value size of the db or refers to other data
+    FixedValueSize(usize),
+    /// a prepared key was used with a db other than the one that prepared it
+    ForeignPreparedKey,
+    /// write refused as the free bytes on disk are below Options::min_free_bytes
+    DiskNearlyFull(u64),
+    /// the db was created with another Options::magic_namespace
+    BadMagic,
+    /// key is longer than this
+    KeyTooLong(usize),
+    /// data is larger than this
+    ValueTooLarge(usize),
+    /// data refers to more than this many prefs
+    TooManyReferences(usize),
+    /// the log of the db kept changing while a snapshot was opened
+    SnapshotBusy,
+    /// no minimal perfect hash was found for the keys of the db
+    NoPerfectHash,
+    /// crash injected at the named point
+    #[cfg(feature="crash-test")]
+    Crashed(String)
 }
 
 impl Error for HammersbaldError {
@@ -55,7 +82,20 @@ impl Error for HammersbaldError {
             #[cfg(feature="bitcoin_support")]
             HammersbaldError::BitcoinSerialize(_) => "Bitcoin Serialize Error",
             HammersbaldError::Poisoned(ref s) => s.as_str(),
-            HammersbaldError::Queue(ref s) => s.as_str()
+            HammersbaldError::Queue(ref s) => s.as_str(),
+            HammersbaldError::ReadOnly => "read-only",
+            HammersbaldError::ChunkUnavailable(_, _) => "chunk unavailable",
+            HammersbaldError::FixedValueSize(_) => "data does not fit fixed value size",
+            HammersbaldError::ForeignPreparedKey => "key prepared by another db",
+            HammersbaldError::DiskNearlyFull(_) => "disk nearly full",
+            HammersbaldError::BadMagic => "db of another namespace",
+            HammersbaldError::KeyTooLong(_) => "key too long",
+            HammersbaldError::ValueTooLarge(_) => "value too large",
+            HammersbaldError::TooManyReferences(_) => "too many references",
+            HammersbaldError::SnapshotBusy => "db changed while opening a snapshot",
+            HammersbaldError::NoPerfectHash => "no perfect hash found",
+            #[cfg(feature="crash-test")]
+            HammersbaldError::Crashed(ref s) => s.as_str()
         }
     }
 
@@ -68,7 +108,20 @@ impl Error for HammersbaldError {
             #[cfg(feature="bitcoin_support")]
             HammersbaldError::BitcoinSerialize(ref e) => Some(e),
             HammersbaldError::Poisoned(_) => None,
-            HammersbaldError::Queue(_) => None
+            HammersbaldError::Queue(_) => None,
+            HammersbaldError::ReadOnly => None,
+            HammersbaldError::ChunkUnavailable(_, _) => None,
+            HammersbaldError::FixedValueSize(_) => None,
+            HammersbaldError::ForeignPreparedKey => None,
+            HammersbaldError::DiskNearlyFull(_) => None,
+            HammersbaldError::BadMagic => None,
+            HammersbaldError::KeyTooLong(_) => None,
+            HammersbaldError::ValueTooLarge(_) => None,
+            HammersbaldError::TooManyReferences(_) => None,
+            HammersbaldError::SnapshotBusy => None,
+            HammersbaldError::NoPerfectHash => None,
+            #[cfg(feature="crash-test")]
+            HammersbaldError::Crashed(_) => None
         }
     }
 }
@@ -108,3 +161,9 @@ impl<T> convert::From<sync::mpsc::SendError<T>> for HammersbaldError {
         HammersbaldError::Queue(err.to_string())
     }
 }
+
+impl convert::From<sync::mpsc::RecvError> for HammersbaldError {
+    fn from(err: sync::mpsc::RecvError) -> HammersbaldError {
+        HammersbaldError::Queue(err.to_string())
+    }
+}
@@ -21,8 +21,33 @@ use pref::PRef;
 
 use byteorder::{WriteBytesExt, ByteOrder, BigEndian};
 
+use std::cmp::min;
 use std::io::Write;
 
+/// set in the type of a data payload whose referred are stored as varint distances back
+pub const REFERRED_DELTAS: u8 = 0x80;
+
+// 7 bits per byte, lowest first, the high bit is set in all but the last byte
+fn write_varint(result: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        result.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    result.push(n as u8);
+}
+
+// the number and the bytes it takes, None if the slice ends within
+fn read_varint(slice: &[u8]) -> Option<(u64, usize)> {
+    let mut n = 0u64;
+    for (i, b) in slice.iter().enumerate().take(10) {
+        n |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
 /// Content envelope wrapping in data file
 pub struct Envelope {
     buffer: Vec<u8>
@@ -53,6 +78,11 @@ impl Envelope {
         result.write(self.buffer.as_slice()).unwrap();
     }
 
+    /// serialize for storage without length, the reader computes it from the payload
+    pub fn serialize_unsized (&self, result: &mut Write) {
+        result.write_all(self.buffer.as_slice()).unwrap();
+    }
+
     /// deserialize for storage
     pub fn deseralize(buffer: Vec<u8>) -> Envelope {
         Envelope{buffer}
@@ -74,7 +104,16 @@ impl<'e> Payload<'e> {
     pub fn serialize (&self, result: &mut Write) {
         match self {
             Payload::Indexed(indexed) => {
-                result.write_u8(0).unwrap();
+                if let Some(timestamp) = indexed.timestamp {
+                    result.write_u8(if indexed.has_key() { 11 } else { 12 }).unwrap();
+                    result.write_u8(indexed.flags).unwrap();
+                    result.write_u64::<BigEndian>(timestamp).unwrap();
+                } else if indexed.flags == 0 {
+                    result.write_u8(if indexed.has_key() { 0 } else { 4 }).unwrap();
+                } else {
+                    result.write_u8(if indexed.has_key() { 5 } else { 6 }).unwrap();
+                    result.write_u8(indexed.flags).unwrap();
+                }
                 indexed.serialize(result);
             },
             Payload::Referred(referred) => {
@@ -82,24 +121,170 @@ impl<'e> Payload<'e> {
                 referred.serialize(result);
             },
             Payload::Link(link) => {
-                result.write_u8(2).unwrap();
+                if link.encoded {
+                    result.write_u8(if link.wide { 10 } else { 9 }).unwrap();
+                } else if link.header_len > 0 {
+                    result.write_u8(if link.wide { 8 } else { 7 }).unwrap();
+                    result.write_u8(link.header_len as u8).unwrap();
+                } else {
+                    result.write_u8(if link.wide { 3 } else { 2 }).unwrap();
+                }
                 link.serialize(result);
             }
         }
     }
 
+    /// check that a slice can be deserialized as indexed or referred data
+    pub fn well_formed_data(slice: &[u8]) -> bool {
+        if slice.is_empty() {
+            return false;
+        }
+        match slice[0] {
+            0 => IndexedData::well_formed(&slice[1..]),
+            1 | 4 => Data::well_formed(&slice[1..]),
+            5 => slice.len() > 1 && IndexedData::well_formed(&slice[2..]),
+            6 => slice.len() > 1 && Data::well_formed(&slice[2..]),
+            11 => slice.len() > 10 && IndexedData::well_formed(&slice[10..]),
+            12 => slice.len() > 10 && Data::well_formed(&slice[10..]),
+            _ => false
+        }
+    }
+
+    /// position of the data length in a data payload, None for other payloads
+    pub fn data_at(slice: &[u8]) -> Option<usize> {
+        match slice.first().map(|t| t & !REFERRED_DELTAS) {
+            Some(0) if slice.len() > 1 => Some(2 + slice[1] as usize),
+            Some(1) | Some(4) => Some(1),
+            Some(5) if slice.len() > 2 => Some(3 + slice[2] as usize),
+            Some(6) => Some(2),
+            Some(11) if slice.len() > 10 => Some(11 + slice[10] as usize),
+            Some(12) => Some(10),
+            _ => None
+        }
+    }
+
+    /// store the referred prefs of a serialized data payload as distances back from own,
+    /// the pref the payload is stored at. None if the payload is no data or refers to nothing
+    pub fn encode_referred_deltas(slice: &[u8], own: PRef) -> Option<Vec<u8>> {
+        let data_at = Self::data_at(slice)?;
+        if slice[0] & REFERRED_DELTAS != 0 || slice.len() < data_at + 3 {
+            return None;
+        }
+        let referred_at = data_at + 3 + BigEndian::read_u24(&slice[data_at .. data_at + 3]) as usize;
+        if referred_at >= slice.len() {
+            return None;
+        }
+        let mut result = slice[.. referred_at].to_vec();
+        result[0] |= REFERRED_DELTAS;
+        for r in slice[referred_at ..].chunks(6) {
+            let r = BigEndian::read_u48(r);
+            if r >= own.as_u64() {
+                // not a backward reference
+                return None;
+            }
+            write_varint(&mut result, own.as_u64() - r);
+        }
+        Some(result)
+    }
+
+    /// the payload with referred prefs stored as distances turned back into prefs
+    /// own is the pref the payload is stored at, other payloads are returned as is
+    pub fn decode_referred_deltas(slice: &[u8], own: PRef) -> Result<Vec<u8>, HammersbaldError> {
+        if slice.is_empty() || slice[0] & REFERRED_DELTAS == 0 {
+            return Ok(slice.to_vec());
+        }
+        let corrupted = || HammersbaldError::Corrupted(format!("invalid referred at {}", own));
+        let data_at = Self::data_at(slice).ok_or_else(corrupted)?;
+        if slice.len() < data_at + 3 {
+            return Err(corrupted());
+        }
+        let referred_at = data_at + 3 + BigEndian::read_u24(&slice[data_at .. data_at + 3]) as usize;
+        if referred_at > slice.len() {
+            return Err(corrupted());
+        }
+        let mut result = slice[.. referred_at].to_vec();
+        result[0] &= !REFERRED_DELTAS;
+        let mut pos = referred_at;
+        while pos < slice.len() {
+            let (delta, len) = read_varint(&slice[pos ..]).ok_or_else(corrupted)?;
+            if delta == 0 || delta > own.as_u64() {
+                return Err(corrupted());
+            }
+            result.write_u48::<BigEndian>(own.as_u64() - delta).unwrap();
+            pos += len;
+        }
+        Ok(result)
+    }
+
     /// deserialize from storage
     pub fn deserialize(slice: &'e [u8]) -> Result<Payload, HammersbaldError> {
         match slice [0] {
             0 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[1..]))),
             1 => Ok(Payload::Referred(Data::deserialize(&slice[1..]))),
             2 => Ok(Payload::Link(Link::deserialize(&slice[1..]))),
+            3 => Ok(Payload::Link(Link::deserialize_wide(&slice[1..]))),
+            4 => Ok(Payload::Indexed(IndexedData::deserialize_hashed(&slice[1..]))),
+            5 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[2..]).with_flags(slice[1]))),
+            6 => Ok(Payload::Indexed(IndexedData::deserialize_hashed(&slice[2..]).with_flags(slice[1]))),
+            // header length before the links
+            7 | 8 if slice.len() < 2 => Err(HammersbaldError::Corrupted("link without header length".to_string())),
+            7 => Ok(Payload::Link(Link::new(&slice[2..], false, slice[1] as usize))),
+            8 => Ok(Payload::Link(Link::new(&slice[2..], true, slice[1] as usize))),
+            9 => Ok(Payload::Link(Link::encoded(&slice[1..], false))),
+            10 => Ok(Payload::Link(Link::encoded(&slice[1..], true))),
+            // flags and timestamp before the data
+            11 | 12 if slice.len() <= 10 => Err(HammersbaldError::Corrupted("data without timestamp".to_string())),
+            11 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[10..]).with_flags(slice[1])
+                .with_timestamp(BigEndian::read_u64(&slice[2..10])))),
+            12 => Ok(Payload::Indexed(IndexedData::deserialize_hashed(&slice[10..]).with_flags(slice[1])
+                .with_timestamp(BigEndian::read_u64(&slice[2..10])))),
             // Link and Table are not serialized with a type
             _ => Err(HammersbaldError::Corrupted("unknown payload type".to_string()))
         }
     }
 }
 
+/// a data payload decoded into owned parts, see Hammersbald::payloads
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoredPayload {
+    /// data stored with a key
+    Indexed {
+        /// key, empty if only the hash of the key is stored
+        key: Vec<u8>,
+        /// data
+        data: Vec<u8>,
+        /// further accessible data
+        referred: Vec<PRef>,
+        /// application defined flags
+        flags: u8,
+        /// unix time in milliseconds the data was stored at, if recorded
+        timestamp: Option<u64>
+    },
+    /// data accessible only by its pref
+    Referred {
+        /// data
+        data: Vec<u8>,
+        /// further accessible data
+        referred: Vec<PRef>
+    }
+}
+
+impl StoredPayload {
+    /// decode the payload of an envelope stored at pref
+    pub fn decode (pref: PRef, envelope: &Envelope) -> Result<StoredPayload, HammersbaldError> {
+        let payload = Payload::decode_referred_deltas(envelope.payload(), pref)?;
+        if !Payload::well_formed_data(payload.as_slice()) {
+            return Err(HammersbaldError::Corrupted(format!("no data at {}", pref)));
+        }
+        match Payload::deserialize(payload.as_slice())? {
+            Payload::Indexed(indexed) => Ok(StoredPayload::Indexed {
+                key: indexed.key.to_vec(), data: indexed.data.data.to_vec(), referred: indexed.data.referred(),
+                flags: indexed.flags(), timestamp: indexed.timestamp() }),
+            Payload::Referred(data) => Ok(StoredPayload::Referred { data: data.data.to_vec(), referred: data.referred() }),
+            Payload::Link(_) => Err(HammersbaldError::Corrupted(format!("no data at {}", pref)))
+        }
+    }
+}
 
 /// data that is accessible only if its position is known
 pub struct Data<'e> {
@@ -141,6 +326,15 @@ impl<'e> Data<'e> {
         result.write(self.referred).unwrap();
     }
 
+    /// check that a slice can be deserialized as data
+    pub fn well_formed(slice: &[u8]) -> bool {
+        if slice.len() < 3 {
+            return false;
+        }
+        let data_len = BigEndian::read_u24(&slice[0 .. 3]) as usize;
+        slice.len() >= 3 + data_len && (slice.len() - 3 - data_len) % 6 == 0
+    }
+
     /// deserialize from storage
     pub fn deserialize(slice: &'e [u8]) -> Data {
         let data_len = BigEndian::read_u24(&slice[0 .. 3]) as usize;
@@ -152,69 +346,227 @@ impl<'e> Data<'e> {
 
 /// data accessible with a key
 pub struct IndexedData<'e> {
-    /// key
+    /// key, empty if only the hash of the key is stored
     pub key: &'e [u8],
     /// data
-    pub data: Data<'e>
+    pub data: Data<'e>,
+    /// key is stored
+    stored_key: bool,
+    /// application defined flags
+    flags: u8,
+    /// unix time in milliseconds the data was stored at, if recorded
+    timestamp: Option<u64>
 }
 
 impl<'e> IndexedData<'e> {
     /// new indexed data
     pub fn new (key: &'e [u8], data: Data<'e>) -> IndexedData<'e> {
-        IndexedData {key, data}
+        IndexedData {key, data, stored_key: true, flags: 0, timestamp: None}
+    }
+
+    /// new indexed data without the key, it is found by the hash of the key only
+    pub fn hashed (data: Data<'e>) -> IndexedData<'e> {
+        IndexedData {key: &[], data, stored_key: false, flags: 0, timestamp: None}
+    }
+
+    /// set application defined flags, stored only if not zero
+    pub fn with_flags (mut self, flags: u8) -> IndexedData<'e> {
+        self.flags = flags;
+        self
+    }
+
+    /// application defined flags, zero if not set
+    pub fn flags (&self) -> u8 {
+        self.flags
+    }
+
+    /// record the unix time in milliseconds the data is stored at
+    pub fn with_timestamp (mut self, timestamp: u64) -> IndexedData<'e> {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// unix time in milliseconds the data was stored at, None if not recorded
+    pub fn timestamp (&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// is the key stored
+    pub fn has_key (&self) -> bool {
+        self.stored_key
     }
 
     /// serialize for storage
     pub fn serialize (&self, result: &mut Write) {
-        result.write_u8(self.key.len() as u8).unwrap();
-        result.write(self.key).unwrap();
+        if self.stored_key {
+            result.write_u8(self.key.len() as u8).unwrap();
+            result.write(self.key).unwrap();
+        }
         self.data.serialize(result);
     }
 
+    /// check that a slice can be deserialized as indexed data with key
+    pub fn well_formed(slice: &[u8]) -> bool {
+        if slice.is_empty() {
+            return false;
+        }
+        let key_len = slice[0] as usize;
+        slice.len() > key_len && Data::well_formed(&slice[key_len+1 ..])
+    }
+
     /// deserialize from storage
     pub fn deserialize(slice: &'e [u8]) -> IndexedData<'e> {
         let key_len = slice[0] as usize;
         let key = &slice[1 .. key_len+1];
         let data = Data::deserialize(&slice[key_len+1 ..]);
-        IndexedData{key, data }
+        IndexedData{key, data, stored_key: true, flags: 0, timestamp: None}
+    }
+
+    /// deserialize from storage, stored without key
+    pub fn deserialize_hashed(slice: &'e [u8]) -> IndexedData<'e> {
+        IndexedData{key: &[], data: Data::deserialize(slice), stored_key: false, flags: 0, timestamp: None}
+    }
+}
+
+/// encodes the slots of a bucket stored in a link, see Options::bucket_codec
+/// hashes are 32 bits unless the db uses wide hashes
+pub trait BucketCodec : Send + Sync {
+    /// encode slots (hash, pref)
+    fn encode(&self, slots: &[(u64, PRef)]) -> Vec<u8>;
+    /// decode what encode returned
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<(u64, PRef)>, HammersbaldError>;
+}
+
+/// the format of links stored without a codec
+pub struct StandardBucketCodec {
+    /// hashes are 64 bits
+    pub wide: bool
+}
+
+impl BucketCodec for StandardBucketCodec {
+    fn encode(&self, slots: &[(u64, PRef)]) -> Vec<u8> {
+        Link::from_slots(slots, self.wide)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<(u64, PRef)>, HammersbaldError> {
+        if bytes.len() % (Link::hash_len(self.wide) + 6) != 0 {
+            return Err(HammersbaldError::Corrupted("link length is not a multiple of slots".to_string()));
+        }
+        Ok(Link::new(bytes, self.wide, 0).slots())
     }
 }
 
 /// A link to data
 pub struct Link<'e> {
     /// slots
-    links: &'e [u8]
+    links: &'e [u8],
+    /// hashes are 64 bits
+    wide: bool,
+    /// bytes of the beginning of data stored with each slot
+    header_len: usize,
+    /// slots are encoded by a bucket codec
+    encoded: bool
 }
 
 impl<'e> Link<'e> {
-    /// serialize slots
-    pub fn from_slots(slots: &[(u32, PRef)]) -> Vec<u8> {
-        let mut links = vec!(0u8;10*slots.len());
-        for (i, slot) in slots.iter().enumerate() {
-            BigEndian::write_u32(&mut links[i*10 .. i*10+4], slot.0);
-            BigEndian::write_u48(&mut links[i*10+4 .. i*10+10], slot.1.as_u64());
+    /// a link of serialized slots, each with a header of header_len bytes
+    pub fn new(links: &'e [u8], wide: bool, header_len: usize) -> Link<'e> {
+        Link{links, wide, header_len, encoded: false}
+    }
+
+    /// a link of slots encoded by a bucket codec
+    pub fn encoded(links: &'e [u8], wide: bool) -> Link<'e> {
+        Link{links, wide, header_len: 0, encoded: true}
+    }
+
+    /// slots are encoded by a bucket codec, slots and headers do not apply
+    pub fn is_encoded(&self) -> bool {
+        self.encoded
+    }
+
+    /// the stored slots, as encoded
+    pub fn bytes(&self) -> &'e [u8] {
+        self.links
+    }
+
+    /// serialize slots, hashes are stored with 32 or 64 bits
+    pub fn from_slots(slots: &[(u64, PRef)], wide: bool) -> Vec<u8> {
+        Self::from_slots_with_headers(slots.iter().map(|slot| (*slot, &[][..])), wide, 0)
+    }
+
+    /// serialize slots each with a header of header_len bytes, shorter headers are padded with zeros
+    pub fn from_slots_with_headers<'h, I>(slots: I, wide: bool, header_len: usize) -> Vec<u8>
+        where I: Iterator<Item=((u64, PRef), &'h [u8])> {
+        let hl = Self::hash_len(wide);
+        let sl = hl + 6 + header_len;
+        let mut links = vec!();
+        for (i, (slot, header)) in slots.enumerate() {
+            links.resize((i + 1) * sl, 0u8);
+            if wide {
+                BigEndian::write_u64(&mut links[i*sl .. i*sl+hl], slot.0);
+            }
+            else {
+                BigEndian::write_u32(&mut links[i*sl .. i*sl+hl], slot.0 as u32);
+            }
+            BigEndian::write_u48(&mut links[i*sl+hl .. i*sl+hl+6], slot.1.as_u64());
+            let header = &header[.. min(header.len(), header_len)];
+            links[i*sl+hl+6 .. i*sl+hl+6+header.len()].copy_from_slice(header);
         }
         links
     }
 
+    /// bytes of the beginning of data stored with each slot, 0 if none
+    pub fn header_len(&self) -> usize {
+        self.header_len
+    }
+
+    /// the prefs of slots with their headers
+    pub fn headers(&self) -> Vec<(PRef, &'e [u8])> {
+        let hl = Self::hash_len(self.wide);
+        let sl = hl + 6 + self.header_len;
+        let links = self.links;
+        (0 .. links.len()/sl).map(|i|
+            (PRef::from(BigEndian::read_u48(&links[i*sl+hl .. i*sl+hl+6])), &links[i*sl+hl+6 .. i*sl+sl])).collect()
+    }
+
     /// get slots
-    pub fn slots(&self) -> Vec<(u32, PRef)> {
+    pub fn slots(&self) -> Vec<(u64, PRef)> {
+        let hl = Self::hash_len(self.wide);
+        let sl = hl + 6 + self.header_len;
         let mut slots = vec!();
-        for i in 0 .. self.links.len()/10 {
-            let hash = BigEndian::read_u32(&self.links[i*10..i*10+4]);
-            let pref = PRef::from(BigEndian::read_u48(&self.links[i*10+4..i*10+10]));
+        for i in 0 .. self.links.len()/sl {
+            let hash = if self.wide {
+                BigEndian::read_u64(&self.links[i*sl..i*sl+hl])
+            } else {
+                BigEndian::read_u32(&self.links[i*sl..i*sl+hl]) as u64
+            };
+            let pref = PRef::from(BigEndian::read_u48(&self.links[i*sl+hl..i*sl+hl+6]));
             slots.push((hash, pref));
         }
         slots
     }
 
+    /// are hashes 64 bits
+    pub fn is_wide(&self) -> bool {
+        self.wide
+    }
+
+    fn hash_len(wide: bool) -> usize {
+        if wide { 8 } else { 4 }
+    }
+
     /// serialize for storage
     pub fn serialize (&self, write: &mut Write) {
         write.write(&self.links).unwrap();
     }
 
-    /// deserialize from storage
+    /// deserialize from storage, with 32 bit hashes
     pub fn deserialize(slice: &'e [u8]) -> Link<'e> {
-        Link{links: slice}
+        Link::new(slice, false, 0)
+    }
+
+    /// deserialize from storage, with 64 bit hashes
+    pub fn deserialize_wide(slice: &'e [u8]) -> Link<'e> {
+        Link::new(slice, true, 0)
     }
 }
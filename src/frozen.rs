@@ -0,0 +1,231 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Frozen db
+//! a db that is no longer written, its keys are found with a minimal perfect hash
+//!
+//! Keys are distributed to groups of about four by one hash, each group has a seed
+//! that maps its keys to distinct free slots of a table with exactly one slot per key.
+//! A get computes two hashes and reads one pref, there are no buckets to scan.
+//!
+
+use api::{Hammersbald, HammersbaldAPI};
+use datafile::DagIterator;
+use error::HammersbaldError;
+use format::Payload;
+use pref::PRef;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::{thread_rng, RngCore};
+use siphasher::sip::SipHasher;
+
+use std::hash::Hasher;
+use std::cmp::min;
+use std::io::{Read, Write};
+
+// average number of keys in a group
+const GROUP_SIZE: usize = 4;
+// seeds tried for a group before starting over with new hash keys
+const MAX_SEED: u32 = 1 << 20;
+// hash keys tried before giving up to build
+const MAX_HASH_KEYS: usize = 16;
+// entries allocated ahead while reading a stored perfect hash, more grow as read
+const PREALLOCATE: usize = 1 << 20;
+
+/// a read-only db with a minimal perfect hash over its keys
+pub struct FrozenDb {
+    db: Hammersbald,
+    hash: PerfectHash
+}
+
+// minimal perfect hash mapping keys to prefs
+struct PerfectHash {
+    sip0: u64,
+    sip1: u64,
+    seeds: Vec<u32>,
+    slots: Vec<PRef>
+}
+
+impl PerfectHash {
+    // returns None if no seed could be found for a group with these hash keys
+    fn build (sip0: u64, sip1: u64, entries: &Vec<(Vec<u8>, PRef)>) -> Option<PerfectHash> {
+        let mut hash = PerfectHash {
+            sip0, sip1,
+            seeds: vec!(0; (entries.len() + GROUP_SIZE - 1) / GROUP_SIZE),
+            slots: vec!(PRef::invalid(); entries.len())
+        };
+        if entries.is_empty() {
+            return Some(hash);
+        }
+        let mut groups = vec!(Vec::new(); hash.seeds.len());
+        for (key, pref) in entries {
+            groups[hash.group(key)].push((key.as_slice(), *pref));
+        }
+        // largest groups first, while most slots are free
+        let mut order = (0 .. groups.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| groups[*b].len().cmp(&groups[*a].len()));
+        let mut used = vec!(false; hash.slots.len());
+        for g in order {
+            if groups[g].is_empty() {
+                break;
+            }
+            let mut found = false;
+            for seed in 0 .. MAX_SEED {
+                let mut slots = groups[g].iter().map(|(key, _)| hash.slot(key, seed)).collect::<Vec<_>>();
+                if slots.iter().any(|s| used[*s]) {
+                    continue;
+                }
+                slots.sort();
+                slots.dedup();
+                if slots.len() < groups[g].len() {
+                    continue;
+                }
+                for (key, pref) in &groups[g] {
+                    let slot = hash.slot(key, seed);
+                    used[slot] = true;
+                    hash.slots[slot] = *pref;
+                }
+                hash.seeds[g] = seed;
+                found = true;
+                break;
+            }
+            if !found {
+                return None;
+            }
+        }
+        Some(hash)
+    }
+
+    fn hash (&self, key: &[u8], seed: u64) -> u64 {
+        let mut hasher = SipHasher::new_with_keys(self.sip0 ^ seed, self.sip1);
+        hasher.write(key);
+        hasher.finish()
+    }
+
+    fn group (&self, key: &[u8]) -> usize {
+        (self.hash(key, 0) % self.seeds.len() as u64) as usize
+    }
+
+    fn slot (&self, key: &[u8], seed: u32) -> usize {
+        (self.hash(key, seed as u64 + 1) % self.slots.len() as u64) as usize
+    }
+
+    // the only pref a key could be stored at
+    fn get (&self, key: &[u8]) -> Option<PRef> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        Some(self.slots[self.slot(key, self.seeds[self.group(key)])])
+    }
+}
+
+impl FrozenDb {
+    /// build the perfect hash over the keys of accessible indexed data of a db
+    /// all keys must be stored with their data
+    pub fn build (db: Hammersbald) -> Result<FrozenDb, HammersbaldError> {
+        let entries = db.filter_entries(|_, _| true).collect::<Result<Vec<_>, _>>()?;
+        if entries.iter().any(|(key, _)| key.is_empty()) {
+            return Err(HammersbaldError::Corrupted("can not freeze a db that stores only hashes of keys".to_string()));
+        }
+        let mut rng = thread_rng();
+        for _ in 0 .. MAX_HASH_KEYS {
+            if let Some(hash) = PerfectHash::build(rng.next_u64(), rng.next_u64(), &entries) {
+                return Ok(FrozenDb { db, hash });
+            }
+        }
+        Err(HammersbaldError::NoPerfectHash)
+    }
+
+    /// store the perfect hash
+    pub fn serialize (&self, writer: &mut Write) -> Result<(), HammersbaldError> {
+        writer.write_u64::<BigEndian>(self.hash.sip0)?;
+        writer.write_u64::<BigEndian>(self.hash.sip1)?;
+        writer.write_u64::<BigEndian>(self.hash.seeds.len() as u64)?;
+        writer.write_u64::<BigEndian>(self.hash.slots.len() as u64)?;
+        for seed in &self.hash.seeds {
+            writer.write_u32::<BigEndian>(*seed)?;
+        }
+        for pref in &self.hash.slots {
+            writer.write_u48::<BigEndian>(pref.as_u64())?;
+        }
+        Ok(())
+    }
+
+    /// use a perfect hash stored by serialize with the db it was built for
+    pub fn open (db: Hammersbald, reader: &mut Read) -> Result<FrozenDb, HammersbaldError> {
+        let sip0 = reader.read_u64::<BigEndian>()?;
+        let sip1 = reader.read_u64::<BigEndian>()?;
+        let n_seeds = reader.read_u64::<BigEndian>()? as usize;
+        let n_slots = reader.read_u64::<BigEndian>()? as usize;
+        if n_seeds != (n_slots + GROUP_SIZE - 1) / GROUP_SIZE {
+            return Err(HammersbaldError::Corrupted(format!("perfect hash of {} keys with {} seeds", n_slots, n_seeds)));
+        }
+        let mut seeds = Vec::with_capacity(min(n_seeds, PREALLOCATE));
+        for _ in 0 .. n_seeds {
+            seeds.push(reader.read_u32::<BigEndian>()?);
+        }
+        let mut slots = Vec::with_capacity(min(n_slots, PREALLOCATE));
+        for _ in 0 .. n_slots {
+            slots.push(PRef::from(reader.read_u48::<BigEndian>()?));
+        }
+        Ok(FrozenDb { db, hash: PerfectHash { sip0, sip1, seeds, slots } })
+    }
+
+    /// number of keys
+    pub fn len (&self) -> usize {
+        self.hash.slots.len()
+    }
+
+    /// there are no keys
+    pub fn is_empty (&self) -> bool {
+        self.hash.slots.is_empty()
+    }
+
+    /// retrieve single data by key
+    /// returns (pref, data, referred)
+    pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        let key = self.db.transformed_key(key);
+        let pref = match self.hash.get(&key) {
+            Some(pref) => pref,
+            None => return Ok(None)
+        };
+        let envelope = self.db.get_envelope(pref)?;
+        if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+            // keys not in the db are also mapped to a slot
+            if indexed.key == &key[..] {
+                return Ok(Some((pref, indexed.data.data.to_vec(), indexed.data.referred())));
+            }
+            return Ok(None);
+        }
+        Err(HammersbaldError::Corrupted("pref should point to indexed data".to_string()))
+    }
+
+    /// get data
+    /// returns (key, data, referred)
+    pub fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
+        self.db.get_referred(pref)
+    }
+
+    /// iterator for a DAG
+    pub fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
+        self.db.dag(root)
+    }
+
+    /// stop background writer
+    pub fn shutdown (&mut self) {
+        self.db.shutdown()
+    }
+}
@@ -32,6 +32,34 @@ extern crate siphasher;
 extern crate rand;
 extern crate byteorder;
 extern crate lru_cache;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature="trace")]
+extern crate tracing;
+
+// a tracing span entered until the end of the enclosing block, nothing without the trace feature
+#[cfg(feature="trace")]
+macro_rules! trace_span {
+    ($name:expr $(, $field:ident = $value:expr)*) => {
+        let _span = ::tracing::debug_span!($name $(, $field = $value)*).entered();
+    }
+}
+#[cfg(not(feature="trace"))]
+macro_rules! trace_span {
+    ($name:expr $(, $field:ident = $value:expr)*) => {}
+}
+
+// a tracing event at info level, nothing without the trace feature
+#[cfg(feature="trace")]
+macro_rules! trace_info {
+    ($($arg:tt)*) => {
+        ::tracing::info!($($arg)*);
+    }
+}
+#[cfg(not(feature="trace"))]
+macro_rules! trace_info {
+    ($($arg:tt)*) => {}
+}
 
 mod page;
 mod pagedfile;
@@ -41,13 +69,27 @@ mod cachedfile;
 mod singlefile;
 mod rolledfile;
 mod asyncfile;
+mod readonlyfile;
+mod spillfile;
+mod stripedfile;
 mod memtable;
 pub mod format;
 pub mod api;
 pub mod datafile;
 pub mod error;
+pub mod options;
 pub mod pref;
 pub mod transient;
 pub mod persistent;
+pub mod frozen;
+pub mod dataonly;
+pub mod sharded;
+pub mod writer;
+#[cfg(feature="crash-test")]
+pub mod crash;
+#[cfg(not(feature="crash-test"))]
+mod crash;
+#[cfg(test)]
+mod testdir;
 #[cfg(feature="bitcoin_support")]
 pub mod bitcoin_support;
\ No newline at end of file
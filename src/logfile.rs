@@ -25,29 +25,59 @@ use pref::PRef;
 
 use std::collections::HashSet;
 
+// layout of the first page
+pub const LOG_DATA_LEN: usize = 0;
+pub const LOG_TABLE_LEN: usize = 6;
+pub const LOG_LINK_LEN: usize = 12;
+// the bucket fill target, 0 in a log written by a version that did not record it
+pub const LOG_FILL_TARGET: usize = 18;
+
 pub struct LogFile {
     file: Box<PagedFile>,
     logged: HashSet<PRef>,
-    source_len: u64
+    source_len: u64,
+    fill_target: u8
 }
 
 impl LogFile {
     pub fn new(rw: Box<PagedFile>) -> LogFile {
-        LogFile { file: rw, logged: HashSet::new(), source_len:0 }
+        LogFile { file: rw, logged: HashSet::new(), source_len:0, fill_target: 0 }
+    }
+
+    /// set the bucket fill target recorded in the first page at the next init
+    pub fn set_fill_target (&mut self, fill_target: u8) {
+        self.fill_target = fill_target;
     }
 
     pub fn init (&mut self, data_len: u64, table_len: u64, link_len: u64) -> Result<(), HammersbaldError> {
         self.truncate(0)?;
         let mut first = Page::new(PRef::from(0));
-        first.write_pref(0, PRef::from(data_len));
-        first.write_pref(6, PRef::from(table_len));
-        first.write_pref(12, PRef::from(link_len));
+        first.write_u48(LOG_DATA_LEN, data_len)?;
+        first.write_u48(LOG_TABLE_LEN, table_len)?;
+        first.write_u48(LOG_LINK_LEN, link_len)?;
+        first.write(LOG_FILL_TARGET, &[self.fill_target]);
 
         self.append_page(first)?;
         self.flush()?;
         Ok(())
     }
 
+    /// record a new data length, keeping the logged pages
+    pub fn set_data_len (&mut self, data_len: u64) -> Result<(), HammersbaldError> {
+        let mut pages = self.page_iter().collect::<Vec<_>>();
+        if pages.is_empty() {
+            return Err(HammersbaldError::Corrupted("log has no first page".to_string()));
+        }
+        let logged = pages.split_off(1);
+        let table_len = pages[0].read_u48(LOG_TABLE_LEN)?;
+        let link_len = pages[0].read_u48(LOG_LINK_LEN)?;
+        self.init(data_len, table_len, link_len)?;
+        for page in logged {
+            self.append_page(page)?;
+        }
+        self.flush()
+    }
+
     pub fn page_iter (&self) -> PagedFileIterator {
         PagedFileIterator::new(self, PRef::from(0))
     }
@@ -97,4 +127,8 @@ impl PagedFile for LogFile {
     fn flush(&mut self) -> Result<(), HammersbaldError> {
         Ok(self.file.flush()?)
     }
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
 }
@@ -20,24 +20,38 @@
 //!
 use error::HammersbaldError;
 use pref::PRef;
-use datafile::{DataFile, DagIterator};
-use tablefile::{TableFile, FIRST_PAGE_HEAD, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE, BUCKET_SIZE};
-use logfile::LogFile;
+use datafile::{DataFile, DagIterator, DagReader, SharedPayload};
+use tablefile::{TableFile, FIRST_PAGE_HEAD, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE, BUCKET_SIZE, HEAD_BUCKETS, HEAD_STEP, HEAD_SIP0, HEAD_SIP1, HEAD_NAMESPACE_SHIFT};
+use logfile::{LogFile, LOG_DATA_LEN, LOG_TABLE_LEN, LOG_LINK_LEN, LOG_FILL_TARGET};
 use page::PAGE_SIZE;
 use pagedfile::PagedFile;
-use format::{Link, Payload, Envelope};
+use format::{Link, Payload, Envelope, IndexedData, Data, BucketCodec};
 use page::Page;
+use options::Options;
+use api::{RecoveryPlan, FragmentationReport};
 
+use crash::{BATCH_LOG_SYNCED, BATCH_TABLE_SYNCED, BATCH_DATA_SYNCED, RECOVERY_REPLAYED};
+#[cfg(feature="crash-test")]
+use crash::CrashPoint;
 use siphasher::sip::SipHasher;
-use rand::{thread_rng, RngCore};
+use rand::{thread_rng, Rng, RngCore};
 
 use std::hash::Hasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io::Read;
+use std::mem;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 use std::cmp::{min, max};
 
 const INIT_BUCKETS: usize = 512;
 const INIT_LOGMOD :usize = 8;
+// fill target of a new db if none is given
+const DEFAULT_FILL_TARGET: usize = 64;
+// wait before the first retry of a failed batch, doubled for each further retry
+const BATCH_RETRY_DELAY_MS: u64 = 10;
 
 pub struct MemTable {
     step: usize,
@@ -50,19 +64,64 @@ pub struct MemTable {
     data_file: DataFile,
     table_file: TableFile,
     link_file: DataFile,
-    bucket_fill_target: usize
+    bucket_fill_target: usize,
+    // the fill target recorded in the db is used unless one is given
+    fill_target_given: bool,
+    wide_hash: bool,
+    store_keys: bool,
+    recovery_memory_limit: usize,
+    resplit_on_load: bool,
+    load_threads: usize,
+    batch_retries: u32,
+    record_timestamps: bool,
+    trust_hash: bool,
+    header_bytes: usize,
+    magic_namespace: u16,
+    // headers of indexed data, those not known are read from the data file
+    headers: HashMap<PRef, Vec<u8>>,
+    bucket_codec: Option<Arc<BucketCodec>>,
+    #[cfg(feature="crash-test")]
+    crash_point: Option<Arc<CrashPoint>>,
+    lazy: bool,
+    orphaned: u64,
+    // position of the next data append at the end of the last batch
+    batched_position: PRef
 }
 
 impl MemTable {
-    pub fn new (log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bucket_fill_target: usize) -> MemTable {
+    pub fn new (mut log_file: LogFile, table_file: TableFile, mut data_file: DataFile, link_file: DataFile, options: &Options) -> MemTable {
         let mut rng = thread_rng();
+        let bucket_fill_target = if options.bucket_fill_target == 0 { DEFAULT_FILL_TARGET } else { max(min(options.bucket_fill_target, 128), 1) };
+        log_file.set_fill_target(bucket_fill_target as u8);
+        data_file.set_fixed_value_size(options.fixed_value_size);
+        data_file.set_referred_deltas(options.referred_deltas);
+        data_file.set_app_trailer_bytes(min(options.app_trailer_bytes, 64));
 
+        let batched_position = data_file.position();
         MemTable {log_mod: INIT_LOGMOD as u32, step: 0,
             sip0: rng.next_u64(),
             sip1: rng.next_u64(),
             buckets: vec!(Bucket::default(); INIT_BUCKETS),
             dirty: Dirty::new(INIT_BUCKETS), log_file, table_file, data_file, link_file,
-            bucket_fill_target: max(min(bucket_fill_target, 128), 1)}
+            bucket_fill_target,
+            fill_target_given: options.bucket_fill_target != 0,
+            wide_hash: options.wide_hash,
+            store_keys: options.store_keys,
+            recovery_memory_limit: options.recovery_memory_limit,
+            resplit_on_load: options.resplit_on_load,
+            load_threads: options.load_threads,
+            batch_retries: options.batch_retries,
+            record_timestamps: options.record_timestamps,
+            trust_hash: options.trust_hash,
+            header_bytes: min(options.header_bytes, 255),
+            magic_namespace: (options.magic_namespace[0] as u16) << 8 | options.magic_namespace[1] as u16,
+            headers: HashMap::new(),
+            bucket_codec: options.bucket_codec.clone(),
+            #[cfg(feature="crash-test")]
+            crash_point: options.crash_point.clone(),
+            lazy: false,
+            orphaned: 0,
+            batched_position}
     }
 
     pub fn init (&mut self) -> Result<(), HammersbaldError> {
@@ -75,10 +134,54 @@ impl MemTable {
         self.sip0, self.sip1)
     }
 
-    /// end current batch and start a new batch
+    /// number of pages waiting to be written in background
+    pub fn queue_len(&self) -> usize {
+        self.log_file.queue_len() + self.table_file.queue_len() + self.data_file.queue_len() + self.link_file.queue_len()
+    }
+
+    /// end the batch, retried on IO errors up to batch_retries times
+    /// a failed attempt is rolled back to the state in the log, so a retry or a later batch starts over
     pub fn batch (&mut self)  -> Result<(), HammersbaldError> {
+        trace_span!("batch", data_len = self.data_file.position().as_u64(), buckets = self.buckets.len());
+        let dirty = self.dirty.clone();
+        let mut retries = 0;
+        loop {
+            match self.batch_once() {
+                Err(HammersbaldError::IO(error)) => {
+                    self.roll_back_batch(&dirty)?;
+                    if retries == self.batch_retries {
+                        return Err(HammersbaldError::IO(error));
+                    }
+                    thread::sleep(Duration::from_millis(BATCH_RETRY_DELAY_MS << min(retries, 10)));
+                    retries += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    /// undo a failed batch attempt: truncate table and link to the logged lengths,
+    /// restore logged table pages and mark the buckets of the batch dirty again.
+    /// data is kept, it holds what the batch commits and is only appended to.
+    fn roll_back_batch (&mut self, dirty: &Dirty) -> Result<(), HammersbaldError> {
+        if let Some(page) = self.log_file.read_page(PRef::from(0))? {
+            let table_len = page.read_u48(LOG_TABLE_LEN)?;
+            let link_len = page.read_u48(LOG_LINK_LEN)?;
+            self.table_file.truncate(table_len)?;
+            self.link_file.truncate(link_len)?;
+            let pages = self.log_file.page_iter().skip(1).collect::<Vec<_>>();
+            for page in pages {
+                self.table_file.update_page(page)?;
+            }
+        }
+        self.dirty = dirty.clone();
+        Ok(())
+    }
+
+    fn batch_once (&mut self)  -> Result<(), HammersbaldError> {
         self.log_file.flush()?;
         self.log_file.sync()?;
+        self.crash(BATCH_LOG_SYNCED)?;
 
         self.flush()?;
         self.dirty.clear();
@@ -88,10 +191,13 @@ impl MemTable {
 
         self.link_file.sync()?;
         let link_len = self.link_file.len()?;
+        self.crash(BATCH_TABLE_SYNCED)?;
 
         self.data_file.flush()?;
         self.data_file.sync()?;
         let data_len = self.data_file.len()?;
+        self.crash(BATCH_DATA_SYNCED)?;
+        self.batched_position = self.data_file.position();
 
         self.log_file.reset(table_len);
         self.log_file.init(data_len, table_len, link_len)?;
@@ -101,6 +207,78 @@ impl MemTable {
         Ok(())
     }
 
+    /// true if data was stored or the hash table changed since the last batch
+    pub fn is_dirty (&self) -> bool {
+        self.dirty.is_dirty() || self.data_file.position() != self.batched_position
+    }
+
+    /// make data stored so far durable without ending the batch
+    /// the hash table is not stored, so data is found by key only after the batch
+    pub fn barrier (&mut self) -> Result<(), HammersbaldError> {
+        self.persist_data(true)
+    }
+
+    /// write data stored so far to the operating system, so it survives a crash of the process
+    /// with sync also to disk, so it survives a crash of the system
+    pub fn persist_data (&mut self, sync: bool) -> Result<(), HammersbaldError> {
+        self.data_file.flush()?;
+        if sync {
+            self.data_file.sync()?;
+        }
+        let data_len = self.data_file.len()?;
+        // recovery must not cut the data, table pages logged are needed until the batch ends
+        self.log_file.set_data_len(data_len)?;
+        if sync {
+            self.log_file.sync()?;
+        }
+        Ok(())
+    }
+
+    /// end current batch, then read back a random sample of the pages written in it
+    /// data pages are compared with their content before the batch, sampled buckets
+    /// are compared with the links they point to
+    pub fn batch_verified (&mut self, sample_ratio: f64) -> Result<(), HammersbaldError> {
+        let mut rng = thread_rng();
+        let mut data_from = 0;
+        if let Some(page) = self.log_file.read_page(PRef::from(0))? {
+            data_from = page.read_u48(LOG_DATA_LEN)?;
+        }
+        let mut pages = Vec::new();
+        let mut pref = PRef::from(data_from);
+        while pref < self.data_file.position().this_page() {
+            if rng.gen::<f64>() < sample_ratio {
+                if let Some(page) = self.data_file.read_page(pref)? {
+                    pages.push((pref, page));
+                }
+            }
+            pref += PAGE_SIZE as u64;
+        }
+        let buckets = DirtyIterator::new(&self.dirty).enumerate()
+            .filter(|&(_, dirty)| dirty && rng.gen::<f64>() < sample_ratio)
+            .map(|(bucket, _)| bucket).collect::<Vec<_>>();
+
+        self.batch()?;
+
+        for (pref, page) in pages {
+            match self.data_file.read_page(pref)? {
+                Some(stored) => if stored.into_buf()[..] != page.into_buf()[..] {
+                    return Err(HammersbaldError::Corrupted(format!("data page {} differs from written", pref)));
+                },
+                None => return Err(HammersbaldError::Corrupted(format!("data page {} was not written", pref)))
+            }
+        }
+        for bucket in buckets {
+            let bucket_pref = TableFile::table_offset(bucket);
+            if self.table_file.read_page(bucket_pref.this_page())?.is_none() {
+                return Err(HammersbaldError::Corrupted(format!("table page {} was not written", bucket_pref.this_page())));
+            }
+            if self.read_bucket(bucket)? != self.buckets[bucket].slots {
+                return Err(HammersbaldError::Corrupted(format!("bucket {} differs from written", bucket)));
+            }
+        }
+        Ok(())
+    }
+
     /// stop background writer
     pub fn shutdown (&mut self) {
         self.data_file.shutdown();
@@ -110,13 +288,47 @@ impl MemTable {
     }
 
     pub fn recover(&mut self) -> Result<(), HammersbaldError> {
+        trace_span!("recover", log_pages = self.log_file.len().unwrap_or(0) / PAGE_SIZE as u64);
+        if let Some((data_len, table_len, link_len)) = self.replay_log()? {
+            self.crash(RECOVERY_REPLAYED)?;
+            self.table_file.flush()?;
+            self.table_file.sync()?;
+
+            self.log_file.init(data_len, table_len, link_len)?;
+            self.log_file.flush()?;
+            self.log_file.sync()?;
+        }
+
+        self.validate_after_recovery()
+    }
+
+    /// check that no stored bucket points beyond the link file
+    pub fn validate_after_recovery(&self) -> Result<(), HammersbaldError> {
+        let link_len = self.link_file.len()?;
+        if let Some((bucket, link)) = self.table_file.iter().enumerate().filter(|(_, link)| link.is_valid()).max_by_key(|(_, link)| *link) {
+            if link.as_u64() >= link_len {
+                return Err(HammersbaldError::Corrupted(format!("bucket {} refers to link {} beyond link file length {}", bucket, link.as_u64(), link_len)));
+            }
+        }
+        Ok(())
+    }
+
+    /// truncate files to the lengths of the last batch and roll back logged table pages
+    /// returns the lengths if table pages were rolled back, the log is not written
+    pub fn replay_log(&mut self) -> Result<Option<(u64, u64, u64)>, HammersbaldError> {
         let mut data_len = 0;
         let mut table_len = 0;
         let mut link_len = 0;
         if let Some(page) = self.log_file.read_page(PRef::from(0))? {
-            data_len = page.read_pref(0).as_u64();
-            table_len = page.read_pref(6).as_u64();
-            link_len = page.read_pref(12).as_u64();
+            data_len = page.read_u48(LOG_DATA_LEN)?;
+            table_len = page.read_u48(LOG_TABLE_LEN)?;
+            link_len = page.read_u48(LOG_LINK_LEN)?;
+            let mut fill_target = [0u8; 1];
+            page.read(LOG_FILL_TARGET, &mut fill_target);
+            if !self.fill_target_given && fill_target[0] > 0 {
+                self.bucket_fill_target = fill_target[0] as usize;
+                self.log_file.set_fill_target(fill_target[0]);
+            }
 
             self.table_file.truncate(table_len)?;
             self.data_file.truncate(data_len)?;
@@ -124,59 +336,193 @@ impl MemTable {
         }
 
         if self.log_file.len()? > PAGE_SIZE as u64 {
-            for page in self.log_file.page_iter().skip(1) {
+            // only pages of the table as of the last batch are logged
+            let pages = self.log_file.page_iter().skip(1).collect::<Vec<_>>();
+            if let Some(page) = pages.iter().find(|page| page.pref().as_u64() >= table_len) {
+                return Err(HammersbaldError::Corrupted(format!("log has table page {} beyond table length {}", page.pref().as_u64(), table_len)));
+            }
+            for page in pages {
                 self.table_file.update_page(page)?;
             }
-            self.table_file.flush()?;
-            self.table_file.sync()?;
-
-            self.log_file.init(data_len, table_len, link_len)?;
-            self.log_file.flush()?;
-            self.log_file.sync()?;
+            return Ok(Some((data_len, table_len, link_len)));
         }
+        Ok(None)
+    }
 
-        Ok(())
+    /// what replay_log would do, nothing is written
+    pub fn recovery_plan(&self) -> Result<RecoveryPlan, HammersbaldError> {
+        let current_data_len = self.data_file.len()?;
+        let current_table_len = self.table_file.len()?;
+        let current_link_len = self.link_file.len()?;
+        let mut plan = RecoveryPlan {
+            current_data_len, target_data_len: current_data_len,
+            current_table_len, target_table_len: current_table_len,
+            current_link_len, target_link_len: current_link_len,
+            table_pages: Vec::new()
+        };
+        if let Some(page) = self.log_file.read_page(PRef::from(0))? {
+            plan.target_data_len = page.read_u48(LOG_DATA_LEN)?;
+            plan.target_table_len = page.read_u48(LOG_TABLE_LEN)?;
+            plan.target_link_len = page.read_u48(LOG_LINK_LEN)?;
+        }
+        if self.log_file.len()? > PAGE_SIZE as u64 {
+            plan.table_pages = self.log_file.page_iter().skip(1).map(|page| page.pref()).collect();
+            if let Some(pref) = plan.table_pages.iter().find(|pref| pref.as_u64() >= plan.target_table_len) {
+                return Err(HammersbaldError::Corrupted(format!("log has table page {} beyond table length {}", pref.as_u64(), plan.target_table_len)));
+            }
+        }
+        Ok(plan)
     }
 
     pub fn load (&mut self) -> Result<(), HammersbaldError>{
         if let Some(first) = self.table_file.read_page(PRef::from(0))? {
-            let n_buckets = first.read_pref(0).as_u64() as u32;
+            let buckets = first.read_u48(HEAD_BUCKETS)?;
+            if (buckets >> HEAD_NAMESPACE_SHIFT) as u16 != self.magic_namespace {
+                return Err(HammersbaldError::BadMagic);
+            }
+            let n_buckets = buckets as u32;
             self.buckets = vec!(Bucket::default(); n_buckets as usize);
             self.dirty = Dirty::new(n_buckets as usize);
-            self.step = first.read_pref(6).as_u64() as usize;
+            self.step = first.read_u48(HEAD_STEP)? as usize;
             self.log_mod = (32 - n_buckets.leading_zeros()) as u32 - 2;
-            self.sip0 = first.read_u64(12);
-            self.sip1 = first.read_u64(20);
+            self.sip0 = first.read_u64(HEAD_SIP0)?;
+            self.sip1 = first.read_u64(HEAD_SIP1)?;
+
+            let projected = n_buckets as usize * self.bucket_fill_target * mem::size_of::<(u64, PRef)>();
+            if self.recovery_memory_limit > 0 && projected > self.recovery_memory_limit {
+                trace_info!(projected, limit = self.recovery_memory_limit, "hash table exceeds recovery_memory_limit, buckets are read when first used");
+                self.lazy = true;
+                for bucket in &mut self.buckets {
+                    bucket.unread = true;
+                }
+                if let Some((_, envelope)) = self.link_file.envelopes().next() {
+                    if let Payload::Link(ref link) = Payload::deserialize(envelope.payload())? {
+                        self.wide_hash = link.is_wide();
+                    }
+                }
+                return Ok(());
+            }
         }
 
+        self.headers.clear();
         let mut link_to_bucket = HashMap::new();
         for (n, link) in self.table_file.iter().enumerate() {
             if link.is_valid() {
                 link_to_bucket.insert(link, n);
             }
         }
-        for (pos, envelope) in self.link_file.envelopes() {
-            if let Payload::Link(ref link) = Payload::deserialize(envelope.payload())? {
-                if let Some(bucket) = link_to_bucket.remove(&pos) {
-                    self.buckets[bucket].slots = link.slots();
+        if self.load_threads > 1 {
+            // links of buckets are read directly instead of scanning the link file
+            let mut links = link_to_bucket.drain().map(|(link, bucket)| (bucket, link)).collect::<Vec<_>>();
+            // each thread reads a range of the link file
+            links.sort_by_key(|(_, link)| *link);
+            self.read_links_parallel(links)?;
+        }
+        else {
+            for (pos, envelope) in self.link_file.envelopes() {
+                if let Payload::Link(ref link) = Payload::deserialize(envelope.payload())? {
+                    // an existing db keeps the hash width it was created with
+                    self.wide_hash = link.is_wide();
+                    if let Some(bucket) = link_to_bucket.remove(&pos) {
+                        self.buckets[bucket].slots = Self::link_slots(self.codec(), link)?;
+                        if link.header_len() == self.header_bytes && !link.is_encoded() {
+                            self.headers.extend(link.headers().into_iter().map(|(pref, header)| (pref, header.to_vec())));
+                        }
+                    }
                 }
             }
         }
         if !link_to_bucket.is_empty() {
             return Err(HammersbaldError::Corrupted(format!("could not find links for {} bucket(s)", link_to_bucket.len())));
         }
+        if self.resplit_on_load {
+            let target = self.buckets.iter().map(|b| b.slots.len()).sum::<usize>() / self.bucket_fill_target;
+            self.split_until(target)?;
+        }
         Ok(())
     }
 
+    /// drop data stored at or after data_len, a length of the data file after a batch
+    /// keys of data dropped are no longer found, also if they were stored before
+    pub fn rewind(&mut self, data_len: PRef) -> Result<(), HammersbaldError> {
+        self.batch()?;
+        if data_len.in_page_pos() != 0 || data_len > self.data_file.position() {
+            return Err(HammersbaldError::InvalidOffset);
+        }
+        for bucket in 0 .. self.buckets.len() {
+            let past = if self.buckets[bucket].unread {
+                self.read_bucket(bucket)?.iter().any(|(_, pref)| *pref >= data_len)
+            } else {
+                self.buckets[bucket].slots.iter().any(|(_, pref)| *pref >= data_len)
+            };
+            if past {
+                self.read_unread(bucket)?;
+                self.buckets[bucket].slots.retain(|(_, pref)| *pref < data_len);
+                self.dirty.set(bucket);
+            }
+        }
+        // the table no longer points to data dropped before it is dropped
+        self.batch()?;
+        self.data_file.truncate(data_len.as_u64())?;
+        self.batch()
+    }
+
+    /// rebuild the hash table from the data file
+    /// returns the number of indexed entries and the number of entries that could not be indexed
+    /// entries stored without key can not be indexed, also the scan stops at the first unreadable entry
+    pub fn rebuild_index(&mut self) -> Result<(usize, usize), HammersbaldError> {
+        self.table_file.truncate(0)?;
+        self.link_file.truncate(0)?;
+        self.log_file.reset(0);
+        self.step = 0;
+        self.log_mod = INIT_LOGMOD as u32;
+        self.buckets = vec!(Bucket::default(); INIT_BUCKETS);
+        self.dirty = Dirty::new(INIT_BUCKETS);
+
+        let mut indexed = vec!();
+        let mut discarded = 0;
+        let mut pos = self.data_file.lep();
+        while pos.is_valid() {
+            if let Ok(envelope) = self.data_file.get_envelope(pos) {
+                match Payload::deserialize(envelope.payload()) {
+                    Ok(Payload::Indexed(ref data)) if data.has_key() => indexed.push(pos),
+                    Ok(Payload::Indexed(_)) | Err(_) => discarded += 1,
+                    Ok(_) => {}
+                }
+                if envelope.previous() >= pos && envelope.previous().is_valid() {
+                    discarded += 1;
+                    break;
+                }
+                pos = envelope.previous();
+            }
+            else {
+                discarded += 1;
+                break;
+            }
+        }
+
+        // oldest first, so later puts of a key replace earlier
+        for pos in indexed.iter().rev() {
+            let envelope = self.data_file.get_envelope(*pos)?;
+            if let Payload::Indexed(data) = Payload::deserialize(envelope.payload())? {
+                self.put(data.key, *pos)?;
+            }
+        }
+        for bucket in 0 .. self.buckets.len() {
+            self.dirty.set(bucket);
+        }
+        Ok((self.buckets.iter().map(|b| b.slots.len()).sum(), discarded))
+    }
+
     pub fn flush (&mut self) -> Result<(), HammersbaldError> {
         {
             // first page
             let fp = PRef::from(0);
             let mut page = self.table_file.read_page(fp)?.unwrap_or(Self::invalid_offsets_page(fp));
-            page.write_pref(0, PRef::from(self.buckets.len() as u64));
-            page.write_pref(6, PRef::from(self.step as u64));
-            page.write_u64(12, self.sip0);
-            page.write_u64(20, self.sip1);
+            page.write_u48(HEAD_BUCKETS, self.buckets.len() as u64 | (self.magic_namespace as u64) << HEAD_NAMESPACE_SHIFT)?;
+            page.write_u48(HEAD_STEP, self.step as u64)?;
+            page.write_u64(HEAD_SIP0, self.sip0)?;
+            page.write_u64(HEAD_SIP1, self.sip1)?;
             self.table_file.update_page(page)?;
         }
         if self.dirty.is_dirty() {
@@ -186,8 +532,19 @@ impl MemTable {
                 if let Some(bucket) = self.buckets.get(bucket_number) {
                     let mut page = self.table_file.read_page(bucket_pref.this_page())?.unwrap_or(Self::invalid_offsets_page(bucket_pref.this_page()));
                     let link = if bucket.slots.len() > 0 {
-                        let slots = Link::from_slots(bucket.slots.as_slice());
-                        self.link_file.append_link(Link::deserialize(slots.as_slice()))?
+                        if let Some(codec) = self.codec() {
+                            let encoded = codec.encode(bucket.slots.as_slice());
+                            self.link_file.append_link(Link::encoded(encoded.as_slice(), self.wide_hash))?
+                        } else {
+                            let slots = if self.header_bytes > 0 {
+                                let headers = bucket.slots.iter().map(|(_, pref)| self.header_of(*pref)).collect::<Result<Vec<_>, _>>()?;
+                                Link::from_slots_with_headers(bucket.slots.iter().cloned().zip(headers.iter().map(|h| h.as_slice())),
+                                    self.wide_hash, self.header_bytes)
+                            } else {
+                                Link::from_slots(bucket.slots.as_slice(), self.wide_hash)
+                            };
+                            self.link_file.append_link(Link::new(slots.as_slice(), self.wide_hash, self.header_bytes))?
+                        }
                     } else {
                         PRef::invalid()
                     };
@@ -217,30 +574,194 @@ impl MemTable {
         page
     }
 
-    pub fn slots<'a>(&'a self) -> impl Iterator<Item=&'a Vec<(u32, PRef)>> +'a {
+    /// a bucket not yet read in lazy mode is read here, an error reading it is its item
+    pub fn slots<'a>(&'a self) -> impl Iterator<Item=Result<Vec<(u64, PRef)>, HammersbaldError>> +'a {
         BucketIterator{file: self, n:0}
     }
 
+    /// the slots of each bucket as stored at the last batch, read from the table and link files
+    /// one bucket at a time, buckets in memory are not used
+    /// an error reading the table head is the only item, an error reading a bucket is its item
+    pub fn stream_buckets<'a>(&'a self) -> impl Iterator<Item=Result<(usize, Vec<(u64, PRef)>), HammersbaldError>> +'a {
+        let head = match self.table_file.read_page(PRef::from(0)) {
+            Ok(Some(first)) => first.read_u48(HEAD_BUCKETS).map(|n| n as u32 as usize),
+            Ok(None) => Ok(0),
+            Err(e) => Err(e)
+        };
+        let (n_buckets, error) = match head {
+            Ok(n) => (n, None),
+            Err(e) => (0, Some(Err(e)))
+        };
+        error.into_iter().chain(self.table_file.iter().take(n_buckets).enumerate().map(move |(n, link)| {
+            if link.is_valid() {
+                return Ok((n, Self::read_link(&self.link_file, self.codec(), link, 0)?.0));
+            }
+            Ok((n, Vec::new()))
+        }))
+    }
+
     pub fn buckets<'a>(&'a self) -> impl Iterator<Item=PRef> +'a {
         self.table_file.iter()
     }
 
+    /// (bucket number, pref of the stored link, number of slots) for each bucket
+    /// the pref is invalid if the bucket was not yet stored
+    pub fn buckets_with_fill<'a>(&'a self) -> impl Iterator<Item=Result<(usize, PRef, usize), HammersbaldError>> +'a {
+        self.slots().enumerate().map(move |(n, slots)| Ok((n, self.stored_link(n)?, slots?.len())))
+    }
+
     pub fn data_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         self.data_file.envelopes()
     }
 
+    pub fn data_envelopes_range<'a>(&'a self, from: PRef, to: PRef) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+        self.data_file.envelopes_range(from, to)
+    }
+
     pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         self.link_file.envelopes()
     }
 
-    pub fn append_data (&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
-        self.data_file.append_data(key, data, referred)
+    pub fn filter_entries<'a, F>(&'a self, pred: F) -> impl Iterator<Item=Result<(Vec<u8>, PRef), HammersbaldError>> +'a
+        where F: Fn(&[u8], &[u8]) -> bool + 'a {
+        self.slots().flat_map(|slots| match slots {
+                Ok(slots) => slots.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec!(Err(e))
+            })
+            .filter_map(move |slot| {
+                let found = slot.and_then(|(_, pref)| {
+                    match Payload::deserialize(self.data_file.get_envelope(pref)?.payload())? {
+                        Payload::Indexed(ref indexed) if pred(indexed.key, indexed.data.data) => Ok(Some((indexed.key.to_vec(), pref))),
+                        _ => Ok(None)
+                    }
+                });
+                match found {
+                    Ok(Some(entry)) => Some(Ok(entry)),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e))
+                }
+            })
+    }
+
+    /// call f with the key of each accessible indexed data stored with its key
+    pub fn for_each_key<F>(&self, mut f: F) -> Result<(), HammersbaldError> where F: FnMut(&[u8]) {
+        for slots in self.slots() {
+            for (_, pref) in slots? {
+                if let Payload::Indexed(indexed) = Payload::deserialize(self.data_file.get_envelope(pref)?.payload())? {
+                    if indexed.has_key() {
+                        f(indexed.key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn append_data (&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>, flags: u8) -> Result<PRef, HammersbaldError> {
+        let rv = Data::from_referred(referred.as_slice());
+        let header = if self.header_bytes > 0 {
+            Some(data[.. min(data.len(), self.header_bytes)].to_vec())
+        } else {
+            None
+        };
+        let indexed = self.indexed(key, Data::new(data, rv.as_slice()), flags);
+        let pref = self.data_file.append_indexed(indexed)?;
+        if let Some(header) = header {
+            self.headers.insert(pref, header);
+        }
+        Ok(pref)
+    }
+
+    /// append indexed data of len bytes read from reader as append_data without referred
+    pub fn append_data_stream<R: Read> (&mut self, key: &[u8], reader: &mut R, len: usize, flags: u8) -> Result<PRef, HammersbaldError> {
+        let mut header = vec!(0u8; min(len, self.header_bytes));
+        reader.read_exact(&mut header)?;
+        let indexed = self.indexed(key, Data::new(&[], &[]), flags);
+        let pref = self.data_file.append_indexed_stream(indexed, &mut header.as_slice().chain(reader), len)?;
+        if self.header_bytes > 0 {
+            self.headers.insert(pref, header);
+        }
+        Ok(pref)
+    }
+
+    // indexed data as stored by this table, with or without key and timestamp
+    fn indexed<'e> (&self, key: &'e [u8], data: Data<'e>, flags: u8) -> IndexedData<'e> {
+        let indexed = if self.store_keys {
+            IndexedData::new(key, data)
+        } else {
+            IndexedData::hashed(data)
+        };
+        let mut indexed = indexed.with_flags(flags);
+        if self.record_timestamps {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+            indexed = indexed.with_timestamp(now.as_secs() * 1000 + now.subsec_millis() as u64);
+        }
+        indexed
+    }
+
+    // the first header_bytes of indexed data
+    fn header_of(&self, pref: PRef) -> Result<Vec<u8>, HammersbaldError> {
+        if let Some(header) = self.headers.get(&pref) {
+            return Ok(header.clone());
+        }
+        match Payload::deserialize(self.data_file.get_envelope(pref)?.payload())? {
+            Payload::Indexed(indexed) => Ok(indexed.data.data[.. min(indexed.data.data.len(), self.header_bytes)].to_vec()),
+            _ => Err(HammersbaldError::Corrupted("pref should point to indexed data".to_string()))
+        }
+    }
+
+    // the headers of the slots of a bucket, with the prefs of the slots
+    fn bucket_headers(&self, bucket: usize) -> Result<Vec<(PRef, Vec<u8>)>, HammersbaldError> {
+        if self.buckets[bucket].unread {
+            let link = self.stored_link(bucket)?;
+            if !link.is_valid() {
+                return Ok(vec!());
+            }
+            let (slots, headers) = Self::read_link(&self.link_file, self.codec(), link, self.header_bytes)?;
+            if headers.len() == slots.len() {
+                return Ok(headers);
+            }
+            return slots.iter().map(|(_, pref)| Ok((*pref, self.header_of(*pref)?))).collect();
+        }
+        self.buckets[bucket].slots.iter().map(|(_, pref)| Ok((*pref, self.header_of(*pref)?))).collect()
+    }
+
+    /// keys and prefs of accessible indexed data whose header, the first header_bytes of its data,
+    /// satisfies pred. Headers are stored with the hash table, only data of matching headers is read
+    /// the key is empty if only its hash was stored
+    /// an error reading a bucket or data is yielded in place of its entries
+    pub fn scan_by_header<'a, F>(&'a self, pred: F) -> impl Iterator<Item=Result<(Vec<u8>, PRef), HammersbaldError>> +'a
+        where F: Fn(&[u8]) -> bool + 'a {
+        (0 .. self.buckets.len()).flat_map(move |bucket| match self.bucket_headers(bucket) {
+                Ok(headers) => headers.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec!(Err(e))
+            })
+            .filter_map(move |headed| {
+                let found = headed.and_then(|(pref, header)| {
+                    if !pred(&header) {
+                        return Ok(None);
+                    }
+                    match Payload::deserialize(self.data_file.get_envelope(pref)?.payload())? {
+                        Payload::Indexed(indexed) => Ok(Some((indexed.key.to_vec(), pref))),
+                        _ => Ok(None)
+                    }
+                });
+                match found {
+                    Ok(Some(entry)) => Some(Ok(entry)),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e))
+                }
+            })
     }
 
     pub fn append_referred (&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
         self.data_file.append_referred(data, referred)
     }
 
+    pub fn append_raw_entry (&mut self, bytes: &[u8]) -> Result<PRef, HammersbaldError> {
+        self.data_file.append_raw_entry(bytes)
+    }
+
     pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, HammersbaldError> {
         self.data_file.get_envelope(pref)
     }
@@ -249,15 +770,89 @@ impl MemTable {
         self.data_file.dag(root)
     }
 
-    pub fn put (&mut self, key: &[u8], data_offset: PRef) -> Result<(), HammersbaldError>{
+    pub fn reachable(&self, roots: &[PRef]) -> DagIterator {
+        self.data_file.reachable(roots)
+    }
+
+    pub fn dag_reader(&self, root: PRef) -> DagReader {
+        self.data_file.dag_reader(root)
+    }
+
+    /// index data with a key, returns true if it replaced data of the key
+    pub fn put (&mut self, key: &[u8], data_offset: PRef) -> Result<bool, HammersbaldError>{
         let hash = self.hash(key);
         let bucket = self.bucket_for_hash(hash);
 
-        self.remove_duplicate(key, hash, bucket)?;
+        let replaced = self.remove_duplicate(key, hash, bucket)?;
 
         self.store_to_bucket(bucket, hash, data_offset)?;
 
-        if thread_rng().next_u32() % self.bucket_fill_target as u32 == 0 && self.step < (1 << 31) {
+        if thread_rng().next_u32() % self.bucket_fill_target as u32 == 0 {
+            self.split()?;
+        }
+        Ok(replaced)
+    }
+
+    /// index data appended without indexing, oldest first
+    /// the table is grown to the size inserts would have grown it to, then all buckets are built at once
+    pub fn bulk_index(&mut self, added: Vec<(u64, PRef)>) -> Result<(), HammersbaldError> {
+        let mut all = Vec::with_capacity(added.len());
+        for bucket in 0 .. self.buckets.len() {
+            self.read_unread(bucket)?;
+            all.extend(self.buckets[bucket].slots.drain(..));
+        }
+        all.extend(added);
+        // same hashes next to each other, older data first
+        all.sort_by_key(|&(hash, pref)| (hash, pref.as_u64()));
+
+        let mut kept = Vec::with_capacity(all.len());
+        let mut same_hash = Vec::new();
+        for (hash, pref) in all {
+            if same_hash.last().map(|&(h, _)| h != hash).unwrap_or(false) {
+                self.keep_newest(&mut same_hash, &mut kept)?;
+            }
+            same_hash.push((hash, pref));
+        }
+        self.keep_newest(&mut same_hash, &mut kept)?;
+
+        let target = kept.len() / self.bucket_fill_target;
+        self.split_until(target)?;
+        for (hash, pref) in kept {
+            let bucket = self.bucket_for_hash(hash);
+            self.buckets[bucket].slots.push((hash, pref));
+        }
+        for bucket in 0 .. self.buckets.len() {
+            self.modify_bucket(bucket)?;
+        }
+        Ok(())
+    }
+
+    // keep slots of the same hash as a put would have, the newest for a key
+    fn keep_newest(&mut self, same_hash: &mut Vec<(u64, PRef)>, kept: &mut Vec<(u64, PRef)>) -> Result<(), HammersbaldError> {
+        if same_hash.len() == 1 {
+            kept.push(same_hash[0]);
+        } else {
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            for (hash, pref) in same_hash.iter().rev() {
+                let envelope = self.data_file.get_envelope(*pref)?;
+                if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                    if !keys.is_empty() && (!indexed.has_key() || keys.iter().any(|k| k.as_slice() == indexed.key)) {
+                        self.orphaned += (3 + 6 + envelope.payload().len()) as u64;
+                        continue;
+                    }
+                    keys.push(indexed.key.to_vec());
+                }
+                kept.push((*hash, *pref));
+            }
+        }
+        same_hash.clear();
+        Ok(())
+    }
+
+    /// perform one step of linear hashing, returns false if the table can not grow further
+    pub fn split(&mut self) -> Result<bool, HammersbaldError> {
+        trace_span!("split", step = self.step, log_mod = self.log_mod);
+        if self.step < (1 << 31) {
             if self.step < (1 << self.log_mod) {
                 let step = self.step;
                 self.rehash_bucket(step)?;
@@ -271,30 +866,43 @@ impl MemTable {
 
             self.buckets.push(Bucket::default());
             self.dirty.append();
+            return Ok(true);
         }
+        Ok(false)
+    }
+
+    /// split until the table has at least n_buckets or can not grow further
+    pub fn split_until(&mut self, n_buckets: usize) -> Result<(), HammersbaldError> {
+        while self.buckets.len() < n_buckets && self.split()? {}
         Ok(())
     }
 
-    fn remove_duplicate(&mut self, key: &[u8], hash: u32, bucket: usize) -> Result<(), HammersbaldError> {
+    // returns true if a slot was removed
+    fn remove_duplicate(&mut self, key: &[u8], hash: u64, bucket: usize) -> Result<bool, HammersbaldError> {
+        self.read_unread(bucket)?;
         if let Some(bucket) = self.buckets.get_mut(bucket) {
             let mut remove = None;
             for (n, (_, pref)) in bucket.slots.iter().enumerate()
                 .filter(|s| (s.1).0 == hash) {
                 let envelope = self.data_file.get_envelope(*pref)?;
                 if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
-                    if indexed.key == key {
-                        remove = Some(n);
+                    if indexed.key == key || !indexed.has_key() {
+                        remove = Some((n, (3 + 6 + envelope.payload().len()) as u64));
                     }
                 }
             }
-            if let Some(r) = remove {
-                bucket.slots.remove(r);
+            if let Some((r, size)) = remove {
+                let (_, pref) = bucket.slots.remove(r);
+                self.headers.remove(&pref);
+                self.orphaned += size;
+                return Ok(true);
             }
         }
-        Ok(())
+        Ok(false)
     }
 
-    fn store_to_bucket(&mut self, bucket: usize, hash: u32, pref: PRef) -> Result<(), HammersbaldError> {
+    fn store_to_bucket(&mut self, bucket: usize, hash: u64, pref: PRef) -> Result<(), HammersbaldError> {
+        self.read_unread(bucket)?;
         if let Some(bucket) = self.buckets.get_mut(bucket as usize) {
             bucket.slots.push((hash, pref));
         } else {
@@ -305,12 +913,13 @@ impl MemTable {
     }
 
     fn rehash_bucket(&mut self, bucket: usize) -> Result<(), HammersbaldError> {
+        self.read_unread(bucket)?;
         let mut rewrite = false;
         let mut new_bucket_store = Bucket::default();
         let mut moves = HashMap::new();
         if let Some(b) = self.buckets.get(bucket as usize) {
             for (hash, pref) in &b.slots {
-                let new_bucket = (hash & (!0u32 >> (32 - self.log_mod - 1))) as usize; // hash % 2^(log_mod + 1)
+                let new_bucket = (hash & (!0u64 >> (64 - self.log_mod - 1))) as usize; // hash % 2^(log_mod + 1)
                 if new_bucket != bucket {
                     moves.entry(new_bucket).or_insert(Vec::new()).push((*hash, *pref));
                     rewrite = true;
@@ -344,45 +953,369 @@ impl MemTable {
         self.log_file.log_page(bucket_page, &self.table_file)
     }
 
+    /// remove slots that point beyond the data file or to anything but indexed data
+    /// returns the number of slots removed, the repaired buckets are stored with the next batch
+    pub fn repair_dangling(&mut self) -> Result<u64, HammersbaldError> {
+        let data_len = self.data_file.position().as_u64();
+        let mut removed = 0;
+        for bucket in 0 .. self.buckets.len() {
+            self.read_unread(bucket)?;
+            let before = self.buckets[bucket].slots.len();
+            {
+                let data_file = &self.data_file;
+                self.buckets[bucket].slots.retain(|&(_, pref)| pref.as_u64() < data_len && Self::is_indexed(data_file, pref));
+            }
+            let after = self.buckets[bucket].slots.len();
+            if after < before {
+                removed += (before - after) as u64;
+                self.modify_bucket(bucket)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn is_indexed(data_file: &DataFile, pref: PRef) -> bool {
+        if let Ok(envelope) = data_file.get_envelope(pref) {
+            let payload = envelope.payload();
+            return payload[0] != 1 && Payload::well_formed_data(payload);
+        }
+        false
+    }
+
     // get the data last associated with the key
     pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
-        let hash = self.hash(key);
+        Ok(self.get_tagged(key)?.map(|(pref, _, data, referred)| (pref, data, referred)))
+    }
+
+    // get the data last associated with the key and the time it was stored at, if recorded
+    pub fn get_with_time(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>, Option<u64>)>, HammersbaldError> {
+        if let Some((pref, envelope)) = self.lookup(key)? {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                return Ok(Some((pref, indexed.data.data.to_vec(), indexed.data.referred(), indexed.timestamp())));
+            }
+        }
+        Ok(None)
+    }
+
+    // get the data and flags last associated with the key
+    pub fn get_tagged(&self, key: &[u8]) -> Result<Option<(PRef, u8, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        if let Some((pref, envelope)) = self.lookup(key)? {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                return Ok(Some((pref, indexed.flags(), indexed.data.data.to_vec(), indexed.data.referred())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// the pref of the data last associated with the key, its data is not copied
+    /// with trust_hash the data is not read
+    pub fn pref_of(&self, key: &[u8]) -> Result<Option<PRef>, HammersbaldError> {
+        if self.trust_hash {
+            return Ok(self.hash_matches(key)?.first().cloned());
+        }
+        Ok(self.lookup(key)?.map(|(pref, _)| pref))
+    }
+
+    // find the envelope of indexed data of a key
+    /// the pref and envelope of indexed data last associated with the key
+    /// with trust_hash the first data with the hash of the key is returned, whatever its key
+    pub fn lookup(&self, key: &[u8]) -> Result<Option<(PRef, Envelope)>, HammersbaldError> {
+        self.lookup_hashed(key, self.hash(key))
+    }
+
+    /// lookup with the hash of the key already computed
+    pub fn lookup_hashed(&self, key: &[u8], hash: u64) -> Result<Option<(PRef, Envelope)>, HammersbaldError> {
+        for data in self.hash_matches_hashed(hash)? {
+            let envelope = self.data_file.get_envelope(data)?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if !self.trust_hash && indexed.key != key && indexed.has_key() {
+                    continue;
+                }
+            } else {
+                return Err(HammersbaldError::Corrupted("pref should point to indexed data".to_string()));
+            }
+            return Ok(Some((data, envelope)));
+        }
+        Ok(None)
+    }
+
+    /// lookup as lookup, the payload is shared with the page cache if stored within a page
+    pub fn lookup_shared(&self, key: &[u8]) -> Result<Option<(PRef, SharedPayload)>, HammersbaldError> {
+        for data in self.hash_matches(key)? {
+            let shared = self.data_file.get_shared(data)?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(shared.payload())? {
+                if !self.trust_hash && indexed.key != key && indexed.has_key() {
+                    continue;
+                }
+            } else {
+                return Err(HammersbaldError::Corrupted("pref should point to indexed data".to_string()));
+            }
+            return Ok(Some((data, shared)));
+        }
+        Ok(None)
+    }
+
+    // prefs of indexed data with the hash of the key, newest first
+    fn hash_matches(&self, key: &[u8]) -> Result<Vec<PRef>, HammersbaldError> {
+        self.hash_matches_hashed(self.hash(key))
+    }
+
+    fn hash_matches_hashed(&self, hash: u64) -> Result<Vec<PRef>, HammersbaldError> {
         let bucket_number = self.bucket_for_hash(hash);
         if let Some(ref bucket) = self.buckets.get(bucket_number) {
-            for (h, data) in &bucket.slots {
-                if *h == hash {
-                    let envelope = self.data_file.get_envelope(*data)?;
-                    if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
-                        if indexed.key == key {
-                            return Ok(Some((*data, indexed.data.data.to_vec(), indexed.data.referred())));
-                        }
-                    } else {
-                        return Err(HammersbaldError::Corrupted("pref should point to indexed data".to_string()));
-                    }
+            let read;
+            let slots = if bucket.unread {
+                read = self.read_bucket(bucket_number)?;
+                &read
+            } else {
+                &bucket.slots
+            };
+            let mut matches = slots.iter().filter(|(h, _)| *h == hash).map(|(_, data)| *data).collect::<Vec<_>>();
+            // a put leaves one slot per key, should there be more the latest data of a key is found
+            matches.sort_unstable_by(|a, b| b.cmp(a));
+            return Ok(matches);
+        }
+        Err(HammersbaldError::Corrupted(format!("bucket {} should exist", bucket_number)))
+    }
+
+    /// estimated bytes stored for the data last associated with the key
+    /// the envelope with its length prefix, its share of page trailers and its slot in a link
+    /// the link envelope header and the table entry are shared by the bucket and not counted
+    pub fn entry_footprint(&self, key: &[u8]) -> Result<Option<u64>, HammersbaldError> {
+        if let Some((pref, _, _, _)) = self.get_tagged(key)? {
+            let envelope = self.data_file.get_envelope(pref)?;
+            // length, previous and payload
+            let stored = (3 + 6 + envelope.payload().len()) as u64;
+            let payload_size = self.data_file.page_payload_size() as u64;
+            let paged = (stored * PAGE_SIZE as u64 + payload_size - 1) / payload_size;
+            let slot = if self.wide_hash { 14 } else { 10 };
+            return Ok(Some(paged + slot));
+        }
+        Ok(None)
+    }
+
+    /// the pages the data last associated with the key is stored in
+    pub fn value_pages(&self, key: &[u8]) -> Result<Option<Vec<PRef>>, HammersbaldError> {
+        if let Some((pref, _)) = self.lookup(key)? {
+            return Ok(Some(self.data_file.envelope_pages(pref)?));
+        }
+        Ok(None)
+    }
+
+    // read the slots of a bucket from the link stored for it
+    fn read_bucket(&self, bucket: usize) -> Result<Vec<(u64, PRef)>, HammersbaldError> {
+        let link = self.stored_link(bucket)?;
+        if link.is_valid() {
+            return Ok(Self::read_link(&self.link_file, self.codec(), link, 0)?.0);
+        }
+        Ok(Vec::new())
+    }
+
+    // the slots of a link and their headers if stored with header_bytes
+    fn read_link(link_file: &DataFile, codec: Option<&BucketCodec>, link: PRef, header_bytes: usize) -> Result<(Vec<(u64, PRef)>, Vec<(PRef, Vec<u8>)>), HammersbaldError> {
+        match Payload::deserialize(link_file.get_envelope(link)?.payload())? {
+            Payload::Link(link) => {
+                let mut headers = Vec::new();
+                if header_bytes > 0 && link.header_len() == header_bytes && !link.is_encoded() {
+                    headers = link.headers().into_iter().map(|(pref, header)| (pref, header.to_vec())).collect();
                 }
+                Ok((Self::link_slots(codec, &link)?, headers))
+            },
+            _ => Err(HammersbaldError::Corrupted(format!("no link at {}", link)))
+        }
+    }
+
+    fn codec(&self) -> Option<&BucketCodec> {
+        self.bucket_codec.as_ref().map(|codec| codec.as_ref())
+    }
+
+    // the slots of a link, decoded by the codec if the link was encoded
+    fn link_slots(codec: Option<&BucketCodec>, link: &Link) -> Result<Vec<(u64, PRef)>, HammersbaldError> {
+        if link.is_encoded() {
+            return match codec {
+                Some(codec) => codec.decode(link.bytes()),
+                None => Err(HammersbaldError::Corrupted("link encoded by a bucket codec, but none is given".to_string()))
+            };
+        }
+        Ok(link.slots())
+    }
+
+    // read the links of buckets by load_threads threads, each reading a share of the buckets
+    // the threads borrow the link file, so they are scoped, that needs Rust 1.63 as in Cargo.toml
+    fn read_links_parallel(&mut self, links: Vec<(usize, PRef)>) -> Result<(), HammersbaldError> {
+        let share = max((links.len() + self.load_threads - 1) / self.load_threads, 1);
+        let link_file = &self.link_file;
+        let header_bytes = self.header_bytes;
+        let codec = self.codec();
+        let read = thread::scope(|scope| {
+            let workers = links.chunks(share).map(|chunk| scope.spawn(move || {
+                chunk.iter().map(|(bucket, link)| Ok((*bucket, Self::read_link(link_file, codec, *link, header_bytes)?)))
+                    .collect::<Result<Vec<_>, HammersbaldError>>()
+            })).collect::<Vec<_>>();
+            workers.into_iter().map(|worker| worker.join().expect("load thread panicked")).collect::<Vec<_>>()
+        });
+        for result in read {
+            for (bucket, (slots, headers)) in result? {
+                self.buckets[bucket].slots = slots;
+                self.headers.extend(headers);
             }
         }
-        else {
-            return Err(HammersbaldError::Corrupted(format!("bucket {} should exist", bucket_number)));
+        if let Some((_, envelope)) = self.link_file.envelopes().next() {
+            if let Payload::Link(ref link) = Payload::deserialize(envelope.payload())? {
+                self.wide_hash = link.is_wide();
+            }
         }
-        Ok(None)
+        Ok(())
+    }
+
+    // the pref of the link stored for a bucket, invalid if none
+    fn stored_link(&self, bucket: usize) -> Result<PRef, HammersbaldError> {
+        let bucket_pref = TableFile::table_offset(bucket);
+        if let Some(page) = self.table_file.read_page(bucket_pref.this_page())? {
+            return Ok(page.read_pref(bucket_pref.in_page_pos()));
+        }
+        Ok(PRef::invalid())
     }
 
-    fn bucket_for_hash(&self, hash: u32) -> usize {
-        let mut bucket = (hash & (!0u32 >> (32 - self.log_mod))) as usize; // hash % 2^(log_mod)
+    // keep the slots of a bucket in memory before it is modified
+    fn read_unread(&mut self, bucket: usize) -> Result<(), HammersbaldError> {
+        if self.buckets.get(bucket).map(|b| b.unread).unwrap_or(false) {
+            let link = self.stored_link(bucket)?;
+            let (slots, headers) = if link.is_valid() {
+                Self::read_link(&self.link_file, self.codec(), link, self.header_bytes)?
+            } else {
+                (Vec::new(), Vec::new())
+            };
+            self.headers.extend(headers);
+            self.buckets[bucket] = Bucket { slots, unread: false };
+        }
+        Ok(())
+    }
+
+    /// bytes of indexed data overwritten since open, referred data is not tracked
+    pub fn orphaned_bytes(&self) -> u64 {
+        self.orphaned
+    }
+
+    /// how the bytes of the data file are used, scanning the data file once
+    pub fn fragmentation(&self) -> Result<FragmentationReport, HammersbaldError> {
+        let mut accessible = HashSet::new();
+        for slots in self.slots() {
+            accessible.extend(slots?.into_iter().map(|(_, pref)| pref));
+        }
+        let total_bytes = self.data_file.position().as_u64();
+        let page_trailer_bytes = total_bytes / PAGE_SIZE as u64 * (PAGE_SIZE - self.data_file.page_payload_size()) as u64;
+        let mut report = FragmentationReport { total_bytes, live_bytes: 0, orphaned_bytes: 0, padding_bytes: 0, page_trailer_bytes };
+        // start of the envelope appended after the current one, envelopes are iterated latest first
+        let mut next: Option<PRef> = None;
+        for (pref, envelope) in self.data_file.envelopes() {
+            let stored = match next {
+                // a batch pads its last page, so an envelope starting a page may follow padding
+                Some(end) if end.in_page_pos() != 0 => self.data_file.len_between(pref, end),
+                _ => self.data_file.stored_len(pref)?
+            };
+            next = Some(pref);
+            match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(_) if !accessible.contains(&pref) => report.orphaned_bytes += stored,
+                _ => report.live_bytes += stored
+            }
+        }
+        report.padding_bytes = total_bytes.saturating_sub(report.live_bytes + report.orphaned_bytes + page_trailer_bytes);
+        Ok(report)
+    }
+
+    /// bytes appended to the data file
+    pub fn data_len(&self) -> u64 {
+        self.data_file.position().as_u64()
+    }
+
+    /// are buckets read from disk when first used
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// number of buckets of the hash table
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// the bucket split next in this round of linear hashing
+    pub fn split_step(&self) -> u32 {
+        self.step as u32
+    }
+
+    /// a round of linear hashing ends after 2^(log_mod + 1) splits
+    pub fn log_mod(&self) -> u32 {
+        self.log_mod
+    }
+
+    #[cfg(feature="crash-test")]
+    fn crash(&self, point: &str) -> Result<(), HammersbaldError> {
+        if let Some(ref crash_point) = self.crash_point {
+            if crash_point.crash(point) {
+                return Err(HammersbaldError::Crashed(point.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature="crash-test"))]
+    fn crash(&self, _: &str) -> Result<(), HammersbaldError> {
+        Ok(())
+    }
+
+    fn bucket_for_hash(&self, hash: u64) -> usize {
+        let mut bucket = (hash & (!0u64 >> (64 - self.log_mod))) as usize; // hash % 2^(log_mod)
         if bucket < self.step {
-            bucket = (hash & (!0u32 >> (32 - self.log_mod - 1))) as usize; // hash % 2^(log_mod + 1)
+            bucket = (hash & (!0u64 >> (64 - self.log_mod - 1))) as usize; // hash % 2^(log_mod + 1)
         }
         bucket
     }
 
-    fn hash (&self, key: &[u8]) -> u32 {
+    /// are hashes 64 bits wide
+    pub fn is_wide_hash(&self) -> bool {
+        self.wide_hash
+    }
+
+    /// set the app trailer of data pages completed from now
+    pub fn write_app_trailer(&mut self, trailer: &[u8]) {
+        self.data_file.write_app_trailer(trailer)
+    }
+
+    /// the app trailer of the data page of pref
+    pub fn read_app_trailer(&self, pref: PRef) -> Result<Vec<u8>, HammersbaldError> {
+        self.data_file.read_app_trailer(pref)
+    }
+
+    /// the bucket fill target in use, it is recorded in the db at the next batch
+    pub fn configured_fill_target(&self) -> usize {
+        self.bucket_fill_target
+    }
+
+    /// change the number of pages cached per file
+    pub fn resize_cache(&mut self, pages: usize) {
+        self.data_file.resize_cache(pages);
+        self.link_file.resize_cache(pages);
+        self.table_file.resize_cache(pages);
+    }
+
+    /// the keys of the hash of keys
+    pub fn sip_keys(&self) -> (u64, u64) {
+        (self.sip0, self.sip1)
+    }
+
+    pub fn hash (&self, key: &[u8]) -> u64 {
         let mut hasher = SipHasher::new_with_keys(self.sip0, self.sip1);
         hasher.write(key);
-        hasher.finish() as u32
+        if self.wide_hash {
+            hasher.finish()
+        } else {
+            hasher.finish() as u32 as u64
+        }
     }
 }
 
+#[derive(Clone)]
 struct Dirty {
     bits: Vec<u64>,
     used: usize
@@ -438,12 +1371,16 @@ struct BucketIterator<'a> {
 }
 
 impl<'a> Iterator for BucketIterator<'a> {
-    type Item = &'a Vec<(u32, PRef)>;
+    type Item = Result<Vec<(u64, PRef)>, HammersbaldError>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         if let Some(bucket) = self.file.buckets.get(self.n as usize) {
+            let n = self.n as usize;
             self.n += 1;
-            return Some(&bucket.slots);
+            if bucket.unread {
+                return Some(self.file.read_bucket(n));
+            }
+            return Some(Ok(bucket.slots.clone()));
         }
         None
     }
@@ -475,7 +1412,9 @@ impl<'b> Iterator for DirtyIterator<'b> {
 
 #[derive(Clone, Default)]
 pub struct Bucket {
-    slots: Vec<(u32, PRef)>
+    slots: Vec<(u64, PRef)>,
+    // slots are not yet read from disk
+    unread: bool
 }
 
 #[cfg(test)]
@@ -488,8 +1427,254 @@ mod test {
 
     use super::*;
     use self::rand::thread_rng;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use self::rand::RngCore;
+    use byteorder::{ByteOrder, BigEndian};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn transient_memtable (options: &Options) -> MemTable {
+        MemTable::new(LogFile::new(Box::new(Transient::new(true))),
+                      TableFile::new(Box::new(Transient::new(false))).unwrap(),
+                      DataFile::new(Box::new(Transient::new(true))).unwrap(),
+                      DataFile::new(Box::new(Transient::new(true))).unwrap(), options)
+    }
+
+    fn hash_collisions (mem: &MemTable, n: u64) -> u64 {
+        let mut seen = HashSet::new();
+        let mut key = [0u8; 8];
+        let mut collisions = 0;
+        for i in 0 .. n {
+            BigEndian::write_u64(&mut key, i);
+            if !seen.insert(mem.hash(&key)) {
+                collisions += 1;
+            }
+        }
+        collisions
+    }
+
+    #[test]
+    fn test_wide_hash_collisions() {
+        let narrow = transient_memtable(&Options::default());
+        let wide = transient_memtable(&Options { wide_hash: true, .. Options::default() });
+        // about 29 collisions are expected with 32 bits and none with 64 bits
+        assert!(hash_collisions(&narrow, 500000) > 0);
+        assert_eq!(hash_collisions(&wide, 500000), 0);
+    }
+
+    // two keys with the same 32 bit hash
+    fn colliding_keys (mem: &MemTable) -> ([u8; 8], [u8; 8]) {
+        let mut seen = HashMap::new();
+        let mut first = [0u8; 8];
+        let mut second = [0u8; 8];
+        for i in 0 .. 1u64 << 24 {
+            BigEndian::write_u64(&mut second, i);
+            if let Some(j) = seen.insert(mem.hash(&second), i) {
+                BigEndian::write_u64(&mut first, j);
+                break;
+            }
+        }
+        assert_ne!(first, second);
+        assert_eq!(mem.hash(&first), mem.hash(&second));
+        (first, second)
+    }
+
+    #[test]
+    fn test_get_compares_keys() {
+        let mut mem = transient_memtable(&Options::new(1, 1));
+        mem.init().unwrap();
+        let (first, second) = colliding_keys(&mem);
+
+        let first_pref = mem.append_data(&first, &[1], &vec!(), 0).unwrap();
+        mem.put(&first, first_pref).unwrap();
+        let second_pref = mem.append_data(&second, &[2], &vec!(), 0).unwrap();
+        mem.put(&second, second_pref).unwrap();
+        mem.batch().unwrap();
+        assert_eq!(mem.get(&first).unwrap().unwrap(), (first_pref, vec!(1), vec!()));
+        assert_eq!(mem.get(&second).unwrap().unwrap(), (second_pref, vec!(2), vec!()));
+
+        // overwriting one must not remove the other
+        let first_pref = mem.append_data(&first, &[3], &vec!(), 0).unwrap();
+        mem.put(&first, first_pref).unwrap();
+        assert_eq!(mem.get(&first).unwrap().unwrap(), (first_pref, vec!(3), vec!()));
+        assert_eq!(mem.get(&second).unwrap().unwrap(), (second_pref, vec!(2), vec!()));
+
+        // a stale slot of the key before the current one is not found
+        let stale = mem.append_data(&first, &[4], &vec!(), 0).unwrap();
+        let first_pref = mem.append_data(&first, &[5], &vec!(), 0).unwrap();
+        mem.put(&first, first_pref).unwrap();
+        let hash = mem.hash(&first);
+        let bucket = mem.bucket_for_hash(hash);
+        mem.buckets[bucket].slots.insert(0, (hash, stale));
+        assert_eq!(mem.get(&first).unwrap().unwrap(), (first_pref, vec!(5), vec!()));
+        assert_eq!(mem.pref_of(&first).unwrap(), Some(first_pref));
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_validate_after_recovery() {
+        let mut mem = transient_memtable(&Options::new(1, 1));
+        mem.init().unwrap();
+        for i in 0 .. 100u64 {
+            let mut key = [0u8; 8];
+            BigEndian::write_u64(&mut key, i);
+            let pref = mem.append_data(&key, &[1], &vec!(), 0).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+        mem.recover().unwrap();
+
+        // a log that truncates the link file too much
+        let data_len = mem.data_file.len().unwrap();
+        let table_len = mem.table_file.len().unwrap();
+        mem.log_file.init(data_len, table_len, 0).unwrap();
+        assert!(mem.recover().is_err());
+        assert!(mem.validate_after_recovery().is_err());
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_replay_out_of_range() {
+        let mut mem = transient_memtable(&Options::new(1, 1));
+        mem.init().unwrap();
+        for i in 0 .. 100u64 {
+            let mut key = [0u8; 8];
+            BigEndian::write_u64(&mut key, i);
+            let pref = mem.append_data(&key, &[1], &vec!(), 0).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+        let table_len = mem.table_file.len().unwrap();
+
+        // a log with a table page beyond the table
+        mem.log_file.append_page(Page::new(PRef::from(table_len))).unwrap();
+        mem.log_file.flush().unwrap();
+        assert!(mem.recover().is_err());
+        assert_eq!(mem.table_file.len().unwrap(), table_len);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_trust_hash() {
+        let mut mem = transient_memtable(&Options { trust_hash: true, .. Options::new(1, 1) });
+        mem.init().unwrap();
+        let (first, second) = colliding_keys(&mem);
+        let first_pref = mem.append_data(&first, &[1], &vec!(), 0).unwrap();
+        mem.put(&first, first_pref).unwrap();
+        mem.batch().unwrap();
+        assert_eq!(mem.get(&first).unwrap().unwrap(), (first_pref, vec!(1), vec!()));
+        assert_eq!(mem.pref_of(&first).unwrap(), Some(first_pref));
+        // the key is not confirmed
+        assert_eq!(mem.get(&second).unwrap().unwrap(), (first_pref, vec!(1), vec!()));
+        assert_eq!(mem.pref_of(&second).unwrap(), Some(first_pref));
+        assert_eq!(mem.pref_of(&[1]).unwrap(), None);
+        mem.shutdown();
+    }
+
+    // counts pages read
+    struct CountingFile {
+        file: Transient,
+        reads: Arc<AtomicUsize>
+    }
+
+    impl PagedFile for CountingFile {
+        fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.file.read_page(pref)
+        }
+        fn len(&self) -> Result<u64, HammersbaldError> { self.file.len() }
+        fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> { self.file.truncate(new_len) }
+        fn sync(&self) -> Result<(), HammersbaldError> { self.file.sync() }
+        fn shutdown(&mut self) { self.file.shutdown() }
+        fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> { self.file.append_page(page) }
+        fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> { self.file.update_page(page) }
+        fn flush(&mut self) -> Result<(), HammersbaldError> { self.file.flush() }
+        fn queue_len(&self) -> usize { self.file.queue_len() }
+    }
+
+    #[test]
+    fn test_scan_by_header() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let options = Options { header_bytes: 1, .. Options::new(1, 2) };
+        let mut mem = MemTable::new(LogFile::new(Box::new(Transient::new(true))),
+                      TableFile::new(Box::new(Transient::new(false))).unwrap(),
+                      DataFile::new(Box::new(CountingFile { file: Transient::new(true), reads: reads.clone() })).unwrap(),
+                      DataFile::new(Box::new(Transient::new(true))).unwrap(), &options);
+        mem.init().unwrap();
+        let mut check = HashSet::new();
+        for i in 0 .. 1000u64 {
+            let mut key = [0u8; 8];
+            BigEndian::write_u64(&mut key, i);
+            // entries are larger than a page, so matches read pages of their own
+            let mut data = vec!(0u8; PAGE_SIZE);
+            data[0] = (i % 10) as u8;
+            let pref = mem.append_data(&key, &data, &vec!(), 0).unwrap();
+            mem.put(&key, pref).unwrap();
+            if i % 10 == 3 {
+                check.insert((key.to_vec(), pref));
+            }
+        }
+        mem.batch().unwrap();
+        for reload in 0 .. 2 {
+            if reload == 1 {
+                mem.load().unwrap();
+            }
+            reads.store(0, Ordering::SeqCst);
+            let found = mem.scan_by_header(|header| header[0] == 3).collect::<Result<HashSet<_>, _>>().unwrap();
+            assert_eq!(found, check);
+            // an entry spans at most three pages, all entries span 3000
+            assert!(reads.load(Ordering::SeqCst) <= 3 * check.len());
+        }
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_load_threads() {
+        let mut mem = transient_memtable(&Options::new(1, 2));
+        mem.init().unwrap();
+        for i in 0 .. 10000u64 {
+            let mut key = [0u8; 8];
+            BigEndian::write_u64(&mut key, i % 7000);
+            let pref = mem.append_data(&key, &[1], &vec!(), 0).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+        mem.load().unwrap();
+        let scanned = mem.slots().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(scanned.len() > 1000);
+        for threads in 2 .. 5 {
+            mem.load_threads = threads;
+            mem.load().unwrap();
+            assert_eq!(mem.slots().collect::<Result<Vec<_>, _>>().unwrap(), scanned);
+        }
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_wide_hash() {
+        let mut db = Transient::new_db_with_options("first", Options { wide_hash: true, .. Options::new(1, 1) }).unwrap();
+        db.init().unwrap();
+        assert!(db.is_wide_hash());
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        let mut check = HashMap::new();
+
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let o = db.put(&key, &data, &vec!()).unwrap();
+            check.insert(key, (o, data.to_vec()));
+        }
+        db.batch().unwrap();
+
+        assert!(db.slots().any(|slots| slots.unwrap().iter().any(|s| s.0 > 0xffffffff)));
+        for (k, (o, data)) in check {
+            assert_eq!(db.get(&k[..]).unwrap().unwrap(), (o, data, vec!()));
+        }
+        db.shutdown();
+    }
 
     #[test]
     fn test_dirty() {
@@ -530,5 +1715,33 @@ mod test {
         }
         db.shutdown();
     }
-}
 
+    #[test]
+    fn test_repair_dangling() {
+        let mut mem = transient_memtable(&Options::new(1, 1));
+        mem.init().unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 100u8 {
+            let pref = mem.append_data(&[i], &[i; 100], &vec!(), 0).unwrap();
+            mem.put(&[i], pref).unwrap();
+            check.insert(i, pref);
+        }
+        let referred = mem.append_referred(&[1, 2, 3], &vec!()).unwrap();
+        mem.batch().unwrap();
+
+        let mut dangling = PRef::from(1 << 40);
+        mem.buckets[0].slots.push((0, dangling));
+        mem.buckets[1].slots.push((1, referred));
+        dangling = check[&7] + 3;
+        mem.buckets[2].slots.push((2, dangling));
+        assert_eq!(mem.repair_dangling().unwrap(), 3);
+        assert_eq!(mem.repair_dangling().unwrap(), 0);
+        mem.batch().unwrap();
+        for (i, pref) in &check {
+            assert_eq!(mem.get(&[*i]).unwrap().unwrap(), (*pref, vec!(*i; 100), vec!()));
+        }
+        mem.load().unwrap();
+        assert_eq!(mem.slots().map(|slots| slots.unwrap().len()).sum::<usize>(), 100);
+        mem.shutdown();
+    }
+}
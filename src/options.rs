@@ -0,0 +1,165 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Options of a db
+//!
+
+#[cfg(feature="crash-test")]
+use crash::CrashPoint;
+use pref::PRef;
+use error::HammersbaldError;
+use format::BucketCodec;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// a function applied to keys before they are hashed or stored
+pub type KeyTransform = Arc<Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// a function called with the pref of a page leaving a page cache
+pub type EvictCallback = Arc<Fn(PRef) + Send + Sync>;
+
+/// a function returning the bytes free for the db on disk
+pub type FreeSpace = Arc<Fn() -> Result<u64, HammersbaldError> + Send + Sync>;
+
+/// options to create or open a db
+#[derive(Clone)]
+pub struct Options {
+    /// number of pages cached per file
+    pub cached_data_pages: usize,
+    /// a bucket is split on average after this many inserts (1 - 128), recorded in the db at batch
+    /// 0 uses the value recorded in an existing db, 64 for a new one. Another value replaces it.
+    /// Versions that do not record it drop the recorded value at their next batch
+    pub bucket_fill_target: usize,
+    /// use 64 bit hashes in the hash table instead of 32 bits
+    /// only applies to a new db, an existing db keeps the width it was created with
+    pub wide_hash: bool,
+    /// store keys with the data, if false only the hash of the key is stored
+    /// and a get returns the data of any key with the same hash
+    pub store_keys: bool,
+    /// do not cache pages, rely on the cache of the operating system
+    pub no_cache: bool,
+    /// read this many pages at once into the cache when pages are read in sequence, e.g. by scans
+    /// at most cached_data_pages, 0 or 1 reads page by page
+    pub readahead_pages: usize,
+    /// if the hash table of an opened db is estimated to need more bytes than this,
+    /// buckets are read from disk when first used instead of all at open, 0 for no limit
+    pub recovery_memory_limit: usize,
+    /// sync the directory after a new chunk file is created, so it survives a crash
+    pub sync_directory: bool,
+    /// split buckets at open until the table matches the fill target, useful after lowering it
+    /// the splits are otherwise spread over later inserts, not done if buckets are read lazily
+    pub resplit_on_load: bool,
+    /// retry a batch failing with an IO error this many times, after a short wait
+    /// the files are recovered to the state before the batch at the next open if all fail
+    pub batch_retries: u32,
+    /// number of threads reading the hash table at open, 0 or 1 scans the link file in one thread
+    pub load_threads: usize,
+    /// do not compare keys of data found by the hash of a key, only for keys that never collide,
+    /// e.g. cryptographic hashes with wide_hash. Otherwise get may return data of another key
+    /// and pref_of a pref of another key. pref_of then does not read data at all
+    pub trust_hash: bool,
+    /// all data is of this size and refers to no other data, saves the 3 byte length of each entry
+    /// other data is rejected. must be the same whenever the db is opened
+    pub fixed_value_size: Option<usize>,
+    /// store this many bytes of the beginning of indexed data with the hash table (at most 255),
+    /// so scan_by_header filters without reading data. The headers are also kept in memory,
+    /// a copy per key not bounded otherwise, that is header_bytes and about 50 bytes per key
+    pub header_bytes: usize,
+    /// store referred prefs as varint distances back from the referring data, saves space if
+    /// data refers to data stored shortly before. A db so written can not be read by earlier versions
+    pub referred_deltas: bool,
+    /// reserve this many bytes (at most 64) at the end of each data page for the application,
+    /// see write_app_trailer. Must be the same whenever the db is opened
+    pub app_trailer_bytes: usize,
+    /// encode the slots of buckets stored in links with this codec instead of the standard format
+    /// the same codec must be used whenever the db is opened. Links so stored have no headers,
+    /// scan_by_header then reads headers from the data
+    pub bucket_codec: Option<Arc<BucketCodec>>,
+    /// a persistent db stores data, links, table and log in the single file name.hb,
+    /// in regions of 1 MiB. Must be the same whenever the db is opened. For small dbs, at most
+    /// 1357 regions. Functions that work on the chunk files of a db, e.g. publish, do not apply
+    pub single_file: bool,
+    /// store the unix time in milliseconds with each indexed data, see get_with_time
+    /// costs 9 bytes per data, data stored without it has no time
+    pub record_timestamps: bool,
+    /// recorded in a new db, opening a db recorded with another namespace fails with BadMagic
+    /// keeps dbs of different applications in a shared directory apart, dbs without one have 0, 0
+    pub magic_namespace: [u8; 2],
+    /// applied to keys of put and get before they are hashed and stored, e.g. to ignore case
+    /// the transformed key is stored, changing the transform invalidates an existing db
+    pub key_transform: Option<KeyTransform>,
+    /// a transient db keeps at most this many bytes of data and link pages in memory each,
+    /// older pages are moved to a temporary file, 0 keeps all in memory
+    pub spill_threshold_bytes: usize,
+    /// directory of temporary files, e.g. spill files or visited sets of dag_disk_visited
+    /// the directory of a persistent db if None, the temporary directory of the system otherwise
+    pub scratch_dir: Option<PathBuf>,
+    /// called whenever a page leaves the cache of a data, link or table file
+    /// it is called while the cache is locked and must not access the db
+    pub on_evict: Option<EvictCallback>,
+    /// writes fail with DiskNearlyFull if fewer bytes are free on disk, reads are still possible
+    /// free_space is asked before each batch and after each MiB of data stored, puts in between
+    /// are checked against the free bytes estimated from the data stored since
+    pub min_free_bytes: Option<u64>,
+    /// tells the free bytes for min_free_bytes, a persistent db asks the file system of the db if none
+    pub free_space: Option<FreeSpace>,
+    /// consulted at crash points of batch and recovery
+    #[cfg(feature="crash-test")]
+    pub crash_point: Option<Arc<CrashPoint>>
+}
+
+impl Options {
+    /// options with the given cache size and fill target, defaults otherwise
+    pub fn new (cached_data_pages: usize, bucket_fill_target: usize) -> Options {
+        Options { cached_data_pages, bucket_fill_target, .. Options::default() }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cached_data_pages: 100,
+            bucket_fill_target: 64,
+            wide_hash: false,
+            store_keys: true,
+            no_cache: false,
+            readahead_pages: 0,
+            recovery_memory_limit: 0,
+            sync_directory: false,
+            resplit_on_load: false,
+            load_threads: 0,
+            batch_retries: 0,
+            trust_hash: false,
+            fixed_value_size: None,
+            header_bytes: 0,
+            bucket_codec: None,
+            single_file: false,
+            app_trailer_bytes: 0,
+            referred_deltas: false,
+            record_timestamps: false,
+            magic_namespace: [0, 0],
+            key_transform: None,
+            spill_threshold_bytes: 0,
+            scratch_dir: None,
+            on_evict: None,
+            min_free_bytes: None,
+            free_space: None,
+            #[cfg(feature="crash-test")]
+            crash_point: None
+        }
+    }
+}
@@ -21,6 +21,7 @@
 //!
 
 use pref::PRef;
+use error::HammersbaldError;
 use byteorder::{ByteOrder, BigEndian};
 
 pub const PAGE_SIZE: usize = 4096;
@@ -73,20 +74,85 @@ impl Page {
         PRef::from(BigEndian::read_u48(&self.content[pos..pos+6]))
     }
 
-    /// write an pref into the page
-    pub fn write_u64 (&mut self, pos: usize, n: u64) {
-        let mut buf = [0u8; 8];
-        BigEndian::write_u64(&mut buf, n);
-        self.content[pos..pos+8].copy_from_slice(&buf[..]);
+    /// write a 32 bit number into the page
+    pub fn write_u32 (&mut self, pos: usize, n: u32) -> Result<(), HammersbaldError> {
+        Self::check_bounds(pos, 4)?;
+        BigEndian::write_u32(&mut self.content[pos..pos+4], n);
+        Ok(())
     }
 
-    /// read an pref at a page position
-    pub fn read_u64(&self, pos: usize) -> u64 {
-        BigEndian::read_u64(&self.content[pos..pos+8])
+    /// read a 32 bit number at a page position
+    pub fn read_u32(&self, pos: usize) -> Result<u32, HammersbaldError> {
+        Self::check_bounds(pos, 4)?;
+        Ok(BigEndian::read_u32(&self.content[pos..pos+4]))
+    }
+
+    /// write a 48 bit number into the page
+    pub fn write_u48 (&mut self, pos: usize, n: u64) -> Result<(), HammersbaldError> {
+        Self::check_bounds(pos, 6)?;
+        BigEndian::write_u48(&mut self.content[pos..pos+6], n);
+        Ok(())
+    }
+
+    /// read a 48 bit number at a page position
+    pub fn read_u48(&self, pos: usize) -> Result<u64, HammersbaldError> {
+        Self::check_bounds(pos, 6)?;
+        Ok(BigEndian::read_u48(&self.content[pos..pos+6]))
+    }
+
+    /// write a 64 bit number into the page
+    pub fn write_u64 (&mut self, pos: usize, n: u64) -> Result<(), HammersbaldError> {
+        Self::check_bounds(pos, 8)?;
+        BigEndian::write_u64(&mut self.content[pos..pos+8], n);
+        Ok(())
+    }
+
+    /// read a 64 bit number at a page position
+    pub fn read_u64(&self, pos: usize) -> Result<u64, HammersbaldError> {
+        Self::check_bounds(pos, 8)?;
+        Ok(BigEndian::read_u64(&self.content[pos..pos+8]))
+    }
+
+    // numbers must not overlap the pref of the page
+    fn check_bounds (pos: usize, len: usize) -> Result<(), HammersbaldError> {
+        if pos + len > PAGE_PAYLOAD_SIZE {
+            return Err(HammersbaldError::Corrupted(format!("page access out of bounds {} + {}", pos, len)));
+        }
+        Ok(())
     }
 
     /// into write buffer
     pub fn into_buf (self) -> [u8; PAGE_SIZE] {
         self.content
     }
+
+    /// the content of the page
+    pub fn as_slice (&self) -> &[u8] {
+        &self.content[..]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_typed_accessors () {
+        let mut page = Page::new(PRef::from(PAGE_SIZE as u64));
+        page.write_u32(0, 0x01020304).unwrap();
+        page.write_u48(4, 0x0102030405).unwrap();
+        page.write_u64(10, 0x0102030405060708).unwrap();
+        page.write_u64(PAGE_PAYLOAD_SIZE - 8, !0u64).unwrap();
+        assert_eq!(page.read_u32(0).unwrap(), 0x01020304);
+        assert_eq!(page.read_u48(4).unwrap(), 0x0102030405);
+        assert_eq!(page.read_u64(10).unwrap(), 0x0102030405060708);
+        assert_eq!(page.read_u64(PAGE_PAYLOAD_SIZE - 8).unwrap(), !0u64);
+        assert_eq!(page.pref(), PRef::from(PAGE_SIZE as u64));
+
+        assert!(page.write_u32(PAGE_PAYLOAD_SIZE - 3, 0).is_err());
+        assert!(page.read_u48(PAGE_PAYLOAD_SIZE - 5).is_err());
+        assert!(page.write_u64(PAGE_PAYLOAD_SIZE, 0).is_err());
+        assert!(page.read_u64(PAGE_SIZE).is_err());
+        assert_eq!(page.pref(), PRef::from(PAGE_SIZE as u64));
+    }
 }
@@ -22,8 +22,10 @@ use error::HammersbaldError;
 use pref::PRef;
 
 use std::cmp::min;
+use std::sync::Arc;
 
 /// a paged file
+/// reads take &self and may run concurrently, implementations lock state they change on read
 pub trait PagedFile : Send + Sync {
     /// read a page at pref
     fn read_page (&self, pref: PRef) -> Result<Option<Page>, HammersbaldError>;
@@ -41,6 +43,31 @@ pub trait PagedFile : Send + Sync {
     fn update_page (&mut self, page: Page) -> Result<u64, HammersbaldError>;
     /// flush buffered writes
     fn flush(&mut self) -> Result<(), HammersbaldError>;
+    /// number of pages waiting to be written in background
+    fn queue_len(&self) -> usize;
+    /// change the number of pages cached, if the file is cached
+    fn resize_cache(&mut self, _pages: usize) {}
+    /// read up to n consecutive pages starting at pref, fewer at the end of the storage
+    fn read_pages (&self, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError> {
+        read_pages_with(|pref| self.read_page(pref), pref, n)
+    }
+    /// read a page at pref, a cached page is shared instead of copied
+    fn read_page_shared (&self, pref: PRef) -> Result<Option<Arc<Page>>, HammersbaldError> {
+        Ok(self.read_page(pref)?.map(Arc::new))
+    }
+}
+
+/// read up to n consecutive pages starting at pref with read, one by one
+pub fn read_pages_with<F> (read: F, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError>
+    where F: Fn(PRef) -> Result<Option<Page>, HammersbaldError> {
+    let mut pages = Vec::new();
+    for i in 0 .. n as u64 {
+        match read(pref + i * PAGE_SIZE as u64)? {
+            Some(page) => pages.push(page),
+            None => break
+        }
+    }
+    Ok(pages)
 }
 
 pub trait PagedFileRead {
@@ -53,18 +80,56 @@ pub trait PagedFileWrite {
     fn append(&mut self, buf: &[u8]) -> Result<PRef, HammersbaldError>;
 }
 
+/// where an appender was, to rewind it to
+pub struct AppendMark {
+    pos: PRef,
+    lep: PRef,
+    page: Option<Page>
+}
+
 /// a reader for a paged file
 pub struct PagedFileAppender {
     file: Box<PagedFile>,
     pos: PRef,
     page: Option<Page>,
-    lep: PRef
+    lep: PRef,
+    // bytes of a page used for data, app trailer bytes follow
+    payload_size: usize,
+    app_trailer: Vec<u8>
 }
 
 impl PagedFileAppender {
     /// create a reader that starts at a position
     pub fn new (file: Box<PagedFile>, pos: PRef, lep: PRef) -> PagedFileAppender {
-        PagedFileAppender {file, pos, page: None, lep}
+        PagedFileAppender {file, pos, page: None, lep, payload_size: PAGE_PAYLOAD_SIZE, app_trailer: Vec::new()}
+    }
+
+    /// reserve bytes at the end of the payload of each page for the application
+    pub fn set_app_trailer_bytes (&mut self, bytes: usize) {
+        self.payload_size = PAGE_PAYLOAD_SIZE - bytes;
+        self.app_trailer = vec!(0u8; bytes);
+    }
+
+    /// bytes of a page used for data
+    pub fn payload_size (&self) -> usize {
+        self.payload_size
+    }
+
+    /// set the app trailer of pages written from now, shorter trailers are padded with zeros
+    pub fn set_app_trailer (&mut self, trailer: &[u8]) {
+        let len = min(trailer.len(), self.app_trailer.len());
+        self.app_trailer = trailer[..len].to_vec();
+        self.app_trailer.resize(PAGE_PAYLOAD_SIZE - self.payload_size, 0);
+    }
+
+    /// read the app trailer of the page of pref
+    pub fn read_app_trailer (&self, pref: PRef) -> Result<Option<Vec<u8>>, HammersbaldError> {
+        if let Some(page) = self.read_page(pref.this_page())? {
+            let mut trailer = vec!(0u8; self.app_trailer.len());
+            page.read(self.payload_size, &mut trailer);
+            return Ok(Some(trailer));
+        }
+        Ok(None)
     }
 
     pub fn position (&self) -> PRef {
@@ -79,6 +144,21 @@ impl PagedFileAppender {
         self.lep = self.pos;
     }
 
+    /// remember the current position, see rewind
+    pub fn mark (&self) -> AppendMark {
+        AppendMark { pos: self.pos, lep: self.lep, page: self.page.clone() }
+    }
+
+    /// remove what was appended since the mark, pages written since are truncated
+    /// the page the mark is in was not yet written, unless the mark is at a page boundary
+    pub fn rewind (&mut self, mark: AppendMark) -> Result<(), HammersbaldError> {
+        self.file.truncate(mark.pos.this_page().as_u64())?;
+        self.pos = mark.pos;
+        self.lep = mark.lep;
+        self.page = mark.page;
+        Ok(())
+    }
+
     pub fn append(&mut self, buf: &[u8]) -> Result<PRef, HammersbaldError> {
         let mut wrote = 0;
         while wrote < buf.len() {
@@ -86,14 +166,15 @@ impl PagedFileAppender {
                 self.page = Some(Page::new(self.lep));
             }
             if let Some(ref mut page) = self.page {
-                let space = min(PAGE_PAYLOAD_SIZE - self.pos.in_page_pos(), buf.len() - wrote);
+                let space = min(self.payload_size - self.pos.in_page_pos(), buf.len() - wrote);
                 page.write(self.pos.in_page_pos(), &buf[wrote..wrote + space]);
                 wrote += space;
                 self.pos += space as u64;
-                if self.pos.in_page_pos() == PAGE_PAYLOAD_SIZE {
+                if self.pos.in_page_pos() == self.payload_size {
+                    page.write(self.payload_size, &self.app_trailer);
                     page.write_pref(PAGE_PAYLOAD_SIZE, self.lep);
                     self.file.append_page(page.clone())?;
-                    self.pos += (PAGE_SIZE - PAGE_PAYLOAD_SIZE) as u64;
+                    self.pos += (PAGE_SIZE - self.payload_size) as u64;
                 }
             }
             if self.pos.in_page_pos() == 0 {
@@ -107,12 +188,12 @@ impl PagedFileAppender {
         let mut read = 0;
         while read < buf.len() {
             if let Some(ref page) = self.read_page(pos.this_page())? {
-                let have = min(PAGE_PAYLOAD_SIZE - pos.in_page_pos(), buf.len() - read);
+                let have = min(self.payload_size - pos.in_page_pos(), buf.len() - read);
                 page.read(pos.in_page_pos(), &mut buf[read .. read + have]);
                 read += have;
                 pos += have as u64;
-                if pos.in_page_pos() == PAGE_PAYLOAD_SIZE {
-                    pos += (PAGE_SIZE - PAGE_PAYLOAD_SIZE) as u64;
+                if pos.in_page_pos() == self.payload_size {
+                    pos += (PAGE_SIZE - self.payload_size) as u64;
                 }
             }
             else {
@@ -133,6 +214,15 @@ impl PagedFile for PagedFileAppender {
         return self.file.read_page(pref)
     }
 
+    fn read_page_shared(&self, pref: PRef) -> Result<Option<Arc<Page>>, HammersbaldError> {
+        if let Some(ref page) = self.page {
+            if self.pos.this_page() == pref {
+                return Ok(Some(Arc::new(page.clone())))
+            }
+        }
+        self.file.read_page_shared(pref)
+    }
+
     fn len(&self) -> Result<u64, HammersbaldError> {
         self.file.len()
     }
@@ -172,13 +262,24 @@ impl PagedFile for PagedFileAppender {
     fn flush(&mut self) -> Result<(), HammersbaldError> {
         if let Some(ref mut page) = self.page {
             if self.pos.in_page_pos() > 0 {
+                page.write(self.payload_size, &self.app_trailer);
                 page.write_pref(PAGE_PAYLOAD_SIZE, self.lep);
                 self.file.append_page(page.clone())?;
                 self.pos += PAGE_SIZE as u64 - self.pos.in_page_pos() as u64;
             }
         }
+        // the next append starts a new page, not the one written
+        self.page = None;
         Ok(self.file.flush()?)
     }
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
+
+    fn resize_cache(&mut self, pages: usize) {
+        self.file.resize_cache(pages)
+    }
 }
 
 /// iterate through pages of a paged file
@@ -211,3 +312,62 @@ impl<'file> Iterator for PagedFileIterator<'file> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+
+    use super::*;
+
+    fn pages_written (appender: &PagedFileAppender) -> u64 {
+        appender.file.len().unwrap() / PAGE_SIZE as u64
+    }
+
+    #[test]
+    fn test_small_appends () {
+        let mut appender = PagedFileAppender::new(Box::new(Transient::new(true)), PRef::from(0), PRef::invalid());
+        // small appends fitting a page are written with the page
+        for _ in 0 .. 400 {
+            appender.append(&[1; 10]).unwrap();
+        }
+        assert_eq!(pages_written(&appender), 0);
+        appender.append(&[1; 100]).unwrap();
+        assert_eq!(pages_written(&appender), 1);
+        appender.flush().unwrap();
+        assert_eq!(pages_written(&appender), 2);
+
+        // a page started after a flush does not carry bytes of the page flushed before
+        appender.append(&[2; 100]).unwrap();
+        appender.flush().unwrap();
+        appender.append(&[3; 10]).unwrap();
+        appender.flush().unwrap();
+        assert_eq!(pages_written(&appender), 4);
+        let mut buf = [0u8; 100];
+        appender.read(PRef::from(3 * PAGE_SIZE as u64), &mut buf).unwrap();
+        assert_eq!(&buf[.. 10], &[3; 10][..]);
+        assert_eq!(&buf[10 ..], &[0; 90][..]);
+    }
+
+    #[test]
+    fn test_rewind () {
+        let mut appender = PagedFileAppender::new(Box::new(Transient::new(true)), PRef::from(0), PRef::invalid());
+        appender.append(&[1; 100]).unwrap();
+        let mark = appender.mark();
+        appender.advance();
+        appender.append(&[2; 3 * PAGE_SIZE]).unwrap();
+        assert_eq!(pages_written(&appender), 3);
+        appender.rewind(mark).unwrap();
+        assert_eq!(pages_written(&appender), 0);
+        assert_eq!(appender.position(), PRef::from(100));
+        assert_eq!(appender.lep(), PRef::invalid());
+
+        // bytes before the mark are kept
+        appender.append(&[3; 10]).unwrap();
+        appender.flush().unwrap();
+        let mut buf = [0u8; 111];
+        appender.read(PRef::from(0), &mut buf).unwrap();
+        assert_eq!(&buf[.. 100], &[1; 100][..]);
+        assert_eq!(&buf[100 .. 110], &[3; 10][..]);
+        assert_eq!(buf[110], 0);
+    }
+}
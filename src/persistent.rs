@@ -18,21 +18,46 @@
 //!
 //! Implements persistent store
 
-use api::{Hammersbald, HammersbaldFactory};
+use api::{Hammersbald, HammersbaldAPI, HammersbaldFactory, RepairReport, RecoveryPlan};
 use asyncfile::AsyncFile;
 use cachedfile::CachedFile;
 use datafile::DataFile;
+use dataonly::DataOnlyDb;
 use error::HammersbaldError;
+use format::Payload;
+use frozen::FrozenDb;
+use options::Options;
 use logfile::LogFile;
+use memtable::MemTable;
 use pref::PRef;
-use page::Page;
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
 use pagedfile::PagedFile;
+use readonlyfile::ReadOnlyFile;
 use rolledfile::RolledFile;
+use stripedfile::StripedFile;
 use tablefile::TableFile;
 
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::cmp::min;
+
 const TABLE_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
 const DATA_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
 const LOG_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+// attempts to open a snapshot while the log changes
+const SNAPSHOT_TRIES: usize = 100;
+// wait before the next try to open a snapshot, doubled after each try up to 64 times this
+const SNAPSHOT_RETRY_DELAY_MS: u64 = 1;
+
+// data, link, log and table, in the order they are published, then the single file
+const FILE_EXTENSIONS: [&str; 5] = ["bc", "bl", "lg", "tb", SINGLE_FILE_EXTENSION];
+// a db with option single_file stores all in name.hb
+const SINGLE_FILE_EXTENSION: &str = "hb";
 
 /// Implements persistent storage
 pub struct Persistent {
@@ -44,31 +69,454 @@ impl Persistent {
     pub fn new(file: RolledFile) -> Persistent {
         Persistent { file }
     }
-}
 
-impl HammersbaldFactory for Persistent {
-    fn new_db(name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Hammersbald, HammersbaldError> {
+    /// open an existing DB on a read-only file system, e.g. a snapshot
+    /// options that must be the same whenever the db is opened must match those it was written with
+    pub fn open_read_only(name: &str, options: Options) -> Result<Hammersbald, HammersbaldError> {
+        let options = Self::with_name_defaults(name, options);
+        let (log, table, data, link) = Self::read_only_files(name, &options)?;
+        Hammersbald::new_read_only(log, table, data, link, &options)
+    }
+
+    /// what recovery at the next open of a DB would do, the files are not changed
+    /// lengths are of whole pages, a torn page would also be cut, see cut_torn_pages
+    pub fn dry_run_recovery(name: &str) -> Result<RecoveryPlan, HammersbaldError> {
+        let (log, table, data, link) = Self::read_only_files(name, &Options::new(1, 0))?;
+        let mut mem = MemTable::new(log, table, data, link, &Options::default());
+        let plan = mem.recovery_plan();
+        mem.shutdown();
+        plan
+    }
+
+    // the layout is that of the files found, a single file db is opened without option single_file too
+    fn read_only_files(name: &str, options: &Options) -> Result<(LogFile, TableFile, DataFile, DataFile), HammersbaldError> {
+        let (data, link, log, table): (Box<PagedFile>, Box<PagedFile>, Box<PagedFile>, Box<PagedFile>) =
+            if Self::is_single_file(name) {
+                let mut files = Self::read_only_single_file(name)?.into_iter();
+                let mut next = || -> Box<PagedFile> { Box::new(files.next().unwrap()) };
+                (next(), next(), next(), next())
+            } else {
+                (Box::new(RolledFile::new_read_only(name, "bc", DATA_CHUNK_SIZE)?),
+                 Box::new(RolledFile::new_read_only(name, "bl", DATA_CHUNK_SIZE)?),
+                 Box::new(RolledFile::new_read_only(name, "lg", LOG_CHUNK_SIZE)?),
+                 Box::new(RolledFile::new_read_only(name, "tb", TABLE_CHUNK_SIZE)?))
+            };
+
+        let data = DataFile::new(
+            CachedFile::with_options(
+                Box::new(ReadOnlyFile::new(data)?), options)?)?;
+
+        let link = DataFile::new(
+            CachedFile::with_options(
+                Box::new(ReadOnlyFile::new(link)?), options)?)?;
+
+        let log = LogFile::new(
+            Box::new(ReadOnlyFile::new(log)?));
+
+        let table = TableFile::new(
+            CachedFile::with_options(
+                Box::new(ReadOnlyFile::new(table)?), options)?)?;
+
+        Ok((log, table, data, link))
+    }
+
+    // data, link, log and table striped in the single file
+    fn read_only_single_file(name: &str) -> Result<Vec<StripedFile>, HammersbaldError> {
+        StripedFile::open_read_only(&Self::single_file_name(name), 4)
+    }
+
+    fn single_file_name(name: &str) -> String {
+        format!("{}.{}", name, SINGLE_FILE_EXTENSION)
+    }
+
+    fn is_single_file(name: &str) -> bool {
+        Path::new(&Self::single_file_name(name)).exists()
+    }
+
+    /// open only the data file of a DB whose table or link files are lost, read-only
+    /// lookups scan the data, data of a batch not ended before a crash may be found too
+    /// a torn last page is not read, the file is not changed
+    pub fn open_data_only(name: &str, options: Options) -> Result<DataOnlyDb, HammersbaldError> {
+        let data: Box<PagedFile> = if Self::is_single_file(name) {
+            Box::new(Self::read_only_single_file(name)?.remove(0))
+        } else {
+            Box::new(RolledFile::new_read_only(name, "bc", DATA_CHUNK_SIZE)?)
+        };
+        let mut file = ReadOnlyFile::new(data)?;
+        Self::cut_torn_page(&mut file, true)?;
+        let data = DataFile::new(CachedFile::with_options(Box::new(file), &options)?)?;
+        Ok(DataOnlyDb::new(data, &options))
+    }
+
+    /// open a read-only view of the last batch of a DB that may be written by another handle,
+    /// e.g. to monitor an import. The view does not follow later batches, open a new one for those.
+    /// Opening is retried after a wait if the log changes meanwhile, as then the table read may be
+    /// inconsistent. Fails with SnapshotBusy if the log keeps changing.
+    pub fn open_snapshot(name: &str, options: Options) -> Result<Hammersbald, HammersbaldError> {
+        let mut tries = 0;
+        loop {
+            let log = Self::log_state(name)?;
+            match Self::open_read_only(name, options.clone()) {
+                Ok(mut db) => {
+                    if Self::log_state(name)? == log {
+                        return Ok(db);
+                    }
+                    db.shutdown();
+                    if tries + 1 >= SNAPSHOT_TRIES {
+                        return Err(HammersbaldError::SnapshotBusy);
+                    }
+                },
+                Err(e) => if tries + 1 >= SNAPSHOT_TRIES {
+                    return Err(e);
+                }
+            }
+            thread::sleep(Duration::from_millis(SNAPSHOT_RETRY_DELAY_MS << min(tries, 6)));
+            tries += 1;
+        }
+    }
+
+    // length and first page of the log
+    fn log_state(name: &str) -> Result<(u64, Vec<u8>), HammersbaldError> {
+        if Self::is_single_file(name) {
+            let log = Self::read_only_single_file(name)?.remove(2);
+            let first = log.read_page(PRef::from(0))?.map(|page| page.into_buf().to_vec()).unwrap_or_default();
+            return Ok((log.len()?, first));
+        }
+        let mut len = 0;
+        let mut first = Vec::new();
+        for (chunk, path) in RolledFile::chunk_files(name, "lg")? {
+            len += fs::metadata(&path)?.len();
+            if chunk == 0 {
+                fs::File::open(&path)?.take(PAGE_SIZE as u64).read_to_end(&mut first)?;
+            }
+        }
+        Ok((len, first))
+    }
+
+    /// open a DB, rebuild the hash table from the data file if it can not be opened
+    /// returns the report of the repair if one was needed
+    pub fn open_or_repair(name: &str, options: Options) -> Result<(Hammersbald, Option<RepairReport>), HammersbaldError> {
+        let options = Self::with_name_defaults(name, options);
+        let (log, table, data, link) = Self::open_files(name, &options)?;
+        Hammersbald::open_or_repair(log, table, data, link, &options)
+    }
+
+    /// freeze a DB and store its perfect hash next to its files
+    pub fn freeze(db: Hammersbald, name: &str) -> Result<FrozenDb, HammersbaldError> {
+        let frozen = db.freeze()?;
+        let mut file = fs::File::create(format!("{}.ph", name))?;
+        frozen.serialize(&mut file)?;
+        file.sync_all()?;
+        Ok(frozen)
+    }
+
+    /// open a DB frozen earlier, read-only
+    pub fn open_frozen(name: &str, options: Options) -> Result<FrozenDb, HammersbaldError> {
+        let db = Self::open_read_only(name, options)?;
+        let mut file = io::BufReader::new(fs::File::open(format!("{}.ph", name))?);
+        FrozenDb::open(db, &mut file)
+    }
+
+    /// copy the accessible content of a DB into a new DB created with the given options
+    /// live keys are stored with their data, overwritten data still referred by other data
+    /// and referred data are stored without key, data is copied oldest first and the
+    /// referred prefs are mapped to the new positions. All keys must be stored with their data.
+    /// The page size is the same for all DBs (PAGE_SIZE), it can not be changed by a migration.
+    pub fn migrate(name: &str, new_name: &str, options: Options) -> Result<(), HammersbaldError> {
+        let mut source = Self::open_read_only(name, Options::new(options.cached_data_pages, 0))?;
+        let mut target = Self::new_db_with_options(new_name, options)?;
+        {
+            let mut referred = HashSet::new();
+            let mut prefs = Vec::new();
+            for (pref, envelope) in source.data_envelopes() {
+                match Payload::deserialize(envelope.payload())? {
+                    Payload::Indexed(indexed) => referred.extend(indexed.data.referred()),
+                    Payload::Referred(data) => referred.extend(data.referred()),
+                    Payload::Link(_) => {}
+                }
+                prefs.push(pref);
+            }
+            let mut moved = HashMap::new();
+            for pref in prefs.into_iter().rev() {
+                let envelope = source.get_envelope(pref)?;
+                let new_pref = match Payload::deserialize(envelope.payload())? {
+                    Payload::Indexed(indexed) => {
+                        if !indexed.has_key() {
+                            return Err(HammersbaldError::Corrupted("can not migrate a db that stores only hashes of keys".to_string()));
+                        }
+                        let links = Self::moved_links(&moved, &indexed.data.referred())?;
+                        if source.get(indexed.key)?.map(|(p, _, _)| p) == Some(pref) {
+                            target.put(indexed.key, indexed.data.data, &links)?
+                        } else if referred.contains(&pref) {
+                            target.put_referred(indexed.data.data, &links)?
+                        } else {
+                            continue;
+                        }
+                    },
+                    Payload::Referred(data) => {
+                        let links = Self::moved_links(&moved, &data.referred())?;
+                        target.put_referred(data.data, &links)?
+                    },
+                    Payload::Link(_) => continue
+                };
+                moved.insert(pref, new_pref);
+            }
+        }
+        target.batch()?;
+        target.shutdown();
+        source.shutdown();
+        Ok(())
+    }
+
+    // the new prefs of referred data, all referred data is migrated before the data referring it
+    fn moved_links(moved: &HashMap<PRef, PRef>, referred: &[PRef]) -> Result<Vec<PRef>, HammersbaldError> {
+        referred.iter().map(|r| match moved.get(r) {
+            Some(new_pref) => Ok(*new_pref),
+            None => Err(HammersbaldError::Corrupted(format!("reference to {} that is not migrated", r)))
+        }).collect()
+    }
+
+    /// end the last batch, close the DB and rename its files to a new name
+    /// fails without renaming anything if a file of the new name exists
+    /// files are renamed one by one, the table last, then the directory is synced
+    pub fn publish(mut db: Hammersbald, name: &str, new_name: &str) -> Result<(), HammersbaldError> {
+        db.batch()?;
+        db.shutdown();
+        drop(db);
+
+        let mut renames = Vec::new();
+        for extension in &FILE_EXTENSIONS {
+            if !db_files(new_name, extension)?.is_empty() {
+                return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::AlreadyExists,
+                    format!("{} already has .{} files", new_name, extension))));
+            }
+            for (chunk, path) in db_files(name, extension)? {
+                renames.push((path, db_file_name(new_name, chunk, extension)));
+            }
+        }
+        for (from, to) in renames {
+            fs::rename(from, to)?;
+        }
+        RolledFile::sync_directory_of(name)?;
+        RolledFile::sync_directory_of(new_name)?;
+        Ok(())
+    }
+
+    /// cut the last page of the data, link and table files if it was torn by a crash,
+    /// that is partially written or its trailer can not be the lep or pref of the page.
+    /// Opening a DB does this too, call it before to learn what was cut.
+    /// returns the extension and recovered length of the files cut
+    /// nothing is cut of a single file db, its lengths are recorded only after its pages are synced
+    pub fn cut_torn_pages(name: &str) -> Result<Vec<(String, u64)>, HammersbaldError> {
+        let mut cut = Vec::new();
+        if Self::is_single_file(name) {
+            return Ok(cut);
+        }
+        for (extension, append_only, chunk_size) in &[("bc", true, DATA_CHUNK_SIZE), ("bl", true, DATA_CHUNK_SIZE), ("tb", false, TABLE_CHUNK_SIZE)] {
+            if RolledFile::chunk_files(name, extension)?.is_empty() {
+                continue;
+            }
+            let mut file = RolledFile::new(name, extension, *append_only, *chunk_size)?;
+            if let Some(len) = Self::cut_torn_page(&mut file, *extension != "tb")? {
+                cut.push((extension.to_string(), len));
+            }
+        }
+        Ok(cut)
+    }
+
+    // data pages end with the lep, that is before the end of the page payload
+    // table pages end with their own pref
+    fn cut_torn_page(file: &mut PagedFile, data: bool) -> Result<Option<u64>, HammersbaldError> {
+        let len = file.len()?;
+        let mut new_len = len - len % PAGE_SIZE as u64;
+        if new_len >= PAGE_SIZE as u64 {
+            let last = new_len - PAGE_SIZE as u64;
+            if let Some(page) = file.read_page(PRef::from(last))? {
+                let trailer = page.pref();
+                let possible = if data {
+                    trailer.is_valid() && trailer.as_u64() < last + PAGE_PAYLOAD_SIZE as u64
+                } else {
+                    trailer.as_u64() == last
+                };
+                if !possible {
+                    new_len = last;
+                }
+            }
+        }
+        if new_len < len {
+            file.truncate(new_len)?;
+            file.sync()?;
+            return Ok(Some(new_len));
+        }
+        Ok(None)
+    }
+
+    // ask the file system of the db for free bytes, if min_free_bytes is set without free_space
+    // and keep scratch files in the directory of the db, if no scratch_dir is set
+    fn with_name_defaults(name: &str, mut options: Options) -> Options {
+        if options.min_free_bytes.is_some() && options.free_space.is_none() {
+            let name = name.to_string();
+            options.free_space = Some(Arc::new(move || Ok(RolledFile::free_bytes_of(name.as_str())?.unwrap_or(u64::max_value()))));
+        }
+        if options.scratch_dir.is_none() {
+            let mut dir = Path::new(name).parent().unwrap_or(Path::new("."));
+            if dir.to_string_lossy().to_string().is_empty() {
+                dir = Path::new(".");
+            }
+            options.scratch_dir = Some(dir.to_path_buf());
+        }
+        options
+    }
+
+    fn open_files(name: &str, options: &Options) -> Result<(LogFile, TableFile, DataFile, DataFile), HammersbaldError> {
+        if options.single_file {
+            return Self::open_single_file(name, options);
+        }
+        Self::cut_torn_pages(name)?;
         let data = DataFile::new(
-            Box::new(CachedFile::new(
+            CachedFile::with_options(
                 Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bc", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                    Box::new(Self::rolled_file(
+                        name, "bc", true, DATA_CHUNK_SIZE, options)?))?), options)?)?;
 
         let link = DataFile::new(
-            Box::new(CachedFile::new(
+            CachedFile::with_options(
                 Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bl", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                    Box::new(Self::rolled_file(
+                        name, "bl", true, DATA_CHUNK_SIZE, options)?))?), options)?)?;
 
         let log = LogFile::new(
             Box::new(AsyncFile::new(
-                Box::new(RolledFile::new(name, "lg", true, LOG_CHUNK_SIZE)?))?));
+                Box::new(Self::rolled_file(name, "lg", true, LOG_CHUNK_SIZE, options)?))?));
 
         let table = TableFile::new(
-            Box::new(CachedFile::new(
-            Box::new(RolledFile::new(name, "tb", false, TABLE_CHUNK_SIZE)?), cached_data_pages)?))?;
+            CachedFile::with_options(
+            Box::new(Self::rolled_file(name, "tb", false, TABLE_CHUNK_SIZE, options)?), options)?)?;
 
-        Hammersbald::new(log, table, data, link, bucket_fill_target)
+        Ok((log, table, data, link))
+    }
+
+    // data, link, log and table striped in one file
+    fn open_single_file(name: &str, options: &Options) -> Result<(LogFile, TableFile, DataFile, DataFile), HammersbaldError> {
+        let mut files = StripedFile::open(&Self::single_file_name(name), 4)?.into_iter();
+        let mut next = || -> Box<PagedFile> { Box::new(files.next().unwrap()) };
+        let data = DataFile::new(CachedFile::with_options(next(), options)?)?;
+        let link = DataFile::new(CachedFile::with_options(next(), options)?)?;
+        let log = LogFile::new(next());
+        let table = TableFile::new(CachedFile::with_options(next(), options)?)?;
+        Ok((log, table, data, link))
+    }
+
+    fn rolled_file(name: &str, extension: &str, append_only: bool, chunk_size: u64, options: &Options) -> Result<RolledFile, HammersbaldError> {
+        let mut file = RolledFile::new(name, extension, append_only, chunk_size)?;
+        file.set_sync_directory(options.sync_directory);
+        Ok(file)
+    }
+}
+
+/// swap the files of two closed DBs, e.g. to replace a live DB by one indexed meanwhile
+/// files of a_name are moved to a temporary name first, the steps are recorded in a journal
+/// next to a_name so recover_swap can complete an interrupted swap. Both DBs must be closed
+/// and in the same file system. Fails if an interrupted swap of a_name was not recovered.
+pub fn swap(a_name: &str, b_name: &str) -> Result<(), HammersbaldError> {
+    if a_name == b_name {
+        return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::InvalidInput, "can not swap a DB with itself")));
+    }
+    if Path::new(&swap_journal(a_name)).exists() {
+        return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::AlreadyExists,
+            format!("recover the interrupted swap of {} first", a_name))));
+    }
+    for extension in &FILE_EXTENSIONS {
+        if !db_files(&swap_name(a_name), extension)?.is_empty() {
+            return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("{} already has .{} files", swap_name(a_name), extension))));
+        }
+    }
+    run_swap(a_name, b_name, 0)
+}
+
+/// complete a swap of a_name interrupted by a crash
+/// returns false if there was none
+pub fn recover_swap(a_name: &str) -> Result<bool, HammersbaldError> {
+    let journal = match fs::read_to_string(swap_journal(a_name)) {
+        Ok(journal) => journal,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(HammersbaldError::IO(e))
+    };
+    let mut lines = journal.lines();
+    if let (Some(b_name), Some(step)) = (lines.next(), lines.next().and_then(|s| s.parse::<usize>().ok())) {
+        run_swap(a_name, b_name, step)?;
+        return Ok(true);
+    }
+    Err(HammersbaldError::Corrupted(format!("invalid swap journal of {}", a_name)))
+}
+
+// the chunk files with an extension, or the single file
+fn db_files(name: &str, extension: &str) -> Result<Vec<(u16, PathBuf)>, HammersbaldError> {
+    if extension == SINGLE_FILE_EXTENSION {
+        let path = PathBuf::from(Persistent::single_file_name(name));
+        return Ok(if path.exists() { vec!((0, path)) } else { Vec::new() });
+    }
+    RolledFile::chunk_files(name, extension)
+}
+
+fn db_file_name(name: &str, chunk: u16, extension: &str) -> String {
+    if extension == SINGLE_FILE_EXTENSION {
+        return Persistent::single_file_name(name);
+    }
+    RolledFile::chunk_name(name, chunk, extension)
+}
+
+// files of a are moved to here during a swap
+fn swap_name(a_name: &str) -> String {
+    format!("{}.swap", a_name)
+}
+
+fn swap_journal(a_name: &str) -> String {
+    format!("{}.swaplog", a_name)
+}
+
+// the next journal is written here, then renamed to the journal
+fn swap_journal_tmp(a_name: &str) -> String {
+    format!("{}.swaplog.tmp", a_name)
+}
+
+// each extension is moved in three steps: a to the temporary name, b to a, then the temporary to b
+// a step moves the files left at its source, so it can be repeated after a crash
+fn run_swap(a_name: &str, b_name: &str, first_step: usize) -> Result<(), HammersbaldError> {
+    let tmp_name = swap_name(a_name);
+    for step in first_step .. FILE_EXTENSIONS.len() * 3 {
+        // the journal is replaced by a rename, so a crash leaves either the previous or the new one
+        {
+            let mut journal = fs::File::create(swap_journal_tmp(a_name))?;
+            writeln!(journal, "{}", b_name)?;
+            writeln!(journal, "{}", step)?;
+            journal.sync_all()?;
+        }
+        fs::rename(swap_journal_tmp(a_name), swap_journal(a_name))?;
+        RolledFile::sync_directory_of(a_name)?;
+
+        let extension = FILE_EXTENSIONS[step / 3];
+        let (from, to) = match step % 3 {
+            0 => (a_name, tmp_name.as_str()),
+            1 => (b_name, a_name),
+            _ => (tmp_name.as_str(), b_name)
+        };
+        for (chunk, path) in db_files(from, extension)? {
+            fs::rename(path, db_file_name(to, chunk, extension))?;
+        }
+        RolledFile::sync_directory_of(a_name)?;
+        RolledFile::sync_directory_of(b_name)?;
+    }
+    fs::remove_file(swap_journal(a_name))?;
+    RolledFile::sync_directory_of(a_name)
+}
+
+impl HammersbaldFactory for Persistent {
+    fn new_db_with_options(name: &str, options: Options) -> Result<Hammersbald, HammersbaldError> {
+        let options = Self::with_name_defaults(name, options);
+        let (log, table, data, link) = Self::open_files(name, &options)?;
+        Hammersbald::new_with_options(log, table, data, link, &options)
     }
 }
 
@@ -102,4 +550,1043 @@ impl PagedFile for Persistent {
     fn flush(&mut self) -> Result<(), HammersbaldError> {
         self.file.flush()
     }
-}
\ No newline at end of file
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use api::{HammersbaldAPI, Durability};
+
+    use super::*;
+    use self::rand::{thread_rng, RngCore};
+    use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+    use format::BucketCodec;
+    use options::KeyTransform;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use testdir::TestDir;
+
+    #[test]
+    fn test_read_only () {
+        let dir = TestDir::new("ro");
+        let name = dir.name("testdb");
+
+        let committed;
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            committed = db.put(&[1u8; 32], &[2u8; 40], &vec!()).unwrap();
+            db.batch().unwrap();
+            db.put(&[3u8; 32], &[4u8; 40], &vec!()).unwrap();
+            db.shutdown();
+        }
+
+        let mut lengths = Vec::new();
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            let mut permissions = fs::metadata(&path).unwrap().permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&path, permissions).unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o222, 0);
+            }
+            lengths.push((path.clone(), fs::metadata(&path).unwrap().len()));
+        }
+
+        {
+            let mut db = Persistent::open_read_only(name.as_str(), Options::new(10, 0)).unwrap();
+            assert!(db.is_read_only());
+            assert_eq!(db.get(&[1u8; 32]).unwrap(), Some((committed, vec!(2u8; 40), vec!())));
+            assert_eq!(db.get(&[3u8; 32]).unwrap(), None);
+            assert!(db.put(&[5u8; 32], &[6u8; 40], &vec!()).is_err());
+            assert!(db.batch().is_err());
+            db.shutdown();
+        }
+        // permissions do not stop root, so also check that nothing of the unfinished batch was cut
+        for (path, len) in lengths {
+            assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        }
+    }
+
+    #[test]
+    fn test_swap () {
+        let dir = TestDir::new("swap");
+        let a = dir.name("a");
+        let b = dir.name("b");
+        for (name, value) in &[(&a, 1u8), (&b, 2u8)] {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            db.put(&[0], &[*value], &vec!()).unwrap();
+            db.put(&[*value], &[*value], &vec!()).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        let check = |a_value: u8, b_value: u8| {
+            for (name, value) in &[(&a, a_value), (&b, b_value)] {
+                let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+                db.init().unwrap();
+                assert_eq!(db.get(&[0]).unwrap().unwrap().1, vec!(*value));
+                assert_eq!(db.get(&[*value]).unwrap().unwrap().1, vec!(*value));
+                db.shutdown();
+            }
+        };
+
+        swap(&a, &b).unwrap();
+        check(2, 1);
+        assert!(!recover_swap(&a).unwrap());
+
+        // interrupted after moving the data of a to the temporary name
+        for (chunk, path) in RolledFile::chunk_files(&a, "bc").unwrap() {
+            fs::rename(path, RolledFile::chunk_name(&swap_name(&a), chunk, "bc")).unwrap();
+        }
+        fs::write(swap_journal(&a), format!("{}\n1\n", b)).unwrap();
+        assert!(swap(&a, &b).is_err());
+        assert!(recover_swap(&a).unwrap());
+        check(1, 2);
+        assert!(!Path::new(&swap_journal(&a)).exists());
+    }
+
+    #[test]
+    fn test_fill_target () {
+        let dir = TestDir::new("ft");
+        let name = dir.name("testdb");
+        let open = |fill_target: usize| {
+            let mut db = Persistent::new_db(name.as_str(), 10, fill_target).unwrap();
+            db.init().unwrap();
+            let configured = db.configured_fill_target();
+            db.batch().unwrap();
+            db.shutdown();
+            configured
+        };
+        assert_eq!(open(0), 64);
+        assert_eq!(open(4), 4);
+        assert_eq!(open(0), 4);
+        assert_eq!(open(8), 8);
+        assert_eq!(open(0), 8);
+        let mut db = Persistent::open_read_only(name.as_str(), Options::new(10, 0)).unwrap();
+        assert_eq!(db.configured_fill_target(), 8);
+        db.shutdown();
+        // the default options keep the fill target of earlier versions
+        let mut db = Persistent::new_db_with_options(name.as_str(), Options::default()).unwrap();
+        assert_eq!(db.configured_fill_target(), 64);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_magic_namespace () {
+        let dir = TestDir::new("ns");
+        let name = dir.name("testdb");
+        let options = |magic_namespace: [u8; 2]| Options { magic_namespace, .. Options::default() };
+        {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options([0xbc, 1])).unwrap();
+            db.init().unwrap();
+            db.put(&[1], &[1], &vec!()).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        for other in &[[0, 0], [0xbc, 2]] {
+            match Persistent::new_db_with_options(name.as_str(), options(*other)) {
+                Err(HammersbaldError::BadMagic) => {},
+                _ => panic!("expected BadMagic")
+            }
+        }
+        // a db of another namespace is not taken as corrupted and rebuilt
+        assert!(Persistent::open_or_repair(name.as_str(), options([0, 0])).is_err());
+        let mut db = Persistent::new_db_with_options(name.as_str(), options([0xbc, 1])).unwrap();
+        assert_eq!(db.get(&[1]).unwrap().unwrap().1, vec!(1));
+        db.shutdown();
+
+        assert!(Persistent::open_read_only(name.as_str(), options([0, 0])).is_err());
+        let mut db = Persistent::open_read_only(name.as_str(), options([0xbc, 1])).unwrap();
+        assert_eq!(db.get(&[1]).unwrap().unwrap().1, vec!(1));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_scratch_in_db_dir () {
+        let dir = TestDir::new("dbscratch");
+        let name = dir.name("testdb");
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        let root = db.put_referred(&[1], &vec!()).unwrap();
+        db.batch().unwrap();
+        let scratch_files = || fs::read_dir(dir.path()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("hammersbald-visited-")).count();
+        {
+            let _dag = db.dag_disk_visited(root, None).unwrap();
+            assert_eq!(scratch_files(), 1);
+        }
+        assert_eq!(scratch_files(), 0);
+        db.shutdown();
+
+        // also for a read-only open
+        let mut db = Persistent::open_read_only(name.as_str(), Options::new(10, 1)).unwrap();
+        {
+            let _dag = db.dag_disk_visited(root, None).unwrap();
+            assert_eq!(scratch_files(), 1);
+        }
+        assert_eq!(scratch_files(), 0);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_single_file () {
+        let dir = TestDir::new("sf");
+        let name = dir.name("testdb");
+        let options = Options { single_file: true, .. Options::new(10, 1) };
+
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+            db.init().unwrap();
+            for i in 0 .. 1000u32 {
+                let key = [(i >> 8) as u8, i as u8];
+                check.push((key, db.put(&key, &[i as u8; 100], &vec!()).unwrap()));
+                if i % 300 == 0 {
+                    db.batch().unwrap();
+                }
+            }
+            db.batch().unwrap();
+            // not part of a batch
+            db.put(&[9, 9, 9], &[1], &vec!()).unwrap();
+            db.shutdown();
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+        db.init().unwrap();
+        for (i, (key, pref)) in check.iter().enumerate() {
+            assert_eq!(db.get(key).unwrap(), Some((*pref, vec!(i as u8; 100), vec!())));
+        }
+        assert_eq!(db.get(&[9, 9, 9]).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_single_file_tools () {
+        let dir = TestDir::new("sft");
+        let name = dir.name("testdb");
+        let options = Options { single_file: true, .. Options::new(10, 1) };
+
+        let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+        db.init().unwrap();
+        let pref = db.put(b"foo", &[1], &vec!()).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        assert!(Persistent::cut_torn_pages(name.as_str()).unwrap().is_empty());
+        // the layout is found without option single_file
+        let mut db = Persistent::open_read_only(name.as_str(), Options::new(10, 1)).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        db.shutdown();
+        let mut db = Persistent::open_snapshot(name.as_str(), Options::new(10, 1)).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        db.shutdown();
+        let mut db = Persistent::open_data_only(name.as_str(), Options::new(10, 1)).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        db.shutdown();
+
+        let migrated = dir.name("migrated");
+        Persistent::migrate(name.as_str(), migrated.as_str(), options.clone()).unwrap();
+        let mut db = Persistent::new_db_with_options(migrated.as_str(), options.clone()).unwrap();
+        db.init().unwrap();
+        db.put(b"bar", &[2], &vec!()).unwrap();
+        let published = dir.name("published");
+        Persistent::publish(db, migrated.as_str(), published.as_str()).unwrap();
+        assert!(!Path::new(&format!("{}.hb", migrated)).exists());
+
+        swap(name.as_str(), published.as_str()).unwrap();
+        let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+        db.init().unwrap();
+        assert_eq!(db.get(b"bar").unwrap().map(|(_, data, _)| data), Some(vec!(2)));
+        db.shutdown();
+        let mut db = Persistent::open_read_only(published.as_str(), Options::new(10, 1)).unwrap();
+        assert_eq!(db.get(b"bar").unwrap(), None);
+        assert!(db.get(b"foo").unwrap().is_some());
+        db.shutdown();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_data_only () {
+        let dir = TestDir::new("do");
+        let name = dir.name("testdb");
+
+        let mut check = Vec::new();
+        let node;
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            for i in 0 .. 100u8 {
+                db.put(&[i], &[i, 0], &vec!()).unwrap();
+            }
+            for i in 0 .. 100u8 {
+                check.push(db.put(&[i], &[i, 1], &vec!()).unwrap());
+            }
+            node = db.put_referred(&[1], &vec!(check[0])).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        for extension in &["tb", "bl", "lg"] {
+            for (_, path) in RolledFile::chunk_files(&name, extension).unwrap() {
+                fs::remove_file(path).unwrap();
+            }
+        }
+
+        let mut db = Persistent::open_data_only(name.as_str(), Options::new(10, 0)).unwrap();
+        for i in 0 .. 100u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some((check[i as usize], vec!(i, 1), vec!())));
+        }
+        assert_eq!(db.get(&[100]).unwrap(), None);
+        assert_eq!(db.get_referred(node).unwrap(), (vec!(), vec!(1), vec!(check[0])));
+        assert_eq!(db.dag(node).count(), 2);
+        assert_eq!(db.data_envelopes().count(), 201);
+        let mut keys = db.iter().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, (0 .. 100u8).map(|i| (vec!(i), check[i as usize])).collect::<Vec<_>>());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_key_transform_read_only () {
+        let dir = TestDir::new("ktro");
+        let name = dir.name("testdb");
+        let upper: KeyTransform = Arc::new(|key: &[u8]| key.to_ascii_uppercase());
+        let options = Options { key_transform: Some(upper), .. Options::new(10, 0) };
+        let pref;
+        {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+            db.init().unwrap();
+            pref = db.put(b"Foo", &[1], &vec!()).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+
+        let mut db = Persistent::open_read_only(name.as_str(), options.clone()).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        db.shutdown();
+
+        let mut db = Persistent::open_data_only(name.as_str(), options).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some((pref, vec!(1), vec!())));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_data_only_options () {
+        let dir = TestDir::new("doo");
+        let name = dir.name("testdb");
+        let options = Options { fixed_value_size: Some(2), app_trailer_bytes: 4, .. Options::new(10, 0) };
+
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+            db.init().unwrap();
+            for i in 0 .. 200u8 {
+                check.push(db.put(&[i; 32], &[i, 1], &vec!()).unwrap());
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        let data_len = fs::metadata(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap().len();
+        {
+            // a whole page with an impossible lep and half a page
+            let mut data = OpenOptions::new().append(true).open(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap();
+            data.write_all(&[0xffu8; PAGE_SIZE + PAGE_SIZE / 2]).unwrap();
+        }
+
+        let mut db = Persistent::open_data_only(name.as_str(), options).unwrap();
+        for i in 0 .. 200u8 {
+            assert_eq!(db.get(&[i; 32]).unwrap(), Some((check[i as usize], vec!(i, 1), vec!())));
+        }
+        assert_eq!(db.data_envelopes().count(), 200);
+        db.shutdown();
+        assert_eq!(fs::metadata(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap().len(), data_len + (PAGE_SIZE + PAGE_SIZE / 2) as u64);
+    }
+
+    #[test]
+    fn test_torn_pages () {
+        let dir = TestDir::new("torn");
+        let name = dir.name("testdb");
+
+        let pref;
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            pref = db.put(&[1u8; 32], &[2u8; 40], &vec!()).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        let data_len = fs::metadata(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap().len();
+        let table_len = fs::metadata(RolledFile::chunk_name(name.as_str(), 0, "tb")).unwrap().len();
+        {
+            // half a page and a whole page with an impossible lep
+            let mut data = OpenOptions::new().append(true).open(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap();
+            data.write_all(&[0xffu8; PAGE_SIZE + PAGE_SIZE / 2]).unwrap();
+            // a table page not ending with its pref
+            let mut table = OpenOptions::new().append(true).open(RolledFile::chunk_name(name.as_str(), 0, "tb")).unwrap();
+            table.write_all(&[0u8; PAGE_SIZE]).unwrap();
+        }
+
+        let cut = Persistent::cut_torn_pages(name.as_str()).unwrap();
+        assert_eq!(cut, vec!(("bc".to_string(), data_len), ("tb".to_string(), table_len)));
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            assert_eq!(db.get(&[1u8; 32]).unwrap(), Some((pref, vec!(2u8; 40), vec!())));
+            db.shutdown();
+        }
+        assert_eq!(fs::metadata(RolledFile::chunk_name(name.as_str(), 0, "bc")).unwrap().len(), data_len);
+        assert!(Persistent::cut_torn_pages(name.as_str()).unwrap().is_empty());
+    }
+
+    #[cfg(feature="crash-test")]
+    #[test]
+    fn test_crash_points () {
+        use crash::{CrashPoint, CRASH_POINTS, BATCH_TABLE_SYNCED, RECOVERY_REPLAYED};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // crashes at a point once armed
+        struct CrashAt (&'static str, AtomicBool);
+        impl CrashPoint for CrashAt {
+            fn crash(&self, point: &str) -> bool {
+                point == self.0 && self.1.load(Ordering::Acquire)
+            }
+        }
+        fn crash_at (point: &'static str, armed: bool) -> (Arc<CrashAt>, Options) {
+            let crash = Arc::new(CrashAt(point, AtomicBool::new(armed)));
+            (crash.clone(), Options { crash_point: Some(crash), .. Options::new(10, 1) })
+        }
+
+        for point in CRASH_POINTS.iter() {
+            let dir = TestDir::new("crash");
+            let name = dir.name("testdb");
+
+            let mut committed = Vec::new();
+            {
+                let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+                db.init().unwrap();
+                for i in 0 .. 1000u32 {
+                    let key = [(i >> 8) as u8, i as u8];
+                    committed.push((key, db.put(&key, &key, &vec!()).unwrap()));
+                }
+                db.batch().unwrap();
+                db.shutdown();
+            }
+            {
+                // a recovery has something to replay after a crash in a batch that changed the table
+                let batch_point = if *point == RECOVERY_REPLAYED { BATCH_TABLE_SYNCED } else { *point };
+                let (crash, options) = crash_at(batch_point, false);
+                let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+                crash.1.store(true, Ordering::Release);
+                for i in 0 .. 1000u32 {
+                    let key = [(i >> 8) as u8, i as u8];
+                    db.put(&key, &[0], &vec!()).unwrap();
+                    db.put(&[9, (i >> 8) as u8, i as u8], &[0], &vec!()).unwrap();
+                }
+                match db.batch() {
+                    Err(HammersbaldError::Crashed(ref p)) if p == batch_point => {},
+                    _ => panic!("expected crash at {}", batch_point)
+                }
+                db.shutdown();
+            }
+            if *point == RECOVERY_REPLAYED {
+                assert!(Persistent::new_db_with_options(name.as_str(), crash_at(RECOVERY_REPLAYED, true).1).is_err());
+            }
+            {
+                let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+                for (key, pref) in &committed {
+                    assert_eq!(db.get(key).unwrap(), Some((*pref, key.to_vec(), vec!())));
+                }
+                assert_eq!(db.get(&[9, 0, 0]).unwrap(), None);
+                assert_eq!(db.slots().map(|s| s.unwrap().len()).sum::<usize>(), 1000);
+                db.put(&[9, 0, 0], &[1], &vec!()).unwrap();
+                db.batch().unwrap();
+                assert_eq!(db.get(&[9, 0, 0]).unwrap().unwrap().1, vec!(1));
+                db.shutdown();
+            }
+        }
+    }
+
+    #[test]
+    fn test_readahead () {
+        let dir = TestDir::new("readahead");
+        let name = dir.name("testdb");
+        {
+            let mut db = Persistent::new_db(name.as_str(), 16, 1).unwrap();
+            db.init().unwrap();
+            for i in 0 .. 1000u32 {
+                let key = [i as u8, (i >> 8) as u8];
+                db.put(&key, &vec!(i as u8; (i as usize * 17) % 5000), &vec!()).unwrap();
+                if i % 300 == 0 {
+                    db.batch().unwrap();
+                }
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        let envelopes = |options: Options| {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+            let envelopes = db.data_envelopes().map(|(pos, envelope)| (pos, envelope.payload().to_vec())).collect::<Vec<_>>();
+            db.shutdown();
+            envelopes
+        };
+        let expected = envelopes(Options::new(16, 1));
+        assert_eq!(expected.len(), 1000);
+        assert!(expected == envelopes(Options { readahead_pages: 8, .. Options::new(16, 1) }));
+    }
+
+    #[test]
+    fn test_barrier () {
+        let dir = TestDir::new("barrier");
+        let copy = TestDir::new("barrier-copy");
+        let name = dir.name("testdb");
+        let log = RolledFile::chunk_name(name.as_str(), 0, "lg");
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 1000u32 {
+            db.put(&[(i >> 8) as u8, i as u8], &[1], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        let mut prefs = Vec::new();
+        for i in 0 .. 1000u32 {
+            prefs.push(db.put(&[(i >> 8) as u8, i as u8], &[2], &vec!()).unwrap());
+        }
+        let logged = fs::metadata(&log).unwrap().len();
+        assert!(logged > PAGE_SIZE as u64);
+        db.barrier().unwrap();
+        // the log keeps the table pages logged
+        assert_eq!(fs::metadata(&log).unwrap().len(), logged);
+
+        // as if the process ended here
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), copy.path().join(entry.file_name())).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = Persistent::new_db(copy.name("testdb").as_str(), 10, 1).unwrap();
+        for pref in prefs {
+            assert_eq!(db.get_referred(pref).unwrap().1, vec!(2));
+        }
+        // the table of the previous batch
+        assert_eq!(db.get(&[0, 1]).unwrap().unwrap().1, vec!(1));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_with_durability () {
+        let dir = TestDir::new("durability");
+        let copy = TestDir::new("durability-copy");
+        let name = dir.name("testdb");
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        db.put(&[0], &[0], &vec!()).unwrap();
+        db.batch().unwrap();
+        let synced = db.put_with_durability(&[1], &[1], &vec!(), Durability::Synced).unwrap();
+        let flushed = db.put_with_durability(&[2], &[2], &vec!(), Durability::Flushed).unwrap();
+        let buffered = db.put_with_durability(&[3], &[3], &vec!(), Durability::Buffered).unwrap();
+
+        // as if the process ended here
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), copy.path().join(entry.file_name())).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = Persistent::new_db(copy.name("testdb").as_str(), 10, 1).unwrap();
+        assert_eq!(db.get_referred(synced).unwrap().1, vec!(1));
+        assert_eq!(db.get_referred(flushed).unwrap().1, vec!(2));
+        assert!(db.get_referred(buffered).is_err());
+        // found by key after the batch only
+        assert_eq!(db.get(&[0]).unwrap().unwrap().1, vec!(0));
+        assert!(db.get(&[1]).unwrap().is_none());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dry_run_recovery () {
+        let dir = TestDir::new("dry");
+        let copy = TestDir::new("dry-copy");
+        let name = dir.name("testdb");
+        let copy_name = copy.name("testdb");
+        let lengths = |name: &str| ["bc", "tb", "bl", "lg"].iter()
+            .map(|e| fs::metadata(RolledFile::chunk_name(name, 0, e)).unwrap().len()).collect::<Vec<_>>();
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 1000u32 {
+            db.put(&[(i >> 8) as u8, i as u8], &[1], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        for i in 0 .. 1000u32 {
+            db.put(&[(i >> 8) as u8, i as u8], &[2], &vec!()).unwrap();
+        }
+        db.barrier().unwrap();
+        // as if the process ended here
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), copy.path().join(entry.file_name())).unwrap();
+        }
+        db.shutdown();
+
+        let before = lengths(copy_name.as_str());
+        let plan = Persistent::dry_run_recovery(copy_name.as_str()).unwrap();
+        assert_eq!(lengths(copy_name.as_str()), before);
+        assert_eq!((plan.current_data_len, plan.current_table_len, plan.current_link_len), (before[0], before[1], before[2]));
+        assert!(!plan.table_pages.is_empty());
+        // the barrier made the data durable
+        assert_eq!(plan.target_data_len, plan.current_data_len);
+
+        let mut db = Persistent::new_db(copy_name.as_str(), 10, 1).unwrap();
+        db.shutdown();
+        let after = lengths(copy_name.as_str());
+        assert_eq!((plan.target_data_len, plan.target_table_len, plan.target_link_len), (after[0], after[1], after[2]));
+        // nothing left to recover
+        let plan = Persistent::dry_run_recovery(copy_name.as_str()).unwrap();
+        assert!(plan.table_pages.is_empty());
+        assert_eq!(plan.target_link_len, plan.current_link_len);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_min_free_bytes () {
+        let dir = TestDir::new("free");
+        let name = dir.name("testdb");
+        assert!(RolledFile::free_bytes_of(name.as_str()).unwrap().unwrap() > 0);
+
+        let mut db = Persistent::new_db_with_options(name.as_str(), Options { min_free_bytes: Some(1), .. Options::new(10, 1) }).unwrap();
+        db.init().unwrap();
+        db.put(&[1], &[1], &vec!()).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        // opens, but refuses writes
+        let mut db = Persistent::new_db_with_options(name.as_str(), Options { min_free_bytes: Some(u64::max_value()), .. Options::new(10, 1) }).unwrap();
+        assert!(db.put(&[2], &[2], &vec!()).is_err());
+        assert_eq!(db.get(&[1]).unwrap().unwrap().1, vec!(1));
+        db.shutdown();
+    }
+
+    // slots sorted by hash with little endian numbers, counting encoded links
+    struct SortedCodec {
+        encoded: AtomicUsize
+    }
+
+    impl BucketCodec for SortedCodec {
+        fn encode(&self, slots: &[(u64, PRef)]) -> Vec<u8> {
+            self.encoded.fetch_add(1, Ordering::SeqCst);
+            let mut sorted = slots.to_vec();
+            sorted.sort();
+            let mut bytes = Vec::new();
+            for (hash, pref) in sorted {
+                bytes.write_u64::<LittleEndian>(hash).unwrap();
+                bytes.write_u48::<LittleEndian>(pref.as_u64()).unwrap();
+            }
+            bytes
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<Vec<(u64, PRef)>, HammersbaldError> {
+            let mut slots = Vec::new();
+            let mut reader = bytes;
+            while !reader.is_empty() {
+                let hash = reader.read_u64::<LittleEndian>()?;
+                slots.push((hash, PRef::from(reader.read_u48::<LittleEndian>()?)));
+            }
+            Ok(slots)
+        }
+    }
+
+    #[test]
+    fn test_bucket_codec () {
+        let dir = TestDir::new("codec");
+        let name = dir.name("testdb");
+        let codec = Arc::new(SortedCodec { encoded: AtomicUsize::new(0) });
+        let options = Options { bucket_codec: Some(codec.clone()), .. Options::new(10, 4) };
+
+        let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+        db.init().unwrap();
+        let mut check = Vec::new();
+        for i in 0 .. 2000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.push((key, db.put(&key, &key, &vec!()).unwrap()));
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        assert!(codec.encoded.load(Ordering::SeqCst) > 0);
+
+        // links are read by a scan, by threads and lazily
+        for options in &[options.clone(), Options { load_threads: 4, .. options.clone() },
+            Options { recovery_memory_limit: 1, .. options.clone() }] {
+            let mut db = Persistent::new_db_with_options(name.as_str(), options.clone()).unwrap();
+            for (key, pref) in &check {
+                assert_eq!(db.get(key).unwrap(), Some((*pref, key.to_vec(), vec!())));
+            }
+            assert_eq!(db.get(&[0xff, 0xff]).unwrap(), None);
+            db.shutdown();
+        }
+        // and by read-only opens
+        let mut db = Persistent::open_read_only(name.as_str(), options.clone()).unwrap();
+        for (key, pref) in &check {
+            assert_eq!(db.get(key).unwrap(), Some((*pref, key.to_vec(), vec!())));
+        }
+        db.shutdown();
+        let mut db = Persistent::open_snapshot(name.as_str(), options).unwrap();
+        assert_eq!(db.get(&check[0].0).unwrap().unwrap().0, check[0].1);
+        db.shutdown();
+        // the links can not be read without the codec
+        assert!(Persistent::new_db(name.as_str(), 10, 4).is_err());
+        assert!(Persistent::open_read_only(name.as_str(), Options::new(10, 0)).is_err());
+    }
+
+    #[test]
+    fn test_rewind () {
+        let dir = TestDir::new("rewind");
+        let name = dir.name("testdb");
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        for i in 0 .. 1000u32 {
+            db.put(&[0, (i >> 8) as u8, i as u8], &[1], &vec!()).unwrap();
+        }
+        db.batch().unwrap();
+        let tip = db.data_tip();
+        for i in 0 .. 1000u32 {
+            db.put(&[1, (i >> 8) as u8, i as u8], &[2], &vec!()).unwrap();
+        }
+        assert!(db.rewind(PRef::from(tip.as_u64() + 1)).is_err());
+        db.rewind(tip).unwrap();
+        assert_eq!(db.data_tip(), tip);
+        assert!(db.get(&[1, 0, 0]).unwrap().is_none());
+        db.put(&[2], &[3], &vec!()).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        for i in 0 .. 1000u32 {
+            assert_eq!(db.get(&[0, (i >> 8) as u8, i as u8]).unwrap().unwrap().1, vec!(1));
+            assert!(db.get(&[1, (i >> 8) as u8, i as u8]).unwrap().is_none());
+        }
+        assert_eq!(db.get(&[2]).unwrap().unwrap().1, vec!(3));
+        assert_eq!(db.data_envelopes().count(), 1001);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_snapshot () {
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let dir = TestDir::new("snapshot");
+        let name = dir.name("testdb");
+
+        let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        let (committed, batches) = channel();
+        let reader_name = name.clone();
+        let reader = thread::spawn(move || {
+            for batch in batches {
+                let mut snapshot = Persistent::open_snapshot(reader_name.as_str(), Options::new(10, 0)).unwrap();
+                // keys of all batches committed so far
+                for i in 0 .. (batch + 1) * 100u32 {
+                    assert_eq!(snapshot.get(&[(i >> 8) as u8, i as u8]).unwrap().unwrap().1, vec!(1));
+                }
+                assert!(snapshot.stats().data_bytes > 0);
+                snapshot.shutdown();
+            }
+        });
+        for batch in 0 .. 20u32 {
+            for i in batch * 100 .. (batch + 1) * 100 {
+                db.put(&[(i >> 8) as u8, i as u8], &[1], &vec!()).unwrap();
+            }
+            db.batch().unwrap();
+            committed.send(batch).unwrap();
+        }
+        drop(committed);
+        reader.join().unwrap();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_publish () {
+        let dir = TestDir::new("publish");
+        let temp_name = dir.name("building");
+        let name = dir.name("testdb");
+
+        let mut db = Persistent::new_db(temp_name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        let pref = db.put(&[1u8; 32], &[2u8; 40], &vec!()).unwrap();
+        Persistent::publish(db, temp_name.as_str(), name.as_str()).unwrap();
+        assert!(RolledFile::chunk_files(temp_name.as_str(), "bc").unwrap().is_empty());
+
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            assert_eq!(db.get(&[1u8; 32]).unwrap(), Some((pref, vec!(2u8; 40), vec!())));
+            db.shutdown();
+        }
+
+        let mut db = Persistent::new_db(temp_name.as_str(), 10, 1).unwrap();
+        db.init().unwrap();
+        db.put(&[3u8; 32], &[4u8; 40], &vec!()).unwrap();
+        assert!(Persistent::publish(db, temp_name.as_str(), name.as_str()).is_err());
+        // nothing was renamed
+        assert!(!RolledFile::chunk_files(temp_name.as_str(), "tb").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_or_repair () {
+        let dir = TestDir::new("repair");
+        let name = dir.name("testdb");
+
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            for i in 0 .. 1000u32 {
+                let key = [i as u8, (i >> 8) as u8];
+                check.push((key, db.put(&key, &[i as u8; 40], &vec!()).unwrap()));
+            }
+            // overwritten keys must resolve to the latest data
+            for entry in check.iter_mut().take(10) {
+                entry.1 = db.put(&entry.0, &[0xff; 40], &vec!()).unwrap();
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+
+        {
+            // break the pref of the first table page
+            let mut table = OpenOptions::new().write(true).open(dir.path().join("testdb.0.tb")).unwrap();
+            table.seek(SeekFrom::Start(PAGE_PAYLOAD_SIZE as u64)).unwrap();
+            table.write_all(&[0xff; 6]).unwrap();
+        }
+        assert!(Persistent::new_db(name.as_str(), 10, 1).is_err());
+
+        {
+            let (mut db, report) = Persistent::open_or_repair(name.as_str(), Options::new(10, 1)).unwrap();
+            let report = report.unwrap();
+            assert_eq!(report.indexed, 1000);
+            assert_eq!(report.discarded, 0);
+            for (key, pref) in &check {
+                assert_eq!(db.get(&key[..]).unwrap().unwrap().0, *pref);
+            }
+            db.shutdown();
+        }
+
+        {
+            let (mut db, report) = Persistent::open_or_repair(name.as_str(), Options::new(10, 1)).unwrap();
+            assert!(report.is_none());
+            for (key, pref) in &check {
+                assert_eq!(db.get(&key[..]).unwrap().unwrap().0, *pref);
+            }
+            db.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_freeze () {
+        let dir = TestDir::new("frozen");
+        let name = dir.name("testdb");
+
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            let mut key = [0u8; 32];
+            let mut data = [0u8; 40];
+            for _ in 0 .. 1000 {
+                thread_rng().fill_bytes(&mut key);
+                thread_rng().fill_bytes(&mut data);
+                check.push((key, db.put(&key, &data, &vec!()).unwrap(), data));
+            }
+            // overwritten data is not reachable
+            let (key, _, _) = check[0];
+            check[0].1 = db.put(&key, &[1, 2, 3], &vec!()).unwrap();
+            let mut frozen = Persistent::freeze(db, name.as_str()).unwrap();
+            assert_eq!(frozen.len(), 1000);
+            assert!(!frozen.is_empty());
+            assert_eq!(frozen.get(&key).unwrap().unwrap(), (check[0].1, vec!(1, 2, 3), vec!()));
+            for (key, pref, data) in &check[1..] {
+                assert_eq!(frozen.get(key).unwrap().unwrap(), (*pref, data.to_vec(), vec!()));
+            }
+            frozen.shutdown();
+        }
+        {
+            let mut frozen = Persistent::open_frozen(name.as_str(), Options::new(10, 0)).unwrap();
+            for (key, pref, _) in &check {
+                assert_eq!(frozen.get(key).unwrap().unwrap().0, *pref);
+            }
+            assert!(frozen.get(&[0u8; 31]).unwrap().is_none());
+            frozen.shutdown();
+        }
+        {
+            // counts of a damaged perfect hash are not trusted for allocation
+            let mut file = fs::File::create(format!("{}.ph", name)).unwrap();
+            for n in &[0u64, 0, 1 << 58, 1 << 60] {
+                file.write_u64::<BigEndian>(*n).unwrap();
+            }
+        }
+        assert!(Persistent::open_frozen(name.as_str(), Options::new(10, 0)).is_err());
+    }
+
+    #[test]
+    fn test_recovery_memory_limit () {
+        let dir = TestDir::new("lazy");
+        let name = dir.name("testdb");
+
+        let mut check = Vec::new();
+        let mut key = [0u8; 32];
+        let mut data = [0u8; 40];
+        let link;
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            for _ in 0 .. 2000 {
+                thread_rng().fill_bytes(&mut key);
+                thread_rng().fill_bytes(&mut data);
+                check.push((key, db.put(&key, &data, &vec!()).unwrap(), data));
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        {
+            let options = Options { recovery_memory_limit: 1000, .. Options::new(10, 1) };
+            let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+            assert!(db.is_lazy());
+            for (key, pref, data) in &check {
+                assert_eq!(db.get(key).unwrap().unwrap(), (*pref, data.to_vec(), vec!()));
+            }
+            assert_eq!(db.slots().map(|slots| slots.unwrap().len()).sum::<usize>(), 2000);
+            // overwrite some and add new
+            for entry in check.iter_mut().take(100) {
+                thread_rng().fill_bytes(&mut data);
+                entry.1 = db.put(&entry.0, &data, &vec!()).unwrap();
+                entry.2 = data;
+            }
+            for _ in 0 .. 1000 {
+                thread_rng().fill_bytes(&mut key);
+                thread_rng().fill_bytes(&mut data);
+                check.push((key, db.put(&key, &data, &vec!()).unwrap(), data));
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            assert!(!db.is_lazy());
+            for (key, pref, data) in &check {
+                assert_eq!(db.get(key).unwrap().unwrap(), (*pref, data.to_vec(), vec!()));
+            }
+            assert_eq!(db.slots().map(|slots| slots.unwrap().len()).sum::<usize>(), 3000);
+            link = db.buckets().enumerate().find(|(_, link)| link.is_valid()).unwrap();
+            db.shutdown();
+        }
+        {
+            // a damaged link is an error of the lazily read bucket
+            let mut file = OpenOptions::new().write(true).open(RolledFile::chunk_name(name.as_str(), 0, "bl")).unwrap();
+            file.seek(SeekFrom::Start(link.1.this_page().as_u64())).unwrap();
+            file.write_all(&[0xee; PAGE_PAYLOAD_SIZE]).unwrap();
+        }
+        {
+            let options = Options { recovery_memory_limit: 1000, .. Options::new(10, 1) };
+            let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+            assert!(db.is_lazy());
+            assert!(db.slots().nth(link.0).unwrap().is_err());
+            db.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_resplit_on_load () {
+        let dir = TestDir::new("resplit");
+        let name = dir.name("testdb");
+
+        let mut key = [0u8; 32];
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 64).unwrap();
+            db.init().unwrap();
+            for _ in 0 .. 5000 {
+                thread_rng().fill_bytes(&mut key);
+                check.push((key, db.put(&key, &[1], &vec!()).unwrap()));
+            }
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        {
+            // a lower fill target alone splits only on inserts
+            let mut db = Persistent::new_db(name.as_str(), 10, 4).unwrap();
+            assert!(db.stats().buckets < 5000 / 4);
+            db.shutdown();
+        }
+        {
+            let options = Options { resplit_on_load: true, .. Options::new(10, 4) };
+            let mut db = Persistent::new_db_with_options(name.as_str(), options).unwrap();
+            assert!(db.stats().buckets >= 5000 / 4);
+            db.shutdown();
+        }
+        {
+            // the splits were stored
+            let mut db = Persistent::new_db(name.as_str(), 10, 4).unwrap();
+            assert!(db.stats().buckets >= 5000 / 4);
+            for (key, pref) in &check {
+                assert_eq!(db.get(key).unwrap().unwrap().0, *pref);
+            }
+            db.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_migrate () {
+        let dir = TestDir::new("migrate");
+        let name = dir.name("testdb");
+        let new_name = dir.name("newdb");
+
+        let mut check = Vec::new();
+        {
+            let mut db = Persistent::new_db(name.as_str(), 10, 1).unwrap();
+            db.init().unwrap();
+            let mut key = [0u8; 32];
+            let mut data = [0u8; 40];
+            for _ in 0 .. 1000 {
+                thread_rng().fill_bytes(&mut key);
+                thread_rng().fill_bytes(&mut data);
+                db.put(&key, &data, &vec!()).unwrap();
+                check.push((key, data.to_vec()));
+            }
+            // a DAG whose leaf is overwritten data
+            let leaf = db.put(&check[0].0, &[0], &vec!()).unwrap();
+            db.put(&check[0].0, &[1], &vec!()).unwrap();
+            check[0].1 = vec!(1);
+            let node = db.put_referred(&[2], &vec!(leaf)).unwrap();
+            db.put(&[3], &[3], &vec!(node, leaf)).unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        Persistent::migrate(name.as_str(), new_name.as_str(), Options { wide_hash: true, .. Options::new(10, 1) }).unwrap();
+        {
+            let mut db = Persistent::new_db(new_name.as_str(), 10, 1).unwrap();
+            assert!(db.is_wide_hash());
+            for (key, data) in &check {
+                assert_eq!(&db.get(key).unwrap().unwrap().1, data);
+            }
+            let (root, data, _) = db.get(&[3]).unwrap().unwrap();
+            assert_eq!(data, vec!(3));
+            let dag = db.dag(root).map(|entry| entry.unwrap().1.payload().to_vec()).collect::<Vec<_>>();
+            assert_eq!(dag.len(), 4);
+            assert_eq!(db.data_envelopes().count(), 1003);
+            db.shutdown();
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Read-only file
+//! a file that can not be written, truncate and update are kept in memory
+//!
+
+use page::{Page, PAGE_SIZE};
+use pagedfile::PagedFile;
+use error::HammersbaldError;
+use pref::PRef;
+
+use std::collections::HashMap;
+
+pub struct ReadOnlyFile {
+    file: Box<PagedFile>,
+    len: u64,
+    updated: HashMap<PRef, Page>
+}
+
+impl ReadOnlyFile {
+    pub fn new (file: Box<PagedFile>) -> Result<ReadOnlyFile, HammersbaldError> {
+        // a page being appended by a writer is not yet part of the file
+        let len = file.len()? / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+        Ok(ReadOnlyFile {file, len, updated: HashMap::new()})
+    }
+}
+
+impl PagedFile for ReadOnlyFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        if pref.as_u64() >= self.len {
+            return Ok(None);
+        }
+        if let Some(page) = self.updated.get(&pref) {
+            return Ok(Some(page.clone()));
+        }
+        self.file.read_page(pref)
+    }
+
+    fn len(&self) -> Result<u64, HammersbaldError> {
+        Ok(self.len)
+    }
+
+    fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> {
+        if new_len > self.len {
+            return Err(HammersbaldError::ReadOnly);
+        }
+        self.len = new_len;
+        self.updated.retain(|pref, _| pref.as_u64() < new_len);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), HammersbaldError> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.file.shutdown()
+    }
+
+    fn append_page(&mut self, _: Page) -> Result<(), HammersbaldError> {
+        Err(HammersbaldError::ReadOnly)
+    }
+
+    fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> {
+        let pref = page.pref();
+        if pref.as_u64() >= self.len {
+            return Err(HammersbaldError::ReadOnly);
+        }
+        self.updated.insert(pref, page);
+        Ok(self.len)
+    }
+
+    fn flush(&mut self) -> Result<(), HammersbaldError> {
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
+}
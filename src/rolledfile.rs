@@ -26,8 +26,8 @@ use singlefile::SingleFile;
 
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::path::Path;
-use std::cmp::max;
+use std::path::{Path, PathBuf};
+use std::cmp::{min, max};
 
 pub struct RolledFile {
     name: String,
@@ -35,23 +35,131 @@ pub struct RolledFile {
     files: HashMap<u16,SingleFile>,
     len: u64,
     append_only: bool,
-    chunk_size: u64
+    read_only: bool,
+    chunk_size: u64,
+    sync_directory: bool,
+    directory_syncs: usize
 }
 
 impl RolledFile {
     pub fn new (name: &str, extension: &str, append_only: bool, chunk_size: u64) -> Result<RolledFile, HammersbaldError> {
-        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, chunk_size};
+        Self::check_chunk_size(chunk_size)?;
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, read_only: false, chunk_size,
+            sync_directory: false, directory_syncs: 0};
         rolled.open()?;
         Ok(rolled)
     }
 
+    /// open existing chunks without write access
+    pub fn new_read_only (name: &str, extension: &str, chunk_size: u64) -> Result<RolledFile, HammersbaldError> {
+        Self::check_chunk_size(chunk_size)?;
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only: false, read_only: true, chunk_size,
+            sync_directory: false, directory_syncs: 0};
+        rolled.open()?;
+        Ok(rolled)
+    }
+
+    // a page must not straddle two chunks, entries crossing a chunk boundary are split at pages
+    fn check_chunk_size (chunk_size: u64) -> Result<(), HammersbaldError> {
+        if chunk_size == 0 || chunk_size % PAGE_SIZE as u64 != 0 {
+            return Err(HammersbaldError::Corrupted(format!("chunk size {} is not a multiple of the page size", chunk_size)));
+        }
+        Ok(())
+    }
+
+    /// sync the directory after a new chunk file is created, so the file survives a crash
+    pub fn set_sync_directory (&mut self, sync: bool) {
+        self.sync_directory = sync;
+    }
+
+    /// number of directory syncs after chunk creation
+    pub fn directory_syncs (&self) -> usize {
+        self.directory_syncs
+    }
+
+    /// sync the directory of a db, making created or renamed files durable
+    pub fn sync_directory_of (name: &str) -> Result<(), HammersbaldError> {
+        #[cfg(unix)]
+        {
+            let mut dir = Path::new(name).parent().unwrap_or(Path::new("."));
+            if dir.to_string_lossy().to_string().is_empty() {
+                dir = Path::new(".");
+            }
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// bytes available to unprivileged users on the file system of a db
+    /// None where the file system can not be asked
+    pub fn free_bytes_of (name: &str) -> Result<Option<u64>, HammersbaldError> {
+        #[cfg(unix)]
+        {
+            let mut dir = Path::new(name).parent().unwrap_or(Path::new("."));
+            if dir.to_string_lossy().to_string().is_empty() {
+                dir = Path::new(".");
+            }
+            return Ok(Some(Self::statvfs_free(dir)?));
+        }
+        #[cfg(not(unix))]
+        Ok(None)
+    }
+
+    // bytes available to unprivileged users on the file system of dir
+    // sound as statvfs only reads the nul terminated path, which outlives the call, and writes
+    // the statvfs struct it is given, a plain C struct for which all zero bytes are a valid value
+    #[cfg(unix)]
+    fn statvfs_free (dir: &Path) -> Result<u64, HammersbaldError> {
+        use std::ffi::CString;
+        use std::io;
+        use std::mem;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| HammersbaldError::IO(e.into()))?;
+        let mut stat: ::libc::statvfs = unsafe { mem::zeroed() };
+        if unsafe { ::libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(HammersbaldError::IO(io::Error::last_os_error()));
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    fn create_chunk (&mut self, chunk: u16, start: u64) -> Result<(), HammersbaldError> {
+        let file = Self::open_file(self.append_only, false, Self::chunk_name(self.name.as_str(), chunk, self.extension.as_str()))?;
+        self.files.insert(chunk, SingleFile::new_chunk(file, start, self.chunk_size)?);
+        if self.sync_directory {
+            Self::sync_directory_of(self.name.as_str())?;
+            self.directory_syncs += 1;
+        }
+        Ok(())
+    }
+
     fn open (&mut self) -> Result<(), HammersbaldError> {
+        let mut highest_chunk = 0;
+        for (number, path) in Self::chunk_files(self.name.as_str(), self.extension.as_str())? {
+            let filename = path.to_string_lossy().to_string();
+            let file = Self::open_file(self.append_only, self.read_only, filename)?;
+            self.files.insert(number,
+                              SingleFile::new_chunk(file, number as u64 * self.chunk_size, self.chunk_size)?);
+            if let Some (file) = self.files.get(&number) {
+                if file.len().unwrap() > 0 {
+                    highest_chunk = max(highest_chunk, number);
+                }
+            }
+        }
+        if let Some (file) = self.files.get(&highest_chunk) {
+            self.len = highest_chunk as u64 * self.chunk_size + file.len()?;
+        }
+        Ok(())
+    }
+
+    /// find chunks of a rolled file
+    pub fn chunk_files (name: &str, extension: &str) -> Result<Vec<(u16, PathBuf)>, HammersbaldError> {
         // interesting file names are:
         // name.index.extension
         // where index is a number
-        if let Some(basename) = Path::new(self.name.as_str()).file_name() {
-            let mut highest_chunk = 0;
-            if let Some(mut dir) = Path::new(&self.name).parent() {
+        let mut chunks = Vec::new();
+        if let Some(basename) = Path::new(name).file_name() {
+            if let Some(mut dir) = Path::new(name).parent() {
                 if dir.to_string_lossy().to_string().is_empty() {
                     dir = Path::new(".");
                 }
@@ -60,25 +168,17 @@ impl RolledFile {
                     if path.is_file() {
                         if let Some(name_index) = path.file_stem() {
                             // name.index
-                            let ni = Path::new(name_index.clone());
+                            let ni = Path::new(name_index);
                             if let Some(name) = ni.file_stem() {
                                 // compare name
                                 if name == basename {
                                     // compare extension
-                                    if let Some(extension) = path.extension() {
-                                        if extension.to_string_lossy().to_string() == self.extension {
+                                    if let Some(ext) = path.extension() {
+                                        if ext.to_string_lossy().to_string() == extension {
                                             // parse index
                                             if let Some(index) = ni.extension() {
                                                 if let Ok(number) = index.to_string_lossy().parse::<u16>() {
-                                                    let filename = path.clone().to_string_lossy().to_string();
-                                                    let file = Self::open_file(self.append_only, filename)?;
-                                                    self.files.insert(number,
-                                                                      SingleFile::new_chunk(file, number as u64 * self.chunk_size, self.chunk_size)?);
-                                                    if let Some (file) = self.files.get(&number) {
-                                                        if file.len().unwrap() > 0 {
-                                                            highest_chunk = max(highest_chunk, number);
-                                                        }
-                                                    }
+                                                    chunks.push((number, path.clone()));
                                                 }
                                             }
                                         }
@@ -89,20 +189,25 @@ impl RolledFile {
                     }
                 }
             }
-            if let Some (file) = self.files.get(&highest_chunk) {
-                self.len = highest_chunk as u64 * self.chunk_size + file.len()?;
-            }
         }
         else {
             return Err(HammersbaldError::Corrupted("invalid db name".to_string()));
         }
-        Ok(())
+        Ok(chunks)
     }
 
-    fn open_file (append: bool, path: String) -> Result<File, HammersbaldError> {
+    /// file name of a chunk
+    pub fn chunk_name (name: &str, chunk: u16, extension: &str) -> String {
+        (((name.to_string() + ".") + chunk.to_string().as_str()) + ".") + extension
+    }
+
+    fn open_file (append: bool, read_only: bool, path: String) -> Result<File, HammersbaldError> {
         let mut open_mode = OpenOptions::new();
 
-        if append {
+        if read_only {
+            open_mode.read(true);
+        }
+        else if append {
             open_mode.read(true).append(true).create(true);
         }
         else{
@@ -114,13 +219,30 @@ impl RolledFile {
 
 impl PagedFile for RolledFile {
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        trace_span!("read_page", pref = pref.as_u64(), bytes = PAGE_SIZE);
         let chunk = (pref.as_u64() / self.chunk_size) as u16;
         if let Some(file) = self.files.get(&chunk) {
             return file.read_page(pref);
         }
+        if pref.as_u64() < self.len {
+            return Err(HammersbaldError::ChunkUnavailable(chunk, pref));
+        }
         Ok(None)
     }
 
+    fn read_pages(&self, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError> {
+        let chunk = (pref.as_u64() / self.chunk_size) as u16;
+        if let Some(file) = self.files.get(&chunk) {
+            // pages of this chunk only
+            let n = min(n as u64, (self.chunk_size - pref.as_u64() % self.chunk_size) / PAGE_SIZE as u64);
+            return file.read_pages(pref, n as usize);
+        }
+        if pref.as_u64() < self.len {
+            return Err(HammersbaldError::ChunkUnavailable(chunk, pref));
+        }
+        Ok(vec!())
+    }
+
     fn len(&self) -> Result<u64, HammersbaldError> {
         Ok(self.len)
     }
@@ -129,6 +251,9 @@ impl PagedFile for RolledFile {
         if new_len % PAGE_SIZE as u64 != 0 {
             return Err(HammersbaldError::Corrupted(format!("truncate not to page boundary {}", new_len)));
         }
+        if self.read_only {
+            return Err(HammersbaldError::ReadOnly);
+        }
         let chunk = (new_len / self.chunk_size) as u16;
         for (c, file) in &mut self.files {
             if *c > chunk {
@@ -152,12 +277,15 @@ impl PagedFile for RolledFile {
     fn shutdown (&mut self) {}
 
     fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> {
+        trace_span!("append_page", pref = self.len, bytes = PAGE_SIZE);
+        if self.read_only {
+            return Err(HammersbaldError::ReadOnly);
+        }
         let chunk = (self.len / self.chunk_size) as u16;
 
         if self.len % self.chunk_size == 0 && !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, self.len, self.chunk_size)?);
+            let start = self.len;
+            self.create_chunk(chunk, start)?;
         }
 
         if let Some (file) = self.files.get_mut(&chunk) {
@@ -171,17 +299,20 @@ impl PagedFile for RolledFile {
     }
 
     fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> {
+        trace_span!("update_page", pref = page.pref().as_u64(), bytes = PAGE_SIZE);
+        if self.read_only {
+            return Err(HammersbaldError::ReadOnly);
+        }
         let n_offset = page.pref().as_u64();
         let chunk = (n_offset / self.chunk_size) as u16;
 
         if !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, (n_offset/self.chunk_size) * self.chunk_size, self.chunk_size)?);
+            self.create_chunk(chunk, (n_offset/self.chunk_size) * self.chunk_size)?;
         }
 
         if let Some(file) = self.files.get_mut(&chunk) {
-            self.len = file.update_page(page)?  + chunk as u64 * self.chunk_size;
+            // an update of a page in an earlier chunk does not shorten the file
+            self.len = max(self.len, file.update_page(page)? + chunk as u64 * self.chunk_size);
             Ok(self.len)
         } else {
             return Err(HammersbaldError::Corrupted(format!("missing chunk in write {}", chunk)));
@@ -194,4 +325,69 @@ impl PagedFile for RolledFile {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn queue_len(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testdir::TestDir;
+
+    #[test]
+    fn test_sync_directory () {
+        let dir = TestDir::new("dirsync");
+        let name = dir.name("testdb");
+        {
+            let mut file = RolledFile::new(name.as_str(), "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+            file.set_sync_directory(true);
+            for n in 0 .. 5 {
+                file.append_page(Page::new(PRef::from(n * PAGE_SIZE as u64))).unwrap();
+            }
+            // chunks of two pages were created for pages 0, 2 and 4
+            assert_eq!(file.directory_syncs(), 3);
+            file.sync().unwrap();
+        }
+        {
+            let mut file = RolledFile::new(name.as_str(), "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+            for n in 5 .. 7 {
+                file.append_page(Page::new(PRef::from(n * PAGE_SIZE as u64))).unwrap();
+            }
+            assert_eq!(file.directory_syncs(), 0);
+        }
+    }
+
+    #[test]
+    fn test_chunk_boundary () {
+        let dir = TestDir::new("boundary");
+        let name = dir.name("testdb");
+        let chunk_size = 2 * PAGE_SIZE as u64;
+
+        assert!(RolledFile::new(name.as_str(), "bc", true, PAGE_SIZE as u64 + 1).is_err());
+        assert!(RolledFile::new_read_only(name.as_str(), "bc", 0).is_err());
+
+        {
+            let mut file = RolledFile::new(name.as_str(), "tb", false, chunk_size).unwrap();
+            for n in 0 .. 5 {
+                file.append_page(Page::new(PRef::from(n * PAGE_SIZE as u64))).unwrap();
+            }
+            // updating a page of the first chunk keeps the length
+            file.update_page(Page::new(PRef::from(PAGE_SIZE as u64))).unwrap();
+            assert_eq!(file.len().unwrap(), 5 * PAGE_SIZE as u64);
+            // the next append starts a new chunk at its base
+            file.update_page(Page::new(PRef::from(5 * PAGE_SIZE as u64))).unwrap();
+            file.append_page(Page::new(PRef::from(6 * PAGE_SIZE as u64))).unwrap();
+            assert_eq!(file.len().unwrap(), 7 * PAGE_SIZE as u64);
+            assert_eq!(file.read_page(PRef::from(6 * PAGE_SIZE as u64)).unwrap().unwrap().pref(), PRef::from(6 * PAGE_SIZE as u64));
+            assert!(file.read_page(PRef::from(7 * PAGE_SIZE as u64)).unwrap().is_none());
+            file.sync().unwrap();
+        }
+        let file = RolledFile::new(name.as_str(), "tb", false, chunk_size).unwrap();
+        assert_eq!(file.len().unwrap(), 7 * PAGE_SIZE as u64);
+        for n in 0 .. 7 {
+            assert_eq!(file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap().pref(), PRef::from(n * PAGE_SIZE as u64));
+        }
+    }
+}
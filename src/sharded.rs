@@ -0,0 +1,259 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Sharded db
+//! distributes keys over several dbs by the hash of the key
+//!
+//! Prefs returned carry the index of their shard in the upper 8 bits,
+//! so a shard may store at most 2^40 bytes of data.
+//! Referred data must be stored in the shard of the data referring to it.
+//! Data that refers to no other data is stored in a shard chosen by its hash.
+//!
+
+use api::{Hammersbald, HammersbaldAPI};
+use datafile::DagIterator;
+use error::HammersbaldError;
+use pref::PRef;
+
+use siphasher::sip::SipHasher;
+
+use std::hash::Hasher;
+
+const SHARD_SHIFT: u32 = 40;
+const MAX_SHARDS: usize = 256;
+
+/// a db distributing keys over several dbs
+pub struct ShardedHammersbald {
+    shards: Vec<Hammersbald>
+}
+
+impl ShardedHammersbald {
+    /// distribute over the given dbs, the order of dbs must not change between uses
+    /// panics if there are no dbs or more than 256
+    pub fn new (shards: Vec<Hammersbald>) -> ShardedHammersbald {
+        assert!(!shards.is_empty() && shards.len() <= MAX_SHARDS, "1 to 256 shards are supported");
+        ShardedHammersbald { shards }
+    }
+
+    /// number of shards
+    pub fn len (&self) -> usize {
+        self.shards.len()
+    }
+
+    /// false, there is at least one shard
+    pub fn is_empty (&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// the shard a key is stored in
+    /// shards should have the same key transform, the one of the first shard is applied
+    pub fn shard_of_key (&self, key: &[u8]) -> usize {
+        self.shard_of_hash(&self.shards[0].transformed_key(key))
+    }
+
+    /// the shard referred data that refers to no other data is stored in
+    pub fn shard_of_data (&self, data: &[u8]) -> usize {
+        self.shard_of_hash(data)
+    }
+
+    fn shard_of_hash (&self, bytes: &[u8]) -> usize {
+        // fixed hash keys, the shard of a key must not change between runs
+        let mut hasher = SipHasher::new_with_keys(0, 0);
+        hasher.write(bytes);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// the shard a pref points into
+    pub fn shard_of_pref (pref: PRef) -> usize {
+        (pref.as_u64() >> SHARD_SHIFT) as usize
+    }
+
+    /// access a shard
+    pub fn shard (&self, n: usize) -> &Hammersbald {
+        &self.shards[n]
+    }
+
+    fn global (shard: usize, pref: PRef) -> Result<PRef, HammersbaldError> {
+        if pref.as_u64() >> SHARD_SHIFT != 0 {
+            return Err(HammersbaldError::InvalidOffset);
+        }
+        Ok(PRef::from(((shard as u64) << SHARD_SHIFT) | pref.as_u64()))
+    }
+
+    fn local (pref: PRef) -> PRef {
+        PRef::from(pref.as_u64() & ((1u64 << SHARD_SHIFT) - 1))
+    }
+
+    fn global_all (shard: usize, prefs: Vec<PRef>) -> Result<Vec<PRef>, HammersbaldError> {
+        prefs.into_iter().map(|p| Self::global(shard, p)).collect()
+    }
+
+    // referred data must be in the shard of the referring data
+    fn local_all (&self, shard: usize, referred: &Vec<PRef>) -> Result<Vec<PRef>, HammersbaldError> {
+        referred.iter().map(|p| {
+            if Self::shard_of_pref(*p) != shard {
+                return Err(HammersbaldError::InvalidOffset);
+            }
+            Ok(Self::local(*p))
+        }).collect()
+    }
+
+    // the next data stored in the shard must have a pref below 2^40
+    fn check_shard_len (&self, shard: usize) -> Result<(), HammersbaldError> {
+        if self.shards[shard].data_tip().as_u64() >> SHARD_SHIFT != 0 {
+            return Err(HammersbaldError::InvalidOffset);
+        }
+        Ok(())
+    }
+
+    fn shard_of_pref_checked (&self, pref: PRef) -> Result<usize, HammersbaldError> {
+        let shard = Self::shard_of_pref(pref);
+        if shard >= self.shards.len() {
+            return Err(HammersbaldError::InvalidOffset);
+        }
+        Ok(shard)
+    }
+}
+
+impl HammersbaldAPI for ShardedHammersbald {
+    fn init (&mut self) -> Result<(), HammersbaldError> {
+        for shard in &mut self.shards {
+            shard.init()?;
+        }
+        Ok(())
+    }
+
+    fn batch (&mut self) -> Result<(), HammersbaldError> {
+        for shard in &mut self.shards {
+            shard.batch()?;
+        }
+        Ok(())
+    }
+
+    fn shutdown (&mut self) {
+        for shard in &mut self.shards {
+            shard.shutdown();
+        }
+    }
+
+    /// store data in the shard of the key, referred data must be in the same shard
+    fn put(&mut self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        let shard = self.shard_of_key(key);
+        let referred = self.local_all(shard, referred)?;
+        self.check_shard_len(shard)?;
+        let pref = self.shards[shard].put(key, data, &referred)?;
+        Self::global(shard, pref)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        let shard = self.shard_of_key(key);
+        if let Some((pref, data, referred)) = self.shards[shard].get(key)? {
+            return Ok(Some((Self::global(shard, pref)?, data, Self::global_all(shard, referred)?)));
+        }
+        Ok(None)
+    }
+
+    /// store referred data in the shard of the data it refers to, by the hash of the data if none
+    fn put_referred(&mut self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        let shard = match referred.first() {
+            Some(first) => self.shard_of_pref_checked(*first)?,
+            None => self.shard_of_data(data)
+        };
+        let referred = self.local_all(shard, referred)?;
+        self.check_shard_len(shard)?;
+        let pref = self.shards[shard].put_referred(data, &referred)?;
+        Self::global(shard, pref)
+    }
+
+    fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
+        let shard = self.shard_of_pref_checked(pref)?;
+        let (key, data, referred) = self.shards[shard].get_referred(Self::local(pref))?;
+        Ok((key, data, Self::global_all(shard, referred)?))
+    }
+
+    /// iterate a DAG within its shard, yields InvalidOffset if the root is of no shard
+    fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
+        match self.shard_of_pref_checked(root) {
+            Ok(shard) => self.shards[shard].dag(Self::local(root)).with_base((shard as u64) << SHARD_SHIFT),
+            Err(error) => self.shards[0].dag(PRef::invalid()).with_error(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use api::HammersbaldFactory;
+    use format::Payload;
+
+    use super::*;
+
+    #[test]
+    fn test_sharded () {
+        let shards = (0 .. 4).map(|_| Transient::new_db("shard", 1, 1).unwrap()).collect::<Vec<_>>();
+        let mut db = ShardedHammersbald::new(shards);
+        db.init().unwrap();
+
+        let mut check = Vec::new();
+        for i in 0 .. 1000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.push((key, db.put(&key, &key, &vec!()).unwrap()));
+        }
+        // referred data stays in the shard of the data it refers to
+        let (key, leaf) = check[0];
+        let node = db.put_referred(&[1], &vec!(leaf)).unwrap();
+        assert_eq!(ShardedHammersbald::shard_of_pref(node), db.shard_of_key(&key));
+        db.batch().unwrap();
+
+        for n in 0 .. 4 {
+            assert!(db.shard(n).data_envelopes().count() > 150);
+        }
+        for (key, pref) in &check {
+            assert_eq!(ShardedHammersbald::shard_of_pref(*pref), db.shard_of_key(key));
+            assert_eq!(db.get(key).unwrap().unwrap(), (*pref, key.to_vec(), vec!()));
+            assert_eq!(db.get_referred(*pref).unwrap(), (key.to_vec(), key.to_vec(), vec!()));
+        }
+        assert_eq!(db.get_referred(node).unwrap(), (vec!(), vec!(1), vec!(leaf)));
+        let dag = db.dag(node).map(|entry| entry.unwrap()).collect::<Vec<_>>();
+        assert_eq!(dag.iter().map(|(pref, _)| *pref).collect::<Vec<_>>(), vec!(node, leaf));
+        match Payload::deserialize(dag[0].1.payload()).unwrap() {
+            Payload::Referred(data) => assert_eq!(data.referred(), vec!(leaf)),
+            _ => panic!("expected referred data")
+        }
+        let mut dag = db.dag(PRef::from(4 << SHARD_SHIFT));
+        match dag.next() {
+            Some(Err(HammersbaldError::InvalidOffset)) => {},
+            _ => panic!("expected InvalidOffset")
+        }
+        assert!(dag.next().is_none());
+
+        // data referring to nothing is spread over the shards
+        let mut used = vec!(0; 4);
+        for i in 0 .. 400u32 {
+            let data = [(i >> 8) as u8, i as u8, 1];
+            let pref = db.put_referred(&data, &vec!()).unwrap();
+            assert_eq!(ShardedHammersbald::shard_of_pref(pref), db.shard_of_data(&data));
+            assert_eq!(db.get_referred(pref).unwrap().1, data.to_vec());
+            used[ShardedHammersbald::shard_of_pref(pref)] += 1;
+        }
+        assert!(used.iter().all(|n| *n > 50));
+
+        // a pref of another shard can not be referred
+        let other = check.iter().find(|(key, _)| db.shard_of_key(key) != db.shard_of_key(&[9, 9])).unwrap().1;
+        assert!(db.put(&[9, 9], &[], &vec!(other)).is_err());
+        db.shutdown();
+    }
+}
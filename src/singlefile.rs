@@ -26,7 +26,7 @@ use pref::PRef;
 use std::sync::Mutex;
 use std::fs::File;
 use std::io::{Read,Write,Seek,SeekFrom};
-use std::cmp::max;
+use std::cmp::{min, max};
 
 pub struct SingleFile {
     file: Mutex<File>,
@@ -66,6 +66,29 @@ impl PagedFile for SingleFile {
         Ok(Some(Page::from_buf(buffer)))
     }
 
+    fn read_pages(&self, pref: PRef, n: usize) -> Result<Vec<Page>, HammersbaldError> {
+        let o = pref.as_u64();
+        if o < self.base || o >= self.base + self.chunk_size {
+            return Err(HammersbaldError::Corrupted("read from wrong file".to_string()));
+        }
+        let pos = o - self.base;
+        let n = min(n as u64, self.len.saturating_sub(pos) / PAGE_SIZE as u64) as usize;
+        if n == 0 {
+            return Ok(vec!());
+        }
+
+        // one read for all pages
+        let mut file = self.file.lock().unwrap();
+        let mut buffer = vec!(0u8; n * PAGE_SIZE);
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer.chunks(PAGE_SIZE).map(|chunk| {
+            let mut content = [0u8; PAGE_SIZE];
+            content.copy_from_slice(chunk);
+            Page::from_buf(content)
+        }).collect())
+    }
+
     fn len(&self) -> Result<u64, HammersbaldError> {
         Ok(self.len)
     }
@@ -105,4 +128,8 @@ impl PagedFile for SingleFile {
     fn flush(&mut self) -> Result<(), HammersbaldError> {
         Ok(self.file.lock().unwrap().flush()?)
     }
+
+    fn queue_len(&self) -> usize {
+        0
+    }
 }
\ No newline at end of file
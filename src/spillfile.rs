@@ -0,0 +1,191 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Spill file
+//! an in memory file that moves its oldest pages to a temporary file above a size
+//!
+
+use error::HammersbaldError;
+use page::{Page, PAGE_SIZE};
+use pagedfile::PagedFile;
+use pref::PRef;
+
+use rand::{thread_rng, RngCore};
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct SpillFile {
+    // pages at and above spilled
+    pages: BTreeMap<u64, Page>,
+    file: Mutex<File>,
+    path: PathBuf,
+    // pages below are in the file
+    spilled: u64,
+    len: u64,
+    max_pages: usize
+}
+
+impl SpillFile {
+    /// keep at most spill_threshold bytes of pages in memory, the rest in a file in dir
+    pub fn new (spill_threshold: usize, dir: &Path) -> Result<SpillFile, HammersbaldError> {
+        let path = dir.join(format!("hammersbald-spill-{}", thread_rng().next_u64()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(SpillFile { pages: BTreeMap::new(), file: Mutex::new(file), path, spilled: 0, len: 0,
+            max_pages: max(spill_threshold / PAGE_SIZE, 1) })
+    }
+
+    fn spill (&mut self) -> Result<(), HammersbaldError> {
+        while self.pages.len() > self.max_pages {
+            let pos = *self.pages.keys().next().unwrap();
+            let page = self.pages.remove(&pos).unwrap();
+            let mut file = self.file.lock()?;
+            file.seek(SeekFrom::Start(pos))?;
+            file.write_all(&page.into_buf())?;
+            self.spilled = pos + PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+impl PagedFile for SpillFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        let pos = pref.as_u64();
+        if pos >= self.len {
+            return Ok(None);
+        }
+        if let Some(page) = self.pages.get(&pos) {
+            return Ok(Some(page.clone()));
+        }
+        if pos < self.spilled {
+            let mut file = self.file.lock()?;
+            let mut buffer = [0u8; PAGE_SIZE];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut buffer)?;
+            return Ok(Some(Page::from_buf(buffer)));
+        }
+        Ok(None)
+    }
+
+    fn len(&self) -> Result<u64, HammersbaldError> {
+        Ok(self.len)
+    }
+
+    fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> {
+        if new_len % PAGE_SIZE as u64 != 0 {
+            return Err(HammersbaldError::Corrupted(format!("truncate not to page boundary {}", new_len)));
+        }
+        self.pages.split_off(&new_len);
+        if new_len < self.spilled {
+            self.file.lock()?.set_len(new_len)?;
+            self.spilled = new_len;
+        }
+        self.len = new_len;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), HammersbaldError> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {}
+
+    fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> {
+        let pos = self.len;
+        self.pages.insert(pos, page);
+        self.len += PAGE_SIZE as u64;
+        self.spill()
+    }
+
+    fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> {
+        let pos = page.pref().as_u64();
+        if pos < self.spilled {
+            let mut file = self.file.lock()?;
+            file.seek(SeekFrom::Start(pos))?;
+            file.write_all(&page.into_buf())?;
+        } else {
+            self.pages.insert(pos, page);
+            self.len = max(self.len, pos + PAGE_SIZE as u64);
+            self.spill()?;
+        }
+        Ok(self.len)
+    }
+
+    fn flush(&mut self) -> Result<(), HammersbaldError> {
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use api::{HammersbaldAPI, HammersbaldFactory};
+    use options::Options;
+    use transient::Transient;
+
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_spill () {
+        let mut file = SpillFile::new(10 * PAGE_SIZE, env::temp_dir().as_path()).unwrap();
+        for n in 0 .. 100u64 {
+            let mut page = Page::new(PRef::from(n * PAGE_SIZE as u64));
+            page.write_u64(0, n).unwrap();
+            file.append_page(page).unwrap();
+            assert!(file.pages.len() <= 10);
+        }
+        for n in 0 .. 100u64 {
+            assert_eq!(file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0).unwrap(), n);
+        }
+        file.truncate(50 * PAGE_SIZE as u64).unwrap();
+        assert!(file.read_page(PRef::from(50 * PAGE_SIZE as u64)).unwrap().is_none());
+        file.append_page(Page::new(PRef::invalid())).unwrap();
+        assert_eq!(file.len().unwrap(), 51 * PAGE_SIZE as u64);
+        assert_eq!(file.read_page(PRef::from(49 * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0).unwrap(), 49);
+    }
+
+    #[test]
+    fn test_spilling_db () {
+        let options = Options { spill_threshold_bytes: 16 * PAGE_SIZE, .. Options::new(1, 1) };
+        let mut db = Transient::new_db_with_options("spill", options).unwrap();
+        db.init().unwrap();
+        let mut check = Vec::new();
+        for i in 0 .. 5000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            check.push((key, db.put(&key, &[i as u8; 100], &vec!()).unwrap()));
+        }
+        db.batch().unwrap();
+        assert!(db.stats().data_bytes > 100 * PAGE_SIZE as u64);
+        for (key, pref) in &check {
+            assert_eq!(db.get(key).unwrap(), Some((*pref, vec!(key[1]; 100), vec!())));
+        }
+        db.shutdown();
+    }
+}
@@ -0,0 +1,348 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//!
+//! # striped file
+//! several paged files stored in one file, in regions allocated on demand
+//!
+//! The first page of the file holds the length of each striped file and the
+//! owner of each region, that is the striped file and the index of the region
+//! within it. A region is allocated as a striped file grows into it, regions
+//! released by a truncate are reused after the next sync, free regions at the
+//! end of the file are cut then. The first page is written at sync, after the
+//! pages it covers are synced.
+//!
+
+use error::HammersbaldError;
+use pagedfile::PagedFile;
+use page::{PAGE_SIZE, Page};
+use pref::PRef;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use std::sync::{Arc, Mutex};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::cmp::max;
+
+// a length is stored in 6 bytes
+const LEN_SIZE: usize = 6;
+// a region owner is stored as striped file + 1 (0 for free) and the index of the region in it
+const OWNER_SIZE: usize = 3;
+// pages of a region
+const REGION_PAGES: u64 = 256;
+const REGION_SIZE: u64 = REGION_PAGES * PAGE_SIZE as u64;
+
+struct Shared {
+    file: File,
+    lens: Vec<u64>,
+    // owner of each region in the file, (striped file + 1, index in it), (0, 0) if free
+    owners: Vec<(u8, u16)>,
+    // regions of each striped file in its order
+    regions: Vec<Vec<usize>>,
+    // regions released by a truncate since the last sync
+    released: Vec<usize>,
+    capacity: usize
+}
+
+impl Shared {
+    // region of the file holding a page of a striped file, allocated if it has none
+    fn region (&mut self, stripe: usize, pref: PRef, allocate: bool) -> Result<Option<usize>, HammersbaldError> {
+        let index = (pref.as_u64() / REGION_SIZE) as usize;
+        while allocate && self.regions[stripe].len() <= index {
+            let n = self.regions[stripe].len();
+            let free = match self.owners.iter().position(|owner| owner.0 == 0) {
+                Some(free) => free,
+                None if self.owners.len() < self.capacity => {
+                    self.owners.push((0, 0));
+                    self.owners.len() - 1
+                },
+                None => return Err(HammersbaldError::IO(io::Error::new(io::ErrorKind::Other, "the single file of the db is full")))
+            };
+            self.owners[free] = (stripe as u8 + 1, n as u16);
+            self.regions[stripe].push(free);
+        }
+        Ok(self.regions[stripe].get(index).cloned())
+    }
+
+    // position of a page of a striped file in the file
+    fn position (&mut self, stripe: usize, pref: PRef, allocate: bool) -> Result<Option<u64>, HammersbaldError> {
+        Ok(self.region(stripe, pref, allocate)?.map(|region|
+            PAGE_SIZE as u64 + region as u64 * REGION_SIZE + pref.as_u64() % REGION_SIZE))
+    }
+
+    fn write_page (&mut self, stripe: usize, pref: PRef, page: Page) -> Result<(), HammersbaldError> {
+        let position = self.position(stripe, pref, true)?.expect("region allocated");
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.write_all(&page.into_buf())?;
+        Ok(())
+    }
+}
+
+/// one of several paged files stored in the same file
+pub struct StripedFile {
+    shared: Arc<Mutex<Shared>>,
+    stripe: usize
+}
+
+impl StripedFile {
+    /// open or create a file holding the given number of striped files
+    pub fn open (path: &str, stripes: usize) -> Result<Vec<StripedFile>, HammersbaldError> {
+        Self::check_stripes(stripes)?;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if file.seek(SeekFrom::End(0))? < PAGE_SIZE as u64 {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&[0u8; PAGE_SIZE])?;
+            file.sync_data()?;
+        }
+        Self::read_head(file, path, stripes)
+    }
+
+    /// open an existing file holding the given number of striped files, e.g. on a read-only file system
+    /// the striped files must not be written
+    pub fn open_read_only (path: &str, stripes: usize) -> Result<Vec<StripedFile>, HammersbaldError> {
+        Self::check_stripes(stripes)?;
+        Self::read_head(File::open(path)?, path, stripes)
+    }
+
+    fn check_stripes (stripes: usize) -> Result<(), HammersbaldError> {
+        if stripes == 0 || stripes > u8::max_value() as usize - 1 || stripes * LEN_SIZE + OWNER_SIZE > PAGE_SIZE {
+            return Err(HammersbaldError::Corrupted(format!("can not store {} files in one", stripes)));
+        }
+        Ok(())
+    }
+
+    fn read_head (mut file: File, path: &str, stripes: usize) -> Result<Vec<StripedFile>, HammersbaldError> {
+        let mut head = [0u8; PAGE_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut head)?;
+        let lens = (0 .. stripes).map(|i| BigEndian::read_u48(&head[i*LEN_SIZE .. (i+1)*LEN_SIZE])).collect::<Vec<_>>();
+        if lens.iter().any(|len| len % PAGE_SIZE as u64 != 0) {
+            return Err(HammersbaldError::Corrupted(format!("{} has a length not at page boundary", path)));
+        }
+        let capacity = (PAGE_SIZE - stripes * LEN_SIZE) / OWNER_SIZE;
+        let mut owners = head[stripes * LEN_SIZE ..].chunks(OWNER_SIZE).take(capacity)
+            .map(|owner| (owner[0], BigEndian::read_u16(&owner[1..3]))).collect::<Vec<_>>();
+        while owners.last().map(|owner| owner.0 == 0).unwrap_or(false) {
+            owners.pop();
+        }
+        let mut regions = vec!(Vec::new(); stripes);
+        for (stripe, len) in lens.iter().enumerate() {
+            let n = ((len + REGION_SIZE - 1) / REGION_SIZE) as usize;
+            for index in 0 .. n {
+                match owners.iter().position(|owner| *owner == (stripe as u8 + 1, index as u16)) {
+                    Some(region) => regions[stripe].push(region),
+                    None => return Err(HammersbaldError::Corrupted(format!("{} misses region {} of file {}", path, index, stripe)))
+                }
+            }
+        }
+        // regions not covered by a length were allocated after the last sync
+        for owner in owners.iter_mut() {
+            if owner.0 == 0 || owner.0 as usize > stripes || regions[owner.0 as usize - 1].len() <= owner.1 as usize {
+                *owner = (0, 0);
+            }
+        }
+        let shared = Arc::new(Mutex::new(Shared { file, lens, owners, regions, released: Vec::new(), capacity }));
+        Ok((0 .. stripes).map(|stripe| StripedFile { shared: shared.clone(), stripe }).collect())
+    }
+}
+
+impl PagedFile for StripedFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, HammersbaldError> {
+        let mut shared = self.shared.lock().unwrap();
+        if pref.as_u64() >= shared.lens[self.stripe] {
+            return Ok(None);
+        }
+        match shared.position(self.stripe, pref, false)? {
+            Some(position) => {
+                let mut buffer = [0u8; PAGE_SIZE];
+                shared.file.seek(SeekFrom::Start(position))?;
+                shared.file.read_exact(&mut buffer)?;
+                Ok(Some(Page::from_buf(buffer)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn len(&self) -> Result<u64, HammersbaldError> {
+        Ok(self.shared.lock().unwrap().lens[self.stripe])
+    }
+
+    // regions beyond the new length are released, pages beyond it in the last region stay until overwritten
+    fn truncate(&mut self, new_len: u64) -> Result<(), HammersbaldError> {
+        let mut shared = self.shared.lock().unwrap();
+        let keep = ((new_len + REGION_SIZE - 1) / REGION_SIZE) as usize;
+        while shared.regions[self.stripe].len() > keep {
+            let region = shared.regions[self.stripe].pop().unwrap();
+            shared.released.push(region);
+        }
+        shared.lens[self.stripe] = new_len;
+        Ok(())
+    }
+
+    // sync pages of all striped files, then record their lengths and regions
+    // released regions are free to reuse after this, those at the end of the file are cut
+    fn sync(&self) -> Result<(), HammersbaldError> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.file.sync_data()?;
+        for region in shared.released.split_off(0) {
+            shared.owners[region] = (0, 0);
+        }
+        while shared.owners.last().map(|owner| owner.0 == 0).unwrap_or(false) {
+            shared.owners.pop();
+        }
+        let mut head = [0u8; PAGE_SIZE];
+        let stripes = shared.lens.len();
+        for (i, len) in shared.lens.iter().enumerate() {
+            BigEndian::write_u48(&mut head[i*LEN_SIZE .. (i+1)*LEN_SIZE], *len);
+        }
+        for (i, owner) in shared.owners.iter().enumerate() {
+            let at = stripes * LEN_SIZE + i * OWNER_SIZE;
+            head[at] = owner.0;
+            BigEndian::write_u16(&mut head[at + 1 .. at + OWNER_SIZE], owner.1);
+        }
+        shared.file.seek(SeekFrom::Start(0))?;
+        shared.file.write_all(&head)?;
+        shared.file.sync_data()?;
+        let end = PAGE_SIZE as u64 + shared.owners.len() as u64 * REGION_SIZE;
+        if shared.file.metadata()?.len() > end {
+            shared.file.set_len(end)?;
+            shared.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn shutdown (&mut self) {}
+
+    fn append_page(&mut self, page: Page) -> Result<(), HammersbaldError> {
+        let mut shared = self.shared.lock().unwrap();
+        let pref = PRef::from(shared.lens[self.stripe]);
+        shared.write_page(self.stripe, pref, page)?;
+        shared.lens[self.stripe] += PAGE_SIZE as u64;
+        Ok(())
+    }
+
+    fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> {
+        let mut shared = self.shared.lock().unwrap();
+        let pref = page.pref();
+        shared.write_page(self.stripe, pref, page)?;
+        let len = max(shared.lens[self.stripe], pref.as_u64() + PAGE_SIZE as u64);
+        shared.lens[self.stripe] = len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), HammersbaldError> {
+        Ok(self.shared.lock().unwrap().file.flush()?)
+    }
+
+    fn queue_len(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testdir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn test_striped () {
+        let dir = TestDir::new("striped");
+        let path = dir.name("striped");
+        {
+            let mut files = StripedFile::open(&path, 3).unwrap();
+            for n in 0 .. 10u64 {
+                for (i, file) in files.iter_mut().enumerate().take((n % 3 + 1) as usize) {
+                    let mut page = Page::new(PRef::from(file.len().unwrap()));
+                    page.write(0, &[i as u8, n as u8]);
+                    file.append_page(page).unwrap();
+                }
+            }
+            files[0].sync().unwrap();
+            // not synced, so not recorded
+            let len = files[1].len().unwrap();
+            files[1].append_page(Page::new(PRef::from(len))).unwrap();
+        }
+        let files = StripedFile::open(&path, 3).unwrap();
+        assert_eq!(files.iter().map(|f| f.len().unwrap() / PAGE_SIZE as u64).collect::<Vec<_>>(), vec!(10, 6, 3));
+        for (i, file) in files.iter().enumerate() {
+            let mut pos = 0;
+            for n in 0 .. 10u64 {
+                if (n % 3 + 1) as usize > i {
+                    let page = file.read_page(PRef::from(pos)).unwrap().unwrap();
+                    let mut buf = [0u8; 2];
+                    page.read(0, &mut buf);
+                    assert_eq!(buf, [i as u8, n as u8]);
+                    pos += PAGE_SIZE as u64;
+                }
+            }
+            assert!(file.read_page(PRef::from(pos)).unwrap().is_none());
+        }
+        let files = StripedFile::open_read_only(&path, 3).unwrap();
+        assert_eq!(files.iter().map(|f| f.len().unwrap() / PAGE_SIZE as u64).collect::<Vec<_>>(), vec!(10, 6, 3));
+        fs::remove_file(&path).unwrap();
+        assert!(StripedFile::open_read_only(&path, 3).is_err());
+    }
+
+    #[test]
+    fn test_regions () {
+        let dir = TestDir::new("regions");
+        let path = dir.name("striped");
+        let page_at = |len: u64, byte: u8| {
+            let mut page = Page::new(PRef::from(len));
+            page.write(0, &[byte]);
+            page
+        };
+        {
+            let mut files = StripedFile::open(&path, 3).unwrap();
+            for n in 0 .. REGION_PAGES + 1 {
+                let len = files[0].len().unwrap();
+                files[0].append_page(page_at(len, n as u8)).unwrap();
+            }
+            files[1].append_page(page_at(0, 1)).unwrap();
+            files[2].append_page(page_at(0, 2)).unwrap();
+            files[0].sync().unwrap();
+            // two regions of the first file and one of each other, the last used as far as written
+            assert_eq!(fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64 + 3 * REGION_SIZE + PAGE_SIZE as u64);
+
+            files[0].truncate(REGION_SIZE).unwrap();
+            files[2].truncate(0).unwrap();
+            files[0].sync().unwrap();
+            // the free region at the end is cut, the one between is kept
+            assert_eq!(fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64 + 3 * REGION_SIZE);
+
+            files[1].truncate(0).unwrap();
+            files[1].sync().unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64 + REGION_SIZE);
+            // the region released by the first file is reused
+            files[2].append_page(page_at(0, 3)).unwrap();
+            files[2].sync().unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64 + REGION_SIZE + PAGE_SIZE as u64);
+        }
+        let files = StripedFile::open(&path, 3).unwrap();
+        assert_eq!(files.iter().map(|f| f.len().unwrap()).collect::<Vec<_>>(), vec!(REGION_SIZE, 0, PAGE_SIZE as u64));
+        for n in 0 .. REGION_PAGES {
+            let mut buf = [0u8; 1];
+            files[0].read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap().read(0, &mut buf);
+            assert_eq!(buf[0], n as u8);
+        }
+        let mut buf = [0u8; 1];
+        files[2].read_page(PRef::from(0)).unwrap().unwrap().read(0, &mut buf);
+        assert_eq!(buf[0], 3);
+        assert!(files[1].read_page(PRef::from(0)).unwrap().is_none());
+    }
+}
@@ -23,7 +23,15 @@ use pagedfile::PagedFile;
 use error::HammersbaldError;
 use pref::PRef;
 
+// layout of the head of the first page
+pub const HEAD_BUCKETS: usize = 0;
+pub const HEAD_STEP: usize = 6;
+pub const HEAD_SIP0: usize = 12;
+pub const HEAD_SIP1: usize = 20;
 pub const FIRST_PAGE_HEAD:usize = 28;
+// the number of buckets is below 2^32, the highest two bytes of its field hold the magic namespace
+// that is 0 in a db that did not record it
+pub const HEAD_NAMESPACE_SHIFT: u32 = 32;
 pub const BUCKET_SIZE: usize = 6;
 pub const BUCKETS_PER_PAGE:usize = PAGE_PAYLOAD_SIZE/BUCKET_SIZE;
 pub const BUCKETS_FIRST_PAGE:usize = (PAGE_PAYLOAD_SIZE - FIRST_PAGE_HEAD)/BUCKET_SIZE;
@@ -89,6 +97,14 @@ impl PagedFile for TableFile {
     fn update_page(&mut self, page: Page) -> Result<u64, HammersbaldError> {
         self.file.update_page(page)
     }
+
+    fn queue_len(&self) -> usize {
+        self.file.queue_len()
+    }
+
+    fn resize_cache(&mut self, pages: usize) {
+        self.file.resize_cache(pages)
+    }
 }
 
 struct BucketIterator<'a> {
@@ -0,0 +1,56 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//!
+//! # test directory
+//! a directory for the files of a test, shared by the tests of all modules
+//!
+
+use rand::{thread_rng, RngCore};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// a directory for the files of a test, removed when dropped, also if the test fails
+pub struct TestDir {
+    dir: PathBuf
+}
+
+impl TestDir {
+    /// create a new directory in the temporary directory, its name starts with hammersbald-prefix
+    pub fn new (prefix: &str) -> TestDir {
+        let dir = env::temp_dir().join(format!("hammersbald-{}-{}", prefix, thread_rng().next_u64()));
+        fs::create_dir_all(&dir).unwrap();
+        TestDir { dir }
+    }
+
+    /// the directory
+    pub fn path (&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// name of a db or file in the directory
+    pub fn name (&self, db: &str) -> String {
+        self.dir.join(db).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -19,6 +19,7 @@
 //! Implements in-memory Read and Write for tests
 
 use error::HammersbaldError;
+use options::Options;
 use logfile::LogFile;
 use api::{HammersbaldFactory, Hammersbald};
 use tablefile::TableFile;
@@ -28,6 +29,7 @@ use page::{Page,PAGE_SIZE};
 use pagedfile::PagedFile;
 use asyncfile::AsyncFile;
 use cachedfile::CachedFile;
+use spillfile::SpillFile;
 
 use std::io::Read;
 use std::io::Write;
@@ -35,6 +37,7 @@ use std::io::Seek;
 use std::io::SeekFrom;
 use std::io;
 use std::cmp::min;
+use std::env;
 use std::sync::Mutex;
 
 /// in memory representation of a file
@@ -55,23 +58,34 @@ impl Transient {
     }
 }
 
+impl Transient {
+    // an append only file, spilling to disk if the options ask for it
+    fn appended (options: &Options) -> Result<Box<PagedFile>, HammersbaldError> {
+        if options.spill_threshold_bytes > 0 {
+            let dir = options.scratch_dir.clone().unwrap_or_else(env::temp_dir);
+            return Ok(Box::new(SpillFile::new(options.spill_threshold_bytes, dir.as_path())?));
+        }
+        Ok(Box::new(Transient::new(true)))
+    }
+}
+
 impl HammersbaldFactory for Transient {
-    fn new_db (_name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Hammersbald, HammersbaldError> {
+    fn new_db_with_options (_name: &str, options: Options) -> Result<Hammersbald, HammersbaldError> {
         let log = LogFile::new(
             Box::new(AsyncFile::new(
             Box::new(Transient::new(true)))?));
         let table = TableFile::new(
-            Box::new(CachedFile::new(
-            Box::new(Transient::new(false)), cached_data_pages)?))?;
+            CachedFile::with_options(
+            Box::new(Transient::new(false)), &options)?)?;
         let data = DataFile::new(
-            Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(Box::new(Transient::new(true)))?),
-                cached_data_pages)?))?;
+            CachedFile::with_options(
+                Box::new(AsyncFile::new(Self::appended(&options)?)?),
+                &options)?)?;
         let link = DataFile::new(
-            Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(Box::new(Transient::new(true)))?),
-                cached_data_pages)?))?;
-        Hammersbald::new(log, table, data, link, bucket_fill_target)
+            CachedFile::with_options(
+                Box::new(AsyncFile::new(Self::appended(&options)?)?),
+                &options)?)?;
+        Hammersbald::new_with_options(log, table, data, link, &options)
     }
 }
 
@@ -121,6 +135,10 @@ impl PagedFile for Transient {
     }
 
     fn flush(&mut self) -> Result<(), HammersbaldError> {Ok(())}
+
+    fn queue_len(&self) -> usize {
+        0
+    }
 }
 
 impl Read for Inner {
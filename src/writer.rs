@@ -0,0 +1,144 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Writer
+//! submit writes from several threads to a db owned by one thread
+//!
+//! Each Writer handle sends its requests over a channel to the owner thread,
+//! that performs them in the order received and replies on a channel of the request.
+//! A batch ends the batch of all handles.
+//!
+
+use api::{Hammersbald, HammersbaldAPI};
+use error::HammersbaldError;
+use pref::PRef;
+
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+enum Request {
+    Put(Vec<u8>, Vec<u8>, Vec<PRef>, Sender<Result<PRef, HammersbaldError>>),
+    PutReferred(Vec<u8>, Vec<PRef>, Sender<Result<PRef, HammersbaldError>>),
+    Get(Vec<u8>, Sender<Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError>>),
+    GetReferred(PRef, Sender<Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError>>),
+    Batch(Sender<Result<(), HammersbaldError>>)
+}
+
+/// a handle to submit writes to a db owned by a coordinator thread, clone it for each thread
+#[derive(Clone)]
+pub struct Writer {
+    sender: Sender<Request>
+}
+
+impl Writer {
+    /// move the db to a new coordinator thread
+    /// the thread ends and returns the db once all handles are dropped
+    pub fn spawn (db: Hammersbald) -> (Writer, JoinHandle<Hammersbald>) {
+        let (sender, receiver) = channel();
+        let owner = thread::spawn(move || {
+            let mut db = db;
+            for request in receiver {
+                // a caller that went away does not need the reply
+                match request {
+                    Request::Put(key, data, referred, reply) => { reply.send(db.put(&key, &data, &referred)).ok(); },
+                    Request::PutReferred(data, referred, reply) => { reply.send(db.put_referred(&data, &referred)).ok(); },
+                    Request::Get(key, reply) => { reply.send(db.get(&key)).ok(); },
+                    Request::GetReferred(pref, reply) => { reply.send(db.get_referred(pref)).ok(); },
+                    Request::Batch(reply) => { reply.send(db.batch()).ok(); }
+                }
+            }
+            db
+        });
+        (Writer { sender }, owner)
+    }
+
+    /// store data with a key
+    /// storing with the same key makes previous data unaddressable
+    pub fn put(&self, key: &[u8], data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        let (reply, result) = channel();
+        self.sender.send(Request::Put(key.to_vec(), data.to_vec(), referred.clone(), reply))?;
+        result.recv()?
+    }
+
+    /// store data accessible only with its pref
+    pub fn put_referred(&self, data: &[u8], referred: &Vec<PRef>) -> Result<PRef, HammersbaldError> {
+        let (reply, result) = channel();
+        self.sender.send(Request::PutReferred(data.to_vec(), referred.clone(), reply))?;
+        result.recv()?
+    }
+
+    /// retrieve single data by key
+    /// returns (pref, data, referred)
+    pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, HammersbaldError> {
+        let (reply, result) = channel();
+        self.sender.send(Request::Get(key.to_vec(), reply))?;
+        result.recv()?
+    }
+
+    /// get data
+    /// returns (key, data, referred)
+    pub fn get_referred(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>, Vec<PRef>), HammersbaldError> {
+        let (reply, result) = channel();
+        self.sender.send(Request::GetReferred(pref, reply))?;
+        result.recv()?
+    }
+
+    /// end the current batch of all handles and start a new batch
+    pub fn batch(&self) -> Result<(), HammersbaldError> {
+        let (reply, result) = channel();
+        self.sender.send(Request::Batch(reply))?;
+        result.recv()?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use api::HammersbaldFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_writer () {
+        let mut db = Transient::new_db("writer", 1, 1).unwrap();
+        db.init().unwrap();
+        let (writer, owner) = Writer::spawn(db);
+
+        let producers = (0 .. 4u8).map(|t| {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                let mut prefs = Vec::new();
+                for i in 0 .. 250u8 {
+                    let leaf = writer.put_referred(&[t, i], &vec!()).unwrap();
+                    prefs.push(([t, i], writer.put(&[t, i], &[i], &vec!(leaf)).unwrap(), leaf));
+                }
+                writer.batch().unwrap();
+                prefs
+            })
+        }).collect::<Vec<_>>();
+        let stored = producers.into_iter().flat_map(|p| p.join().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(stored.len(), 1000);
+        for (key, pref, leaf) in &stored {
+            assert_eq!(writer.get(key).unwrap(), Some((*pref, vec!(key[1]), vec!(*leaf))));
+            assert_eq!(writer.get_referred(*leaf).unwrap(), (vec!(), key.to_vec(), vec!()));
+        }
+        drop(writer);
+        let mut db = owner.join().unwrap();
+        assert_eq!(db.get(&[3, 249]).unwrap().unwrap().1, vec!(249));
+        db.shutdown();
+    }
+}